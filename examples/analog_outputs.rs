@@ -21,13 +21,13 @@ fn main() -> Result<(), Box<dyn Error>> {
     // Open the first available nLab
     let nlab = bench.open_first_available(true)?;
 
-    nlab.a1.turn_on();
+    nlab.a1.turn_on()?;
     thread::sleep(time::Duration::from_secs(10));
-    nlab.a1.turn_off();
+    nlab.a1.turn_off()?;
 
-    nlab.a2.turn_on();
+    nlab.a2.turn_on()?;
     thread::sleep(time::Duration::from_secs(10));
-    nlab.a2.turn_off();
+    nlab.a2.turn_off()?;
 
     Ok(())
 }
\ No newline at end of file
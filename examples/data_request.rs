@@ -20,7 +20,7 @@ fn main() -> Result<(), Box<dyn Error>> {
     // Open the first available nLab
     let nlab = bench.open_first_available(true)?;
 
-    nlab.a1.turn_on();
+    nlab.a1.turn_on()?;
 
     let sweep_handle = nlab.request(8000.0, 19200, None);
 
@@ -28,7 +28,7 @@ fn main() -> Result<(), Box<dyn Error>> {
         println!("{:?}", sample.data);
     }
 
-    nlab.a1.turn_off();
+    nlab.a1.turn_off()?;
     
     
     let sweep_handle = nlab.request(8000.0, 19200, Some(Trigger{
@@ -39,8 +39,8 @@ fn main() -> Result<(), Box<dyn Error>> {
         trigger_delay_us: 0,
     }));
 
-    nlab.a1.set_polarity(AnalogSignalPolarity::Bipolar);
-    nlab.a1.turn_on();
+    nlab.a1.set_polarity(AnalogSignalPolarity::Bipolar)?;
+    nlab.a1.turn_on()?;
     for sample in sweep_handle.receiver {
         println!("{:?}", sample.data);
     }
@@ -0,0 +1,37 @@
+/***************************************************************************************************
+ *
+ *  nLabs, LLC
+ *  https://getnlab.com
+ *  Copyright(c) 2020. All Rights Reserved
+ *
+ *  This file is part of the nLab API
+ *
+ **************************************************************************************************/
+
+use nlabapi::export::csv::{self, Delimiter};
+use nlabapi::LabBench;
+use std::error::Error;
+use std::fs::File;
+use std::io::BufWriter;
+
+fn main() -> Result<(), Box<dyn Error>> {
+    env_logger::init();
+
+    // Create a LabBench
+    let bench = LabBench::new()?;
+
+    // Open the first available nLab
+    let nlab = bench.open_first_available(true)?;
+
+    nlab.a1.turn_on()?;
+
+    // Stream a long capture straight to disk
+    let sweep_handle = nlab.request(8000.0, 80000, None);
+    let file = BufWriter::new(File::create("sweep.csv")?);
+    let samples = csv::write_sweep_handle(sweep_handle, file, Delimiter::Comma)?;
+    println!("Wrote {} samples to sweep.csv", samples);
+
+    nlab.a1.turn_off()?;
+
+    Ok(())
+}
@@ -0,0 +1,36 @@
+/***************************************************************************************************
+ *
+ *  nLabs, LLC
+ *  https://getnlab.com
+ *  Copyright(c) 2020. All Rights Reserved
+ *
+ *  This file is part of the nLab API
+ *
+ **************************************************************************************************/
+
+use nlabapi::fra::{self, FraSettings};
+use nlabapi::LabBench;
+use std::error::Error;
+
+fn main() -> Result<(), Box<dyn Error>> {
+    env_logger::init();
+
+    // Create a LabBench
+    let bench = LabBench::new()?;
+
+    // Open the first available nLab
+    let mut nlab = bench.open_first_available(true)?;
+
+    // Drive the network from A1, measure its input on Ch1 and its output on Ch2
+    let settings = FraSettings {
+        frequencies: fra::log_frequencies(10.0, 10_000.0, 31),
+        ..Default::default()
+    };
+
+    println!("{:>12} {:>10} {:>10}", "Freq (Hz)", "Gain (dB)", "Phase (°)");
+    for point in fra::analyze(&mut nlab, &settings)? {
+        println!("{:>12.2} {:>10.2} {:>10.1}", point.frequency, point.gain_db, point.phase_degrees);
+    }
+
+    Ok(())
+}
@@ -21,13 +21,13 @@ fn main() -> Result<(), Box<dyn Error>> {
     // Open the first available nLab
     let nlab = bench.open_first_available(true)?;
 
-    nlab.p1.turn_on();
+    nlab.p1.turn_on()?;
     thread::sleep(time::Duration::from_secs(10));
-    nlab.p1.turn_off();
+    nlab.p1.turn_off()?;
 
-    nlab.p2.turn_on();
+    nlab.p2.turn_on()?;
     thread::sleep(time::Duration::from_secs(10));
-    nlab.p2.turn_off();
+    nlab.p2.turn_off()?;
 
     Ok(())
 }
\ No newline at end of file
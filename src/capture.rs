@@ -0,0 +1,652 @@
+/***************************************************************************************************
+ *
+ *  nLabs, LLC
+ *  https://getnlab.com
+ *  Copyright(c) 2020. All Rights Reserved
+ *
+ *  This file is part of the nLab API
+ *
+ **************************************************************************************************/
+
+//! Native capture files, recording raw ADC codes so that sweeps can be replayed exactly
+//!
+//! A capture file holds everything needed to reproduce the samples of a sweep bit for bit: the
+//! identity of the nLab, the hardware gain and offset settings and scaling of every channel,
+//! the trigger, the effective sample rate, the timing of any stimulus and the raw 12-bit code of
+//! every reading. A power log is interleaved with the samples. All values are little-endian.
+//!
+//! Replaying a capture produces a [`SweepHandle`] that delivers the same [`Sample`]s the nLab
+//! did, so analysis code can run unchanged against archived lab sessions.
+
+use std::convert::TryFrom;
+use std::error::Error;
+use std::io::{Read, Write};
+use std::sync::{Arc, mpsc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use crate::scope::data_requests::sample_from_measurements;
+use crate::{
+    AnalogInput, ChannelConfig, ChannelRange, ChannelScaling, Nlab, PowerState, PowerStatus, Sample,
+    SensorTransform, StimulusTiming, Sweep, SweepHandle, SweepMetadata, Trigger, TriggerType,
+};
+
+const MAGIC: &[u8; 7] = b"NLABCAP";
+const FORMAT_VERSION: u8 = 1;
+
+const RECORD_END: u8 = 0;
+const RECORD_SAMPLES: u8 = 1;
+const RECORD_POWER: u8 = 2;
+const RECORD_STIMULUS: u8 = 3;
+
+/// Samples written per block of the file
+const SAMPLES_PER_BLOCK: usize = 256;
+/// Code stored for a reading missing from a sample
+const MISSING_CODE: u16 = 0xFFFF;
+/// Most samples allocated up front, as the sample count in a file is not trusted
+const MAX_PREALLOCATED_SAMPLES: u32 = 1 << 16;
+/// Minimum time between entries of the power log while recording
+const POWER_LOG_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The nLab a capture was recorded on
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DeviceIdentity {
+    /// True for first generation nLabs running legacy firmware
+    pub legacy: bool,
+    pub firmware_version: Option<u16>,
+    /// Version of the nLab API that recorded the capture
+    pub api_version: String,
+}
+
+impl DeviceIdentity {
+    pub fn of(nlab: &Nlab) -> Self {
+        DeviceIdentity {
+            legacy: nlab.is_legacy(),
+            firmware_version: nlab.version().ok(),
+            api_version: crate::version(),
+        }
+    }
+}
+
+/// Power supply status at a point during the capture
+#[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PowerLogEntry {
+    /// Time since the start of the sweep, of the last sample received before the entry
+    pub time: f64,
+    pub status: PowerStatus,
+}
+
+/// Writes a capture file, one sample at a time
+#[derive(Debug)]
+pub struct CaptureWriter<W: Write> {
+    writer: W,
+    channels: Vec<usize>,
+    block: Vec<[Option<u16>; 4]>,
+    samples_written: u32,
+}
+
+impl<W: Write> CaptureWriter<W> {
+    /// Creates a writer and writes the header of a sweep
+    pub fn new(mut writer: W, device: &DeviceIdentity, metadata: &SweepMetadata) -> Result<Self, Box<dyn Error>> {
+        writer.write_all(MAGIC)?;
+        put_u8(&mut writer, FORMAT_VERSION)?;
+
+        put_u8(&mut writer, device.legacy as u8)?;
+        put_u8(&mut writer, device.firmware_version.is_some() as u8)?;
+        put_u16(&mut writer, device.firmware_version.unwrap_or(0))?;
+        put_str(&mut writer, &device.api_version)?;
+
+        let start = metadata.start_time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        put_u64(&mut writer, start.as_secs())?;
+        put_u32(&mut writer, start.subsec_nanos())?;
+        put_f64(&mut writer, metadata.sample_rate_hz)?;
+        put_f64(&mut writer, metadata.requested_sample_rate_hz)?;
+        put_u32(&mut writer, metadata.number_of_samples)?;
+
+        let trigger = &metadata.trigger;
+        put_u8(&mut writer, trigger.is_enabled as u8)?;
+        put_u8(&mut writer, trigger.trigger_type.value())?;
+        put_u8(&mut writer, trigger.source_channel as u8)?;
+        put_f64(&mut writer, trigger.trigger_level)?;
+        put_u32(&mut writer, trigger.trigger_delay_us)?;
+
+        for config in metadata.channels.iter() {
+            put_u8(&mut writer, config.is_on as u8)?;
+            put_u8(&mut writer, config.gain_cmd)?;
+            put_u8(&mut writer, config.offset_cmd)?;
+            put_f64(&mut writer, config.range.min_voltage)?;
+            put_f64(&mut writer, config.range.max_voltage)?;
+            put_f64(&mut writer, config.range.resolution)?;
+            put_scaling(&mut writer, &config.scaling)?;
+        }
+
+        if let Some(stimulus) = metadata.stimulus {
+            put_u8(&mut writer, RECORD_STIMULUS)?;
+            put_f64(&mut writer, stimulus.offset)?;
+            put_f64(&mut writer, stimulus.uncertainty)?;
+        }
+
+        Ok(CaptureWriter {
+            writer,
+            channels: (0..4).filter(|&ch| metadata.channels[ch].is_on).collect(),
+            block: Vec::with_capacity(SAMPLES_PER_BLOCK),
+            samples_written: 0,
+        })
+    }
+
+    /// Adds a sample to the capture
+    pub fn write_sample(&mut self, sample: &Sample) -> Result<(), Box<dyn Error>> {
+        self.block.push(sample.raw);
+        self.samples_written += 1;
+        if self.block.len() == SAMPLES_PER_BLOCK {
+            self.flush_block()?;
+        }
+        Ok(())
+    }
+
+    /// Adds an entry to the power log, after the samples written so far
+    pub fn write_power(&mut self, entry: &PowerLogEntry) -> Result<(), Box<dyn Error>> {
+        self.flush_block()?;
+        put_u8(&mut self.writer, RECORD_POWER)?;
+        put_f64(&mut self.writer, entry.time)?;
+        put_u8(&mut self.writer, power_state_code(entry.status.state))?;
+        put_f64(&mut self.writer, entry.status.usage)?;
+        Ok(())
+    }
+
+    pub fn samples_written(&self) -> u32 {
+        self.samples_written
+    }
+
+    /// Ends the capture and returns the underlying writer
+    pub fn finish(mut self) -> Result<W, Box<dyn Error>> {
+        self.flush_block()?;
+        put_u8(&mut self.writer, RECORD_END)?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    fn flush_block(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        put_u8(&mut self.writer, RECORD_SAMPLES)?;
+        put_u32(&mut self.writer, self.block.len() as u32)?;
+        for raw in self.block.iter() {
+            for &ch in self.channels.iter() {
+                put_u16(&mut self.writer, raw[ch].unwrap_or(MISSING_CODE))?;
+            }
+        }
+        self.block.clear();
+        Ok(())
+    }
+}
+
+/// Streams a sweep from an nLab to a capture file, blocking until the sweep finishes
+///
+/// The power status of the nLab is logged as the samples arrive. Returns the number of samples
+/// written.
+pub fn record<W: Write>(nlab: &Nlab, handle: SweepHandle, writer: W) -> Result<u32, Box<dyn Error>> {
+    let mut capture = CaptureWriter::new(writer, &DeviceIdentity::of(nlab), handle.metadata())?;
+    let mut last_power_log: Option<Instant> = None;
+    let mut time = 0.0;
+
+    for sample in handle.receiver.iter() {
+        let log_power = match last_power_log {
+            Some(last) => last.elapsed() >= POWER_LOG_INTERVAL,
+            None => true,
+        };
+        if log_power {
+            if let Ok(status) = nlab.power_status() {
+                capture.write_power(&PowerLogEntry { time, status })?;
+            }
+            last_power_log = Some(Instant::now());
+        }
+        capture.write_sample(&sample)?;
+        time = sample.time_since_start;
+    }
+    if let Ok(status) = nlab.power_status() {
+        capture.write_power(&PowerLogEntry { time, status })?;
+    }
+
+    let written = capture.samples_written();
+    capture.finish()?;
+    Ok(written)
+}
+
+/// A capture file read into memory
+#[derive(Debug, Clone)]
+pub struct CaptureFile {
+    pub device: DeviceIdentity,
+    pub metadata: SweepMetadata,
+    pub power_log: Vec<PowerLogEntry>,
+    channels: [AnalogInput; 4],
+    codes: Vec<[Option<u16>; 4]>,
+}
+
+impl CaptureFile {
+    pub fn read<R: Read>(mut reader: R) -> Result<Self, Box<dyn Error>> {
+        let mut magic = [0u8; 7];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err("Not an nLab capture file".into());
+        }
+        let version = get_u8(&mut reader)?;
+        if version != FORMAT_VERSION {
+            return Err(format!("Unsupported capture file version: {}", version).into());
+        }
+
+        let legacy = get_u8(&mut reader)? != 0;
+        let has_firmware_version = get_u8(&mut reader)? != 0;
+        let firmware_version = get_u16(&mut reader)?;
+        let device = DeviceIdentity {
+            legacy,
+            firmware_version: if has_firmware_version { Some(firmware_version) } else { None },
+            api_version: get_str(&mut reader)?,
+        };
+
+        let start_secs = get_u64(&mut reader)?;
+        let start_nanos = get_u32(&mut reader)?;
+        if start_nanos >= 1_000_000_000 {
+            return Err(format!("Invalid start time in capture file: {} ns", start_nanos).into());
+        }
+        let start_time = UNIX_EPOCH
+            .checked_add(Duration::new(start_secs, start_nanos))
+            .ok_or("Invalid start time in capture file")?;
+        let sample_rate_hz = get_f64(&mut reader)?;
+        let requested_sample_rate_hz = get_f64(&mut reader)?;
+        let number_of_samples = get_u32(&mut reader)?;
+
+        let trigger = Trigger {
+            is_enabled: get_u8(&mut reader)? != 0,
+            trigger_type: match get_u8(&mut reader)? {
+                1 => TriggerType::FallingEdge,
+                2 => TriggerType::RisingEdge,
+                kind => return Err(format!("Unknown trigger type in capture file: {}", kind).into()),
+            },
+            source_channel: get_u8(&mut reader)? as usize,
+            trigger_level: get_f64(&mut reader)?,
+            trigger_delay_us: get_u32(&mut reader)?,
+        };
+
+        let mut configs: Vec<ChannelConfig> = Vec::with_capacity(4);
+        let mut channels: Vec<AnalogInput> = Vec::with_capacity(4);
+        for _ in 0..4 {
+            let is_on = get_u8(&mut reader)? != 0;
+            let gain_cmd = get_u8(&mut reader)?;
+            let offset_cmd = get_u8(&mut reader)?;
+            let config = ChannelConfig {
+                is_on,
+                range: ChannelRange {
+                    min_voltage: get_f64(&mut reader)?,
+                    max_voltage: get_f64(&mut reader)?,
+                    resolution: get_f64(&mut reader)?,
+                },
+                scaling: get_scaling(&mut reader)?,
+                gain_cmd,
+                offset_cmd,
+            };
+            channels.push(AnalogInput::from_settings(legacy, &config));
+            configs.push(config);
+        }
+
+        let on_channels: Vec<usize> = (0..4).filter(|&ch| configs[ch].is_on).collect();
+        let mut codes = Vec::with_capacity(number_of_samples.min(MAX_PREALLOCATED_SAMPLES) as usize);
+        let mut power_log = Vec::new();
+        let mut stimulus = None;
+        loop {
+            match get_u8(&mut reader)? {
+                RECORD_END => break,
+                RECORD_SAMPLES => {
+                    for _ in 0..get_u32(&mut reader)? {
+                        let mut raw = [None; 4];
+                        for &ch in on_channels.iter() {
+                            let code = get_u16(&mut reader)?;
+                            raw[ch] = if code == MISSING_CODE { None } else { Some(code) };
+                        }
+                        codes.push(raw);
+                    }
+                }
+                RECORD_POWER => {
+                    let time = get_f64(&mut reader)?;
+                    let state = PowerState::from(get_u8(&mut reader)?);
+                    let usage = get_f64(&mut reader)?;
+                    power_log.push(PowerLogEntry { time, status: PowerStatus { state, usage } });
+                }
+                RECORD_STIMULUS => {
+                    let offset = get_f64(&mut reader)?;
+                    let uncertainty = get_f64(&mut reader)?;
+                    stimulus = Some(StimulusTiming { offset, uncertainty });
+                }
+                record => return Err(format!("Unknown record in capture file: {}", record).into()),
+            }
+        }
+
+        let metadata = SweepMetadata {
+            sample_rate_hz,
+            requested_sample_rate_hz,
+            number_of_samples,
+            channels: [configs[0].clone(), configs[1].clone(), configs[2].clone(), configs[3].clone()],
+            trigger,
+            firmware_version: device.firmware_version,
+            start_time,
+            stimulus,
+        };
+        let channels = [channels[0].clone(), channels[1].clone(), channels[2].clone(), channels[3].clone()];
+
+        Ok(CaptureFile { device, metadata, power_log, channels, codes })
+    }
+
+    /// Number of samples in the capture
+    pub fn len(&self) -> usize {
+        self.codes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.codes.is_empty()
+    }
+
+    /// Returns the sample at an index, as the nLab reported it
+    pub fn sample(&self, index: usize) -> Option<Sample> {
+        let raw = *self.codes.get(index)?;
+        Some(sample_from_measurements(&self.channels, self.metadata.sample_rate_hz, index as u32, raw))
+    }
+
+    /// Returns all the samples of the capture as a finished sweep
+    pub fn sweep(&self) -> Sweep {
+        let samples: Vec<Sample> = (0..self.len()).filter_map(|i| self.sample(i)).collect();
+        let mut clipped_samples = [0; 4];
+        for sample in samples.iter() {
+            for (count, &clipped) in clipped_samples.iter_mut().zip(sample.clipped.iter()) {
+                *count += clipped as u32;
+            }
+        }
+        Sweep { metadata: self.metadata.clone(), samples, clipped_samples }
+    }
+
+    /// Plays the capture back through a `SweepHandle`, like a live sweep from an nLab
+    ///
+    /// With `real_time`, samples are delivered at the rate they were recorded; otherwise they
+    /// are delivered as fast as they are read. The metadata reports the original start time.
+    pub fn replay(&self, real_time: bool) -> SweepHandle {
+        let (tx, rx) = mpsc::channel::<Sample>();
+        let (stop_send, stop_recv) = mpsc::channel::<()>();
+        let remaining = Arc::new(RwLock::new(self.len() as u32));
+        let clipped = Arc::new(RwLock::new([0; 4]));
+
+        let capture = self.clone();
+        let backend_remaining = remaining.clone();
+        let backend_clipped = clipped.clone();
+        let replay_thread = thread::Builder::new().name("Replay Thread".to_string());
+        let spawned = replay_thread.spawn(move || {
+            let start = Instant::now();
+            for index in 0..capture.len() {
+                if stop_recv.try_recv().is_ok() {
+                    break;
+                }
+                let sample = capture.sample(index).unwrap();
+                if real_time {
+                    let due = Duration::from_secs_f64(sample.time_since_start);
+                    if let Some(wait) = due.checked_sub(start.elapsed()) {
+                        thread::sleep(wait);
+                    }
+                }
+                for (count, &is_clipped) in backend_clipped.write().unwrap().iter_mut().zip(sample.clipped.iter()) {
+                    *count += is_clipped as u32;
+                }
+                *backend_remaining.write().unwrap() -= 1;
+                if tx.send(sample).is_err() {
+                    break;
+                }
+            }
+            *backend_remaining.write().unwrap() = 0;
+        });
+        if spawned.is_err() {
+            *remaining.write().unwrap() = 0;
+        }
+
+        SweepHandle::new(rx, self.metadata.clone(), remaining, clipped, stop_send)
+    }
+}
+
+fn power_state_code(state: PowerState) -> u8 {
+    match state {
+        PowerState::PowerOff => 0,
+        PowerState::PowerOn => 1,
+        PowerState::Shorted => 2,
+        PowerState::Overcurrent => 3,
+        PowerState::Startup => 4,
+        PowerState::Unknown => 5,
+    }
+}
+
+fn put_scaling<W: Write>(writer: &mut W, scaling: &ChannelScaling) -> Result<(), Box<dyn Error>> {
+    put_f64(writer, scaling.scale)?;
+    put_f64(writer, scaling.offset)?;
+    put_str(writer, &scaling.unit)?;
+    match &scaling.transform {
+        None => put_u8(writer, 0)?,
+        Some(SensorTransform::Polynomial(coefficients)) => {
+            put_u8(writer, 1)?;
+            put_u32(writer, coefficients.len() as u32)?;
+            for &c in coefficients.iter() {
+                put_f64(writer, c)?;
+            }
+        }
+        Some(SensorTransform::LookupTable(points)) => {
+            put_u8(writer, 2)?;
+            put_u32(writer, points.len() as u32)?;
+            for &(input, output) in points.iter() {
+                put_f64(writer, input)?;
+                put_f64(writer, output)?;
+            }
+        }
+        Some(SensorTransform::SteinhartHart { a, b, c, series_resistance, excitation_voltage }) => {
+            put_u8(writer, 3)?;
+            for &value in [a, b, c, series_resistance, excitation_voltage].iter() {
+                put_f64(writer, *value)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn get_scaling<R: Read>(reader: &mut R) -> Result<ChannelScaling, Box<dyn Error>> {
+    let scale = get_f64(reader)?;
+    let offset = get_f64(reader)?;
+    let unit: Arc<str> = get_str(reader)?.into();
+    let transform = match get_u8(reader)? {
+        0 => None,
+        1 => {
+            let n = get_u32(reader)?;
+            Some(SensorTransform::Polynomial((0..n).map(|_| get_f64(reader)).collect::<Result<_, _>>()?))
+        }
+        2 => {
+            let n = get_u32(reader)?;
+            let points = (0..n)
+                .map(|_| Ok((get_f64(reader)?, get_f64(reader)?)))
+                .collect::<Result<_, Box<dyn Error>>>()?;
+            Some(SensorTransform::LookupTable(points))
+        }
+        3 => Some(SensorTransform::SteinhartHart {
+            a: get_f64(reader)?,
+            b: get_f64(reader)?,
+            c: get_f64(reader)?,
+            series_resistance: get_f64(reader)?,
+            excitation_voltage: get_f64(reader)?,
+        }),
+        kind => return Err(format!("Unknown sensor transform in capture file: {}", kind).into()),
+    };
+    Ok(ChannelScaling { scale, offset, unit, transform })
+}
+
+fn put_u8<W: Write>(writer: &mut W, value: u8) -> Result<(), Box<dyn Error>> {
+    Ok(writer.write_all(&[value])?)
+}
+
+fn put_u16<W: Write>(writer: &mut W, value: u16) -> Result<(), Box<dyn Error>> {
+    Ok(writer.write_all(&value.to_le_bytes())?)
+}
+
+fn put_u32<W: Write>(writer: &mut W, value: u32) -> Result<(), Box<dyn Error>> {
+    Ok(writer.write_all(&value.to_le_bytes())?)
+}
+
+fn put_u64<W: Write>(writer: &mut W, value: u64) -> Result<(), Box<dyn Error>> {
+    Ok(writer.write_all(&value.to_le_bytes())?)
+}
+
+fn put_f64<W: Write>(writer: &mut W, value: f64) -> Result<(), Box<dyn Error>> {
+    Ok(writer.write_all(&value.to_le_bytes())?)
+}
+
+fn put_str<W: Write>(writer: &mut W, value: &str) -> Result<(), Box<dyn Error>> {
+    let len = u16::try_from(value.len()).map_err(|_| format!("String of {} bytes is too long for a capture file", value.len()))?;
+    put_u16(writer, len)?;
+    Ok(writer.write_all(value.as_bytes())?)
+}
+
+fn get_bytes<R: Read, const N: usize>(reader: &mut R) -> Result<[u8; N], Box<dyn Error>> {
+    let mut bytes = [0u8; N];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn get_u8<R: Read>(reader: &mut R) -> Result<u8, Box<dyn Error>> {
+    Ok(get_bytes::<R, 1>(reader)?[0])
+}
+
+fn get_u16<R: Read>(reader: &mut R) -> Result<u16, Box<dyn Error>> {
+    Ok(u16::from_le_bytes(get_bytes(reader)?))
+}
+
+fn get_u32<R: Read>(reader: &mut R) -> Result<u32, Box<dyn Error>> {
+    Ok(u32::from_le_bytes(get_bytes(reader)?))
+}
+
+fn get_u64<R: Read>(reader: &mut R) -> Result<u64, Box<dyn Error>> {
+    Ok(u64::from_le_bytes(get_bytes(reader)?))
+}
+
+fn get_f64<R: Read>(reader: &mut R) -> Result<f64, Box<dyn Error>> {
+    Ok(f64::from_le_bytes(get_bytes(reader)?))
+}
+
+fn get_str<R: Read>(reader: &mut R) -> Result<String, Box<dyn Error>> {
+    let len = get_u16(reader)? as usize;
+    let mut bytes = vec![0u8; len];
+    reader.read_exact(&mut bytes)?;
+    Ok(String::from_utf8(bytes)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replays_the_recorded_samples() {
+        let mut channels = [AnalogInput::create(true), AnalogInput::create(true), AnalogInput::create(true), AnalogInput::create(true)];
+        channels[0].set_range(-1.0, 3.0).unwrap();
+        channels[1].set_scaling(ChannelScaling::probe(10.0));
+        channels[2].turn_off();
+        channels[3].turn_off();
+
+        let metadata = SweepMetadata {
+            sample_rate_hz: 1000.0,
+            requested_sample_rate_hz: 1000.0,
+            number_of_samples: 600,
+            channels: [channels[0].config(), channels[1].config(), channels[2].config(), channels[3].config()],
+            trigger: Trigger::default(),
+            firmware_version: Some(0x15),
+            start_time: UNIX_EPOCH + Duration::from_millis(1_700_000_000_123),
+            stimulus: Some(StimulusTiming { offset: -0.0015, uncertainty: 0.0008 }),
+        };
+        let device = DeviceIdentity { legacy: true, firmware_version: Some(0x15), api_version: "1.0.3".into() };
+        let recorded: Vec<Sample> = (0..600u32)
+            .map(|i| sample_from_measurements(&channels, 1000.0, i, [Some((i * 7 % 4096) as u16), Some(2048), None, None]))
+            .collect();
+
+        let mut writer = CaptureWriter::new(Vec::new(), &device, &metadata).unwrap();
+        for sample in recorded.iter() {
+            writer.write_sample(sample).unwrap();
+        }
+        let status = PowerStatus { state: PowerState::PowerOn, usage: 0.25 };
+        writer.write_power(&PowerLogEntry { time: 0.6, status }).unwrap();
+        let bytes = writer.finish().unwrap();
+
+        let capture = CaptureFile::read(bytes.as_slice()).unwrap();
+        assert_eq!(capture.device, device);
+        assert_eq!(capture.metadata.channels, metadata.channels);
+        assert_eq!(capture.metadata.start_time, metadata.start_time);
+        assert_eq!(capture.metadata.stimulus, metadata.stimulus);
+        assert_eq!(capture.power_log.len(), 1);
+
+        let replayed: Vec<Sample> = capture.replay(false).receiver.iter().collect();
+        assert_eq!(replayed.len(), recorded.len());
+        for (a, b) in replayed.iter().zip(recorded.iter()) {
+            assert_eq!((a.time_since_start, a.data, a.raw, a.clipped), (b.time_since_start, b.data, b.raw, b.clipped));
+        }
+    }
+
+    #[test]
+    fn does_not_trust_the_header() {
+        let config = AnalogInput::create(false).config();
+        let metadata = SweepMetadata {
+            sample_rate_hz: 1000.0,
+            requested_sample_rate_hz: 1000.0,
+            number_of_samples: u32::MAX,
+            channels: [config.clone(), config.clone(), config.clone(), config],
+            trigger: Trigger::default(),
+            firmware_version: None,
+            start_time: UNIX_EPOCH,
+            stimulus: None,
+        };
+        let device = DeviceIdentity { legacy: false, firmware_version: None, api_version: "1.0.3".into() };
+        let bytes = CaptureWriter::new(Vec::new(), &device, &metadata).unwrap().finish().unwrap();
+
+        // The sample count is only a hint for the allocation
+        let capture = CaptureFile::read(bytes.as_slice()).unwrap();
+        assert_eq!(capture.metadata.number_of_samples, u32::MAX);
+        assert!(capture.is_empty());
+
+        // The start time follows the magic, version, device identity and API version
+        let start = 7 + 1 + 4 + 2 + device.api_version.len();
+        let mut corrupt = bytes.clone();
+        corrupt[start + 8..start + 12].copy_from_slice(&1_000_000_000u32.to_le_bytes());
+        assert!(CaptureFile::read(corrupt.as_slice()).is_err());
+        let mut corrupt = bytes.clone();
+        corrupt[start..start + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(CaptureFile::read(corrupt.as_slice()).is_err());
+
+        // The trigger type follows the start time, sample rates, sample count and trigger enable
+        let trigger_type = start + 12 + 8 + 8 + 4 + 1;
+        assert_eq!(bytes[trigger_type], metadata.trigger.trigger_type.value());
+        let mut corrupt = bytes;
+        corrupt[trigger_type] = 7;
+        let error = CaptureFile::read(corrupt.as_slice()).unwrap_err();
+        assert_eq!(error.to_string(), "Unknown trigger type in capture file: 7");
+    }
+
+    #[test]
+    fn rejects_strings_too_long_for_their_length() {
+        let config = AnalogInput::create(false).config();
+        let metadata = SweepMetadata {
+            sample_rate_hz: 1000.0,
+            requested_sample_rate_hz: 1000.0,
+            number_of_samples: 0,
+            channels: [config.clone(), config.clone(), config.clone(), config],
+            trigger: Trigger::default(),
+            firmware_version: None,
+            start_time: UNIX_EPOCH,
+            stimulus: None,
+        };
+        let device = DeviceIdentity { legacy: false, firmware_version: None, api_version: "v".repeat(u16::MAX as usize + 1) };
+        assert!(CaptureWriter::new(Vec::new(), &device, &metadata).is_err());
+
+        let device = DeviceIdentity { api_version: "v".repeat(u16::MAX as usize), ..device };
+        let bytes = CaptureWriter::new(Vec::new(), &device, &metadata).unwrap().finish().unwrap();
+        assert_eq!(CaptureFile::read(bytes.as_slice()).unwrap().device, device);
+    }
+}
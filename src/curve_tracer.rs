@@ -0,0 +1,349 @@
+/***************************************************************************************************
+ *
+ *  nLabs, LLC
+ *  https://getnlab.com
+ *  Copyright(c) 2020. All Rights Reserved
+ *
+ *  This file is part of the nLab API
+ *
+ **************************************************************************************************/
+
+//! Current-voltage characterization of two-terminal devices
+//!
+//! A triangle ramp from `a1` or `a2` drives a sense resistor in series with the device under
+//! test, with the device connected to ground:
+//!
+//! ```text
+//!   a1 ──┬── R ──┬── device ── GND
+//!        │       │
+//!    source   device
+//!    channel  channel
+//! ```
+//!
+//! The device channel measures the voltage across the device, and the current is the voltage
+//! across the resistor divided by its resistance. Transistors can be traced one junction at a
+//! time, or collector-emitter with the base biased externally.
+
+use std::error::Error;
+use std::thread;
+use std::time::Duration;
+
+use crate::measure::regression;
+use crate::scope::data_requests::{buffer_samples, effective_sample_rate, sample_clock};
+use crate::{AnalogOutputState, AnalogSignalPolarity, AnalogWaveType, Nlab};
+
+/// Thermal voltage kT/q at 300 K
+const THERMAL_VOLTAGE: f64 = 0.025852;
+
+/// Settings for an I-V capture
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CurveTracerSettings {
+    /// Analog output driving the resistor, 1 for `a1` or 2 for `a2`
+    pub output: usize,
+    /// Scope channel measuring the output side of the sense resistor, 1 through 4
+    pub source_channel: usize,
+    /// Scope channel measuring the node between the sense resistor and the device, 1 through 4
+    pub device_channel: usize,
+    /// Sense resistance in Ohms
+    pub sense_resistance: f64,
+    pub amplitude: f64,
+    /// Unipolar traces the first quadrant only, Bipolar also traces reverse bias
+    pub polarity: AnalogSignalPolarity,
+    /// Frequency of the triangle ramp in Hz
+    pub frequency: f64,
+    /// Number of ramp periods to capture
+    pub periods: u32,
+    /// Time to wait after starting the ramp, before capturing
+    pub settle_time: Duration,
+    /// Model fitted to the curve, or None to return only the points
+    pub model: Option<DeviceModel>,
+}
+
+impl Default for CurveTracerSettings {
+    fn default() -> Self {
+        CurveTracerSettings {
+            output: 1,
+            source_channel: 1,
+            device_channel: 2,
+            sense_resistance: 100.0,
+            amplitude: 5.0,
+            polarity: AnalogSignalPolarity::Unipolar,
+            frequency: 10.0,
+            periods: 1,
+            settle_time: Duration::from_millis(100),
+            model: Some(DeviceModel::Diode { min_current: 1e-4, max_current: 10e-3, reference_current: 1e-3 }),
+        }
+    }
+}
+
+/// Kind of device under test, which decides the model fitted to its curve
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DeviceModel {
+    /// Diode or LED, fitted with [`IvCurve::fit_diode`]
+    Diode {
+        /// Lowest current in the fit, well above the current noise floor
+        min_current: f64,
+        /// Highest current in the fit, below where series resistance bends the curve
+        max_current: f64,
+        /// Current at which the forward voltage is reported
+        reference_current: f64,
+    },
+    /// Resistive device, fitted with [`IvCurve::fit_linear`]
+    Resistor,
+}
+
+/// A single point of an I-V curve
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct IvPoint {
+    /// Voltage across the device
+    pub voltage: f64,
+    /// Current through the device in Amps
+    pub current: f64,
+}
+
+/// Measured current-voltage characteristic of a device, in capture order
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct IvCurve {
+    pub points: Vec<IvPoint>,
+    /// True if either channel saturated during the capture
+    pub clipped: bool,
+    /// Model fitted by [`trace`], or None if no model was requested or the points do not fit it
+    pub fit: Option<CurveFit>,
+}
+
+/// Parameters of the Shockley diode equation fitted to the forward region of a curve
+#[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DiodeFit {
+    /// Saturation current Is in Amps
+    pub saturation_current: f64,
+    /// Ideality factor n
+    pub ideality_factor: f64,
+    /// Measured voltage at which the current first reaches the reference current
+    pub forward_voltage: Option<f64>,
+    /// Current at which the forward voltage was measured, in Amps
+    pub reference_current: f64,
+}
+
+/// Model fitted to a curve
+#[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum CurveFit {
+    Diode(DiodeFit),
+    Linear(LinearFit),
+}
+
+/// Straight line fitted to a curve, for resistive devices
+#[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LinearFit {
+    /// Resistance in Ohms, the inverse of the slope of current against voltage
+    pub resistance: f64,
+    /// Voltage at which the current is zero
+    pub offset_voltage: f64,
+}
+
+impl IvCurve {
+    /// Builds a curve from voltages measured on both sides of a sense resistor
+    pub fn from_voltages(source: &[f64], device: &[f64], sense_resistance: f64) -> Self {
+        let points = source
+            .iter()
+            .zip(device.iter())
+            .map(|(&vs, &vd)| IvPoint {
+                voltage: vd,
+                current: (vs - vd) / sense_resistance,
+            })
+            .collect();
+        IvCurve { points, clipped: false, fit: None }
+    }
+
+    /// Fits a device model to the curve
+    pub fn fit(&self, model: &DeviceModel) -> Option<CurveFit> {
+        match *model {
+            DeviceModel::Diode { min_current, max_current, reference_current } => {
+                self.fit_diode(min_current, max_current, reference_current).map(CurveFit::Diode)
+            }
+            DeviceModel::Resistor => self.fit_linear().map(CurveFit::Linear),
+        }
+    }
+
+    /// Fits the diode equation `I = Is * (exp(V / (n * Vt)) - 1)` to points with currents
+    /// between `min_current` and `max_current`
+    ///
+    /// `min_current` should be well above the current noise floor, which is the voltage
+    /// resolution of the channels divided by the sense resistance. The forward voltage is
+    /// reported at `reference_current`.
+    pub fn fit_diode(&self, min_current: f64, max_current: f64, reference_current: f64) -> Option<DiodeFit> {
+        let (v, ln_i): (Vec<f64>, Vec<f64>) = self
+            .points
+            .iter()
+            .filter(|p| p.current >= min_current && p.current <= max_current)
+            .map(|p| (p.voltage, p.current.ln()))
+            .unzip();
+
+        let (slope, intercept) = regression(&v, &ln_i)?;
+        if slope <= 0.0 {
+            return None;
+        }
+
+        Some(DiodeFit {
+            saturation_current: intercept.exp(),
+            ideality_factor: 1.0 / (slope * THERMAL_VOLTAGE),
+            forward_voltage: self.voltage_at_current(reference_current),
+            reference_current,
+        })
+    }
+
+    /// Fits a straight line to all points of the curve
+    pub fn fit_linear(&self) -> Option<LinearFit> {
+        let (v, i): (Vec<f64>, Vec<f64>) = self.points.iter().map(|p| (p.voltage, p.current)).unzip();
+        let (slope, intercept) = regression(&v, &i)?;
+        if slope == 0.0 {
+            return None;
+        }
+        Some(LinearFit {
+            resistance: 1.0 / slope,
+            offset_voltage: -intercept / slope,
+        })
+    }
+
+    /// Lowest voltage at which the current reaches `current`, interpolated between points
+    pub fn voltage_at_current(&self, current: f64) -> Option<f64> {
+        let mut points = self.points.clone();
+        points.sort_by(|a, b| a.voltage.total_cmp(&b.voltage));
+        points.windows(2).find_map(|pair| {
+            let (a, b) = (pair[0], pair[1]);
+            if a.current < current && b.current >= current {
+                Some(a.voltage + (b.voltage - a.voltage) * (current - a.current) / (b.current - a.current))
+            } else {
+                None
+            }
+        })
+    }
+}
+
+/// Ramps the output across the device and captures its I-V curve
+///
+/// Only the source and device channels are turned on during the capture; the previous channel
+/// states are restored and the output is turned off when it finishes.
+///
+/// The model in `settings` is fitted to the curve, which reports the forward voltage and
+/// ideality factor of a diode:
+///
+/// ```rust,no_run
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use nlabapi::curve_tracer::{self, CurveFit, CurveTracerSettings};
+///
+/// let bench = nlabapi::LabBench::new()?;
+/// let mut nlab = bench.open_first_available(true)?;
+/// let curve = curve_tracer::trace(&mut nlab, &CurveTracerSettings::default())?;
+/// if let Some(CurveFit::Diode(fit)) = curve.fit {
+///     println!("Vf = {:?} V, n = {}", fit.forward_voltage, fit.ideality_factor);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub fn trace(nlab: &mut Nlab, settings: &CurveTracerSettings) -> Result<IvCurve, Box<dyn Error>> {
+    let channels = [settings.source_channel, settings.device_channel];
+    if channels.iter().any(|&ch| nlab.channel(ch).is_none()) || channels[0] == channels[1] {
+        return Err("Source and device must be two different channels between 1 and 4".into());
+    }
+    if nlab.analog_output(settings.output).is_none() {
+        return Err(format!("Invalid analog output: {}", settings.output).into());
+    }
+    if settings.sense_resistance <= 0.0 {
+        return Err("Sense resistance must be positive".into());
+    }
+
+    let result = nlab.with_channels(&channels, |nlab| capture(nlab, settings));
+    let turned_off = nlab.analog_output(settings.output).unwrap().turn_off();
+    result.and_then(|value| turned_off.map(|()| value))
+}
+
+fn capture(nlab: &Nlab, settings: &CurveTracerSettings) -> Result<IvCurve, Box<dyn Error>> {
+    let output = nlab.analog_output(settings.output).unwrap();
+    output.set_state(AnalogOutputState {
+        is_on: true,
+        frequency: settings.frequency,
+        amplitude: settings.amplitude,
+        wave_type: AnalogWaveType::Triangle,
+        polarity: settings.polarity,
+        modulation: None,
+    })?;
+
+    // Spread the capture across the nLab's buffer so that any sample rate can be recorded
+    let max_samples = buffer_samples(2, nlab.is_legacy());
+    let frequency = output.frequency();
+    let periods = settings.periods.max(1) as f64;
+    let requested_rate = (max_samples as f64 * frequency / periods).min(sample_clock(2, nlab.is_legacy()));
+    let sample_rate = effective_sample_rate(requested_rate, 2, nlab.is_legacy());
+    let number_of_samples = ((periods * sample_rate / frequency).round() as u32).min(max_samples);
+
+    thread::sleep(settings.settle_time);
+
+    let sweep = nlab.request(requested_rate, number_of_samples, None).into_sweep();
+    let source = sweep
+        .channel(settings.source_channel)
+        .ok_or("No data received on the source channel")?;
+    let device = sweep
+        .channel(settings.device_channel)
+        .ok_or("No data received on the device channel")?;
+    if source.values.len() < number_of_samples as usize || device.values.len() < number_of_samples as usize {
+        return Err("Incomplete capture".into());
+    }
+
+    let mut curve = IvCurve::from_voltages(&source.values, &device.values, settings.sense_resistance);
+    curve.clipped = source.clipped_samples > 0 || device.clipped_samples > 0;
+    curve.fit = settings.model.and_then(|model| curve.fit(&model));
+    Ok(curve)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fits_an_ideal_diode() {
+        let (is, n) = (1e-12, 1.8);
+        let points = (0..200)
+            .map(|i| {
+                let voltage = i as f64 * 0.005;
+                IvPoint { voltage, current: is * ((voltage / (n * THERMAL_VOLTAGE)).exp() - 1.0) }
+            })
+            .collect();
+        let curve = IvCurve { points, clipped: false, fit: None };
+        let fit = curve.fit_diode(1e-5, 0.1, 1e-3).unwrap();
+
+        assert!((fit.ideality_factor - n).abs() < 0.01, "n: {}", fit.ideality_factor);
+        assert!((fit.saturation_current / is - 1.0).abs() < 0.05, "Is: {}", fit.saturation_current);
+        let expected_vf = n * THERMAL_VOLTAGE * (1e-3 / is + 1.0).ln();
+        assert!((fit.forward_voltage.unwrap() - expected_vf).abs() < 0.005);
+
+        let model = DeviceModel::Diode { min_current: 1e-5, max_current: 0.1, reference_current: 1e-3 };
+        match curve.fit(&model) {
+            Some(CurveFit::Diode(diode)) => assert_eq!(diode.ideality_factor, fit.ideality_factor),
+            other => panic!("Expected a diode fit, got {:?}", other),
+        }
+        // No points within the currents of the model
+        let model = DeviceModel::Diode { min_current: 1.0, max_current: 2.0, reference_current: 1.5 };
+        assert!(curve.fit(&model).is_none());
+    }
+
+    #[test]
+    fn fits_a_resistor() {
+        let source: Vec<f64> = (0..100).map(|i| i as f64 * 0.05).collect();
+        // 1 kOhm device below a 100 Ohm sense resistor
+        let device: Vec<f64> = source.iter().map(|v| v * 1000.0 / 1100.0).collect();
+        let fit = match IvCurve::from_voltages(&source, &device, 100.0).fit(&DeviceModel::Resistor) {
+            Some(CurveFit::Linear(fit)) => fit,
+            other => panic!("Expected a linear fit, got {:?}", other),
+        };
+
+        assert!((fit.resistance - 1000.0).abs() < 1e-6);
+        assert!(fit.offset_voltage.abs() < 1e-9);
+    }
+}
@@ -0,0 +1,113 @@
+/***************************************************************************************************
+ *
+ *  nLabs, LLC
+ *  https://getnlab.com
+ *  Copyright(c) 2020. All Rights Reserved
+ *
+ *  This file is part of the nLab API
+ *
+ **************************************************************************************************/
+
+//! Serial protocol decoders working on [`LogicCapture`]s
+//!
+//! Channels are numbered 1 through 4, with channel 1 in bit 0 of the capture's state.
+//! Timestamps are in seconds since the start of the sweep, and are only as precise as the sample
+//! period, so the sample rate should be at least ten times the bit rate of the bus.
+
+use std::error::Error;
+
+use crate::logic::{channel_mask, LogicCapture, LOGIC_CHANNELS};
+
+pub mod uart;
+pub mod i2c;
+pub mod spi;
+
+/// Checks that a line of a bus is one of the captured channels
+fn check_channel(capture: &LogicCapture, line: &str, channel: usize) -> Result<(), Box<dyn Error>> {
+    if !(1..=LOGIC_CHANNELS).contains(&channel) {
+        return Err(format!("{} channel must be 1 through {}, got {}", line, LOGIC_CHANNELS, channel).into());
+    }
+    if capture.channels & channel_mask(channel) == 0 {
+        return Err(format!("{} channel {} was not captured", line, channel).into());
+    }
+    Ok(())
+}
+
+/// Level history of a single line of a capture
+struct Line {
+    initial: bool,
+    transitions: Vec<(f64, bool)>,
+    end: f64,
+}
+
+impl Line {
+    fn new(capture: &LogicCapture, channel: usize) -> Self {
+        Line {
+            initial: capture.initial_level(channel),
+            transitions: capture.transitions(channel).collect(),
+            end: capture.duration,
+        }
+    }
+
+    /// Level of the line at `time`
+    fn level_at(&self, time: f64) -> bool {
+        match self.transitions.partition_point(|&(t, _)| t <= time) {
+            0 => self.initial,
+            i => self.transitions[i - 1].1,
+        }
+    }
+
+    /// Time of the first transition to `level` strictly after `time`
+    fn next_transition(&self, time: f64, level: bool) -> Option<f64> {
+        let start = self.transitions.partition_point(|&(t, _)| t <= time);
+        self.transitions[start..].iter().find(|&&(_, l)| l == level).map(|&(t, _)| t)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::logic::LogicEdge;
+
+    use super::*;
+
+    /// Builds a capture sampled at `sample_rate_hz` from per-sample states of all lines
+    pub(super) fn capture(states: &[u8], sample_rate_hz: f64) -> LogicCapture {
+        let mut capture = LogicCapture {
+            channels: 0x0F,
+            initial_state: states[0],
+            edges: Vec::new(),
+            number_of_samples: states.len() as u32,
+            duration: (states.len() - 1) as f64 / sample_rate_hz,
+        };
+        for (i, pair) in states.windows(2).enumerate() {
+            if pair[0] != pair[1] {
+                capture.edges.push(LogicEdge {
+                    time: (i + 1) as f64 / sample_rate_hz,
+                    index: i as u32 + 1,
+                    state: pair[1],
+                    changed: pair[0] ^ pair[1],
+                });
+            }
+        }
+        capture
+    }
+
+    #[test]
+    fn line_levels() {
+        let line = Line::new(&capture(&[1, 1, 0, 0, 1], 1.0), 1);
+        assert!(line.level_at(1.5));
+        assert!(!line.level_at(2.0));
+        assert_eq!(line.next_transition(2.0, true), Some(4.0));
+        assert_eq!(line.next_transition(4.0, false), None);
+    }
+
+    #[test]
+    fn lines_must_be_captured() {
+        let mut capture = capture(&[0, 1, 0], 1.0);
+        capture.channels = 0x05;
+        assert!(check_channel(&capture, "SDA", 3).is_ok());
+        assert!(check_channel(&capture, "SDA", 2).unwrap_err().to_string().contains("SDA"));
+        assert!(check_channel(&capture, "SDA", 0).is_err());
+        assert!(check_channel(&capture, "SDA", 5).is_err());
+    }
+}
@@ -0,0 +1,165 @@
+/***************************************************************************************************
+ *
+ *  nLabs, LLC
+ *  https://getnlab.com
+ *  Copyright(c) 2020. All Rights Reserved
+ *
+ *  This file is part of the nLab API
+ *
+ **************************************************************************************************/
+
+use std::error::Error;
+
+use crate::logic::{channel_mask, LogicCapture};
+
+use super::check_channel;
+
+/// Lines of an I2C bus
+#[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct I2cSettings {
+    /// Channel carrying the clock, 1 through 4
+    pub scl: usize,
+    /// Channel carrying the data, 1 through 4
+    pub sda: usize,
+}
+
+impl Default for I2cSettings {
+    fn default() -> Self {
+        I2cSettings { scl: 1, sda: 2 }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum I2cFrameKind {
+    Start,
+    /// A start condition before the previous transaction was stopped
+    RepeatedStart,
+    Stop,
+    /// The 7-bit address and direction following a start condition
+    Address { address: u8, read: bool },
+    Data(u8),
+    /// A byte interrupted by a start or stop condition after the given number of bits
+    Incomplete { bits: u8 },
+}
+
+/// A condition or byte on an I2C bus
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct I2cFrame {
+    pub start_time: f64,
+    pub end_time: f64,
+    pub kind: I2cFrameKind,
+    /// The receiver did not acknowledge the byte, always false for conditions
+    pub nack: bool,
+}
+
+/// Decodes the conditions and bytes on an I2C bus
+pub fn decode(capture: &LogicCapture, settings: &I2cSettings) -> Result<Vec<I2cFrame>, Box<dyn Error>> {
+    check_channel(capture, "SCL", settings.scl)?;
+    check_channel(capture, "SDA", settings.sda)?;
+    if settings.scl == settings.sda {
+        return Err("SCL and SDA must be on different channels".into());
+    }
+    let (scl_mask, sda_mask) = (channel_mask(settings.scl), channel_mask(settings.sda));
+
+    let mut frames = Vec::new();
+    let mut in_transaction = false;
+    let mut expect_address = false;
+    let mut bits: Vec<bool> = Vec::with_capacity(9);
+    let mut byte_start = 0.0;
+    let mut previous = capture.initial_state;
+
+    for edge in capture.edges.iter() {
+        let scl_high = previous & scl_mask != 0;
+        let condition = |kind| I2cFrame { start_time: edge.time, end_time: edge.time, kind, nack: false };
+
+        if edge.changed & scl_mask != 0 {
+            // Data is valid on the rising edge of the clock
+            if edge.state & scl_mask != 0 && in_transaction {
+                if bits.is_empty() {
+                    byte_start = edge.time;
+                }
+                bits.push(edge.state & sda_mask != 0);
+                if bits.len() == 9 {
+                    let byte = bits[..8].iter().fold(0u8, |byte, &bit| (byte << 1) | bit as u8);
+                    let kind = if expect_address {
+                        I2cFrameKind::Address { address: byte >> 1, read: byte & 1 != 0 }
+                    } else {
+                        I2cFrameKind::Data(byte)
+                    };
+                    frames.push(I2cFrame { start_time: byte_start, end_time: edge.time, kind, nack: bits[8] });
+                    expect_address = false;
+                    bits.clear();
+                }
+            }
+        } else if edge.changed & sda_mask != 0 && scl_high {
+            // Data changing while the clock is high is a start or stop condition. The clock rise
+            // that precedes every condition is counted as a bit, so only longer runs are errors.
+            if bits.len() > 1 {
+                let kind = I2cFrameKind::Incomplete { bits: bits.len() as u8 };
+                frames.push(I2cFrame { start_time: byte_start, end_time: edge.time, kind, nack: false });
+            }
+            bits.clear();
+            if edge.state & sda_mask == 0 {
+                frames.push(condition(if in_transaction { I2cFrameKind::RepeatedStart } else { I2cFrameKind::Start }));
+                in_transaction = true;
+                expect_address = true;
+            } else if in_transaction {
+                frames.push(condition(I2cFrameKind::Stop));
+                in_transaction = false;
+            }
+        }
+        previous = edge.state;
+    }
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::tests::capture;
+
+    /// Bus states with SCL in bit 0 and SDA in bit 1, writing `bytes` to `address`
+    fn transaction(address: u8, bytes: &[u8], ack: bool) -> Vec<u8> {
+        let state = |scl: bool, sda: bool| scl as u8 | (sda as u8) << 1;
+        let mut states = vec![state(true, true), state(true, false), state(false, false)];
+        let mut words = vec![address << 1];
+        words.extend_from_slice(bytes);
+        for word in words {
+            let bits = (0..8).rev().map(|i| (word >> i) & 1 != 0).chain(std::iter::once(!ack));
+            for bit in bits {
+                states.extend([state(false, bit), state(true, bit), state(false, bit)]);
+            }
+        }
+        states.extend([state(false, false), state(true, false), state(true, true)]);
+        states
+    }
+
+    #[test]
+    fn decodes_a_write() {
+        let frames = decode(&capture(&transaction(0x48, &[0x01, 0xA5], true), 1e6), &I2cSettings::default()).unwrap();
+        let kinds: Vec<I2cFrameKind> = frames.iter().map(|frame| frame.kind).collect();
+        assert_eq!(kinds, vec![
+            I2cFrameKind::Start,
+            I2cFrameKind::Address { address: 0x48, read: false },
+            I2cFrameKind::Data(0x01),
+            I2cFrameKind::Data(0xA5),
+            I2cFrameKind::Stop,
+        ]);
+        assert!(frames.iter().all(|frame| !frame.nack));
+
+        let frames = decode(&capture(&transaction(0x48, &[], false), 1e6), &I2cSettings::default()).unwrap();
+        assert!(frames[1].nack);
+    }
+
+    #[test]
+    fn rejects_channels_outside_the_capture() {
+        let capture = capture(&transaction(0x48, &[], true), 1e6);
+        assert!(decode(&capture, &I2cSettings { scl: 8, sda: 2 }).is_err());
+        assert!(decode(&capture, &I2cSettings { scl: 0, sda: 2 }).is_err());
+        assert!(decode(&capture, &I2cSettings { scl: 1, sda: 5 }).is_err());
+        assert!(decode(&capture, &I2cSettings { scl: 2, sda: 2 }).is_err());
+    }
+}
@@ -0,0 +1,165 @@
+/***************************************************************************************************
+ *
+ *  nLabs, LLC
+ *  https://getnlab.com
+ *  Copyright(c) 2020. All Rights Reserved
+ *
+ *  This file is part of the nLab API
+ *
+ **************************************************************************************************/
+
+use std::error::Error;
+
+use crate::logic::{channel_mask, LogicCapture};
+
+use super::check_channel;
+
+/// Lines and framing of an SPI bus, on channels 1 through 4
+#[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SpiSettings {
+    pub clock: usize,
+    pub mosi: Option<usize>,
+    pub miso: Option<usize>,
+    /// Active low chip select, or None to decode every clock edge
+    pub chip_select: Option<usize>,
+    /// SPI mode 0 through 3, combining clock polarity (bit 1) and phase (bit 0)
+    pub mode: u8,
+    /// Bits per word, 1 through 32
+    pub word_bits: u8,
+    pub msb_first: bool,
+}
+
+impl Default for SpiSettings {
+    fn default() -> Self {
+        SpiSettings {
+            clock: 1,
+            mosi: Some(2),
+            miso: Some(3),
+            chip_select: Some(4),
+            mode: 0,
+            word_bits: 8,
+            msb_first: true,
+        }
+    }
+}
+
+/// A word transferred on an SPI bus
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SpiFrame {
+    /// Time of the first sampling clock edge of the word
+    pub start_time: f64,
+    /// Time of the last sampling clock edge of the word
+    pub end_time: f64,
+    pub mosi: Option<u32>,
+    pub miso: Option<u32>,
+    /// Chip select was released before all bits of the word were clocked
+    pub incomplete: bool,
+}
+
+/// Decodes the words transferred on an SPI bus
+pub fn decode(capture: &LogicCapture, settings: &SpiSettings) -> Result<Vec<SpiFrame>, Box<dyn Error>> {
+    if settings.mode > 3 {
+        return Err("SPI mode must be 0 through 3".into());
+    }
+    if !(1..=32).contains(&settings.word_bits) {
+        return Err("SPI words must have 1 to 32 bits".into());
+    }
+    check_channel(capture, "SPI clock", settings.clock)?;
+    let lines = [("MOSI", settings.mosi), ("MISO", settings.miso), ("SPI chip select", settings.chip_select)];
+    for (line, channel) in lines.iter() {
+        if let Some(channel) = channel {
+            check_channel(capture, line, *channel)?;
+        }
+    }
+
+    // Data is sampled on the rising clock edge when polarity and phase are equal
+    let sample_on_rising = (settings.mode >> 1) == (settings.mode & 1);
+    let clock_mask = channel_mask(settings.clock);
+    let line = |channel: Option<usize>, state: u8| channel.map(|ch| state & channel_mask(ch) != 0);
+    let selected = |state: u8| match settings.chip_select {
+        Some(cs) => state & channel_mask(cs) == 0,
+        None => true,
+    };
+
+    let mut frames = Vec::new();
+    let mut bits: Vec<(Option<bool>, Option<bool>)> = Vec::with_capacity(settings.word_bits as usize);
+    let mut word_start = 0.0;
+    let mut word_end = 0.0;
+    let mut previous = capture.initial_state;
+
+    let word = |bits: &[(Option<bool>, Option<bool>)], mosi: bool| -> Option<u32> {
+        let values: Option<Vec<bool>> = bits.iter().map(|&(o, i)| if mosi { o } else { i }).collect();
+        values.map(|values| {
+            let mut word = 0u32;
+            for (i, &bit) in values.iter().enumerate() {
+                let position = if settings.msb_first { values.len() - 1 - i } else { i };
+                word |= (bit as u32) << position;
+            }
+            word
+        })
+    };
+
+    for edge in capture.edges.iter() {
+        if selected(previous) && !selected(edge.state) && !bits.is_empty() {
+            frames.push(SpiFrame {
+                start_time: word_start,
+                end_time: word_end,
+                mosi: word(&bits, true),
+                miso: word(&bits, false),
+                incomplete: true,
+            });
+            bits.clear();
+        }
+
+        let clock_rose = edge.state & clock_mask != 0;
+        if edge.changed & clock_mask != 0 && clock_rose == sample_on_rising && selected(edge.state) {
+            if bits.is_empty() {
+                word_start = edge.time;
+            }
+            word_end = edge.time;
+            bits.push((line(settings.mosi, edge.state), line(settings.miso, edge.state)));
+            if bits.len() == settings.word_bits as usize {
+                frames.push(SpiFrame {
+                    start_time: word_start,
+                    end_time: word_end,
+                    mosi: word(&bits, true),
+                    miso: word(&bits, false),
+                    incomplete: false,
+                });
+                bits.clear();
+            }
+        }
+        previous = edge.state;
+    }
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::tests::capture;
+
+    #[test]
+    fn decodes_mode_0_words() {
+        // Clock in bit 0, MOSI in bit 1, MISO in bit 2, chip select in bit 3
+        let (mosi, miso) = (0xC3u8, 0x5Au8);
+        let mut states = vec![0b1000, 0b0000];
+        for i in (0..8).rev() {
+            let data = ((mosi >> i) & 1) << 1 | ((miso >> i) & 1) << 2;
+            states.extend([data, data | 1]);
+        }
+        // Three bits of a second word, then chip select released
+        states.extend([0b0000, 0b0001, 0b0000, 0b0001, 0b0000, 0b0001, 0b0000, 0b1000]);
+
+        let frames = decode(&capture(&states, 1e6), &SpiSettings::default()).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!((frames[0].mosi, frames[0].miso, frames[0].incomplete), (Some(0xC3), Some(0x5A), false));
+        assert!(frames[1].incomplete);
+
+        let capture = capture(&states, 1e6);
+        assert!(decode(&capture, &SpiSettings { clock: 8, ..Default::default() }).is_err());
+        assert!(decode(&capture, &SpiSettings { chip_select: Some(9), ..Default::default() }).is_err());
+    }
+}
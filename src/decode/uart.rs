@@ -0,0 +1,200 @@
+/***************************************************************************************************
+ *
+ *  nLabs, LLC
+ *  https://getnlab.com
+ *  Copyright(c) 2020. All Rights Reserved
+ *
+ *  This file is part of the nLab API
+ *
+ **************************************************************************************************/
+
+use std::error::Error;
+
+use crate::logic::LogicCapture;
+
+use super::{check_channel, Line};
+
+/// Common baud rates that detected rates are rounded to
+const STANDARD_BAUD_RATES: [f64; 16] = [
+    300.0, 600.0, 1200.0, 2400.0, 4800.0, 9600.0, 14400.0, 19200.0, 28800.0, 31250.0, 38400.0,
+    57600.0, 76800.0, 115200.0, 230400.0, 250000.0,
+];
+/// Largest relative difference for a detected rate to be rounded to a standard rate
+const STANDARD_BAUD_TOLERANCE: f64 = 0.05;
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Parity {
+    None,
+    Even,
+    Odd,
+}
+
+/// Line settings of a UART
+#[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct UartSettings {
+    /// Channel carrying the data, 1 through 4
+    pub channel: usize,
+    /// Bits per second, or None to detect the rate from the capture
+    pub baud_rate: Option<f64>,
+    /// Data bits per frame, 5 through 9
+    pub data_bits: u8,
+    pub parity: Parity,
+    /// Stop bits per frame, 1, 1.5 or 2
+    pub stop_bits: f64,
+    /// True if the line idles low, as on an RS-232 level signal
+    pub inverted: bool,
+}
+
+impl Default for UartSettings {
+    fn default() -> Self {
+        UartSettings {
+            channel: 1,
+            baud_rate: None,
+            data_bits: 8,
+            parity: Parity::None,
+            stop_bits: 1.0,
+            inverted: false,
+        }
+    }
+}
+
+/// A single character received by a UART
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct UartFrame {
+    /// Time of the leading edge of the start bit
+    pub start_time: f64,
+    /// Time of the end of the last stop bit
+    pub end_time: f64,
+    /// Data bits, least significant bit first on the wire
+    pub data: u16,
+    /// The stop bit was not at the idle level
+    pub framing_error: bool,
+    /// The parity bit did not match the data
+    pub parity_error: bool,
+}
+
+/// Estimates the baud rate of a line, 1 through 4, from the widths of its pulses
+///
+/// Needs at least one isolated bit in the capture. Rates within 5% of a common baud rate are
+/// rounded to it.
+pub fn detect_baud_rate(capture: &LogicCapture, channel: usize) -> Option<f64> {
+    let times: Vec<f64> = capture.transitions(channel).map(|(time, _)| time).collect();
+    let widths: Vec<f64> = times.windows(2).map(|pair| pair[1] - pair[0]).collect();
+    let shortest = widths.iter().copied().filter(|&w| w > 0.0).fold(f64::INFINITY, f64::min);
+    if !shortest.is_finite() {
+        return None;
+    }
+
+    // Refine the bit time using every pulse short enough to count its bits reliably
+    let (total_time, total_bits) = widths
+        .iter()
+        .map(|&w| (w, (w / shortest).round()))
+        .filter(|&(_, bits)| bits <= 10.0)
+        .fold((0.0, 0.0), |(time, count), (w, bits)| (time + w, count + bits));
+    let rate = total_bits / total_time;
+
+    let standard = STANDARD_BAUD_RATES
+        .iter()
+        .copied()
+        .find(|&standard| ((rate - standard) / standard).abs() < STANDARD_BAUD_TOLERANCE);
+    Some(standard.unwrap_or(rate))
+}
+
+/// Decodes the characters on a UART line
+pub fn decode(capture: &LogicCapture, settings: &UartSettings) -> Result<Vec<UartFrame>, Box<dyn Error>> {
+    if !(5..=9).contains(&settings.data_bits) {
+        return Err("UART frames must have 5 to 9 data bits".into());
+    }
+    check_channel(capture, "UART", settings.channel)?;
+    let baud_rate = match settings.baud_rate {
+        Some(rate) => rate,
+        None => detect_baud_rate(capture, settings.channel).ok_or("Cannot detect the baud rate of the line")?,
+    };
+    if baud_rate <= 0.0 {
+        return Err("Baud rate must be positive".into());
+    }
+
+    let line = Line::new(capture, settings.channel);
+    let idle = !settings.inverted;
+    let bit = 1.0 / baud_rate;
+    let parity_bits = if settings.parity == Parity::None { 0.0 } else { 1.0 };
+    let data_bits = settings.data_bits as f64;
+    let frame_bits = 1.0 + data_bits + parity_bits + settings.stop_bits;
+
+    let mut frames = Vec::new();
+    let mut time = f64::NEG_INFINITY;
+    while let Some(start) = line.next_transition(time, !idle) {
+        // Logical value of a bit, true at the idle level
+        let sample = |bit_index: f64| line.level_at(start + (bit_index + 0.5) * bit) == idle;
+        let end_time = start + frame_bits * bit;
+        if end_time > line.end + bit / 2.0 {
+            break;
+        }
+
+        // A start bit that does not last to its middle is a glitch
+        if sample(0.0) {
+            time = start;
+            continue;
+        }
+
+        let data = (0..settings.data_bits).fold(0u16, |data, i| data | ((sample(1.0 + i as f64) as u16) << i));
+        let parity_error = match settings.parity {
+            Parity::None => false,
+            parity => {
+                let ones = data.count_ones() + sample(1.0 + data_bits) as u32;
+                (ones & 1 == 0) != (parity == Parity::Even)
+            }
+        };
+        let framing_error = !sample(1.0 + data_bits + parity_bits);
+
+        frames.push(UartFrame { start_time: start, end_time, data, framing_error, parity_error });
+
+        // Look for the next start bit from the middle of the stop bit
+        time = start + (1.5 + data_bits + parity_bits) * bit;
+    }
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::tests::capture;
+
+    /// Line states of 8N1 characters at 10 samples per bit, with idle time between them
+    fn serial(bytes: &[u8], parity: Parity) -> Vec<u8> {
+        let mut states = vec![1; 25];
+        for &byte in bytes {
+            let mut bits = vec![0];
+            bits.extend((0..8).map(|i| (byte >> i) & 1));
+            match parity {
+                Parity::None => {}
+                Parity::Even => bits.push((byte.count_ones() % 2) as u8),
+                Parity::Odd => bits.push((byte.count_ones() % 2 == 0) as u8),
+            }
+            bits.push(1);
+            states.extend(bits.iter().flat_map(|&b| vec![b; 10]));
+            states.extend(vec![1; 17]);
+        }
+        states
+    }
+
+    #[test]
+    fn decodes_with_detected_baud_rate() {
+        // 10 samples per bit at 96 kHz is 9600 baud
+        let capture = capture(&serial(b"nLab", Parity::Even), 96_000.0);
+        assert_eq!(detect_baud_rate(&capture, 1), Some(9600.0));
+        assert_eq!(detect_baud_rate(&capture, 0), None);
+
+        let settings = UartSettings { parity: Parity::Even, ..Default::default() };
+        let frames = decode(&capture, &settings).unwrap();
+        let data: Vec<u8> = frames.iter().map(|frame| frame.data as u8).collect();
+        assert_eq!(data, b"nLab");
+        assert!(frames.iter().all(|frame| !frame.framing_error && !frame.parity_error));
+
+        let settings = UartSettings { parity: Parity::Odd, ..settings };
+        assert!(decode(&capture, &settings).unwrap().iter().all(|frame| frame.parity_error));
+    }
+}
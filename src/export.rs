@@ -0,0 +1,103 @@
+/***************************************************************************************************
+ *
+ *  nLabs, LLC
+ *  https://getnlab.com
+ *  Copyright(c) 2020. All Rights Reserved
+ *
+ *  This file is part of the nLab API
+ *
+ **************************************************************************************************/
+
+//! Reading and writing sweeps in file formats used by other tools
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[cfg(test)]
+use crate::{AnalogInput, Sample, Sweep, SweepMetadata, Trigger};
+
+pub mod csv;
+pub mod sigrok;
+pub mod wav;
+
+/// Formats a time as an ISO 8601 UTC timestamp with millisecond precision
+pub(crate) fn format_timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    let (days, seconds_of_day) = (seconds / 86_400, seconds % 86_400);
+
+    // Civil date from days since 1970-01-01, in the proleptic Gregorian calendar
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        seconds_of_day / 3600,
+        seconds_of_day / 60 % 60,
+        seconds_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Builds a sweep in volts with one scope channel on for each slice of readings
+#[cfg(test)]
+pub(crate) fn test_sweep(sample_rate_hz: f64, readings: &[&[f64]]) -> Sweep {
+    let mut channels = [
+        AnalogInput::create(false).config(),
+        AnalogInput::create(false).config(),
+        AnalogInput::create(false).config(),
+        AnalogInput::create(false).config(),
+    ];
+    for (ch, config) in channels.iter_mut().enumerate() {
+        config.is_on = ch < readings.len();
+    }
+    let number_of_samples = readings.first().map_or(0, |values| values.len());
+    let samples = (0..number_of_samples)
+        .map(|i| {
+            let mut sample = Sample { time_since_start: i as f64 / sample_rate_hz, ..Default::default() };
+            for (ch, values) in readings.iter().enumerate() {
+                sample.data[ch] = Some(values[i]);
+                sample.units[ch] = Some(channels[ch].scaling.unit.clone());
+            }
+            sample
+        })
+        .collect();
+
+    Sweep {
+        metadata: SweepMetadata {
+            sample_rate_hz,
+            requested_sample_rate_hz: sample_rate_hz,
+            number_of_samples: number_of_samples as u32,
+            channels,
+            trigger: Trigger::default(),
+            firmware_version: None,
+            start_time: UNIX_EPOCH,
+            stimulus: None,
+        },
+        samples,
+        clipped_samples: [0; 4],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn formats_utc_timestamps() {
+        assert_eq!(format_timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        let time = UNIX_EPOCH + Duration::from_millis(1_709_210_096_789);
+        assert_eq!(format_timestamp(time), "2024-02-29T12:34:56.789Z");
+    }
+}
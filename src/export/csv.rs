@@ -0,0 +1,199 @@
+/***************************************************************************************************
+ *
+ *  nLabs, LLC
+ *  https://getnlab.com
+ *  Copyright(c) 2020. All Rights Reserved
+ *
+ *  This file is part of the nLab API
+ *
+ **************************************************************************************************/
+
+//! Delimited text export with a commented metadata header
+//!
+//! The file starts with `#` comment lines describing the sweep, followed by a column header and
+//! one row per sample: the time since the start of the sweep in seconds, then one column per
+//! channel that was on. Readings missing from a sample are left empty. Column names and units
+//! that contain the delimiter, a quote or a line break are quoted, with quotes doubled.
+
+use std::borrow::Cow;
+use std::error::Error;
+use std::io::Write;
+
+use crate::{Sample, Sweep, SweepHandle, SweepMetadata};
+
+use super::format_timestamp;
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Delimiter {
+    Comma,
+    Tab,
+}
+
+impl Delimiter {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Delimiter::Comma => ",",
+            Delimiter::Tab => "\t",
+        }
+    }
+}
+
+/// Quotes a field that contains the delimiter, a quote or a line break
+fn quote(field: &str, delimiter: Delimiter) -> Cow<'_, str> {
+    if field.contains(delimiter.as_str()) || field.contains(['"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+/// Writes samples to a CSV or TSV file as they arrive
+#[derive(Debug)]
+pub struct CsvWriter<W: Write> {
+    writer: W,
+    delimiter: Delimiter,
+    channels: Vec<usize>,
+    samples_written: u32,
+}
+
+impl<W: Write> CsvWriter<W> {
+    /// Creates a writer and writes the metadata and column headers of a sweep
+    pub fn new(mut writer: W, metadata: &SweepMetadata, delimiter: Delimiter) -> Result<Self, Box<dyn Error>> {
+        writeln!(writer, "# nLab sweep")?;
+        writeln!(writer, "# start_time: {}", format_timestamp(metadata.start_time))?;
+        match metadata.firmware_version {
+            Some(version) => writeln!(writer, "# firmware_version: 0x{:04X}", version)?,
+            None => writeln!(writer, "# firmware_version: unknown")?,
+        }
+        writeln!(writer, "# sample_rate_hz: {}", metadata.sample_rate_hz)?;
+        writeln!(writer, "# requested_sample_rate_hz: {}", metadata.requested_sample_rate_hz)?;
+        writeln!(writer, "# number_of_samples: {}", metadata.number_of_samples)?;
+
+        let channels: Vec<usize> = (0..4).filter(|&ch| metadata.channels[ch].is_on).collect();
+        for &ch in channels.iter() {
+            let config = &metadata.channels[ch];
+            writeln!(
+                writer,
+                "# ch{}: range {} V to {} V, resolution {} V, scale {}, offset {}, unit {}{}",
+                ch + 1,
+                config.range.min_voltage,
+                config.range.max_voltage,
+                config.range.resolution,
+                config.scaling.scale,
+                config.scaling.offset,
+                // Metadata fields are separated by commas whatever the delimiter of the columns
+                quote(&config.scaling.unit, Delimiter::Comma),
+                if config.scaling.transform.is_some() { ", sensor transform" } else { "" },
+            )?;
+        }
+
+        let trigger = &metadata.trigger;
+        if trigger.is_enabled {
+            writeln!(
+                writer,
+                "# trigger: {:?} on ch{} at {}, delay {} us",
+                trigger.trigger_type,
+                trigger.source_channel + 1,
+                trigger.trigger_level,
+                trigger.trigger_delay_us,
+            )?;
+        } else {
+            writeln!(writer, "# trigger: none")?;
+        }
+
+        let mut columns = vec!["time_s".to_string()];
+        columns.extend(channels.iter().map(|&ch| {
+            let name = format!("ch{}_{}", ch + 1, metadata.channels[ch].scaling.unit);
+            quote(&name, delimiter).into_owned()
+        }));
+        writeln!(writer, "{}", columns.join(delimiter.as_str()))?;
+
+        Ok(CsvWriter { writer, delimiter, channels, samples_written: 0 })
+    }
+
+    /// Writes one row
+    pub fn write_sample(&mut self, sample: &Sample) -> Result<(), Box<dyn Error>> {
+        write!(self.writer, "{}", sample.time_since_start)?;
+        for &ch in self.channels.iter() {
+            write!(self.writer, "{}", self.delimiter.as_str())?;
+            if let Some(value) = sample.data[ch] {
+                write!(self.writer, "{}", value)?;
+            }
+        }
+        writeln!(self.writer)?;
+        self.samples_written += 1;
+        Ok(())
+    }
+
+    pub fn samples_written(&self) -> u32 {
+        self.samples_written
+    }
+
+    /// Flushes the output and returns the underlying writer
+    pub fn finish(mut self) -> Result<W, Box<dyn Error>> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Streams a sweep to `writer` as samples arrive, blocking until the sweep finishes
+///
+/// Only one sample is held in memory at a time, so this suits captures of any length. Returns
+/// the number of samples written.
+pub fn write_sweep_handle<W: Write>(handle: SweepHandle, writer: W, delimiter: Delimiter) -> Result<u32, Box<dyn Error>> {
+    let mut csv = CsvWriter::new(writer, handle.metadata(), delimiter)?;
+    for sample in handle.receiver.iter() {
+        csv.write_sample(&sample)?;
+    }
+    let written = csv.samples_written();
+    csv.finish()?;
+    Ok(written)
+}
+
+/// Writes a finished sweep to `writer`
+pub fn write_sweep<W: Write>(sweep: &Sweep, writer: W, delimiter: Delimiter) -> Result<(), Box<dyn Error>> {
+    let mut csv = CsvWriter::new(writer, &sweep.metadata, delimiter)?;
+    for sample in sweep.samples.iter() {
+        csv.write_sample(sample)?;
+    }
+    csv.finish()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::export::test_sweep;
+
+    use super::*;
+
+    #[test]
+    fn writes_header_and_rows() {
+        let mut sweep = test_sweep(1000.0, &[&[1.0, -0.5, 2.0], &[0.0, 1.5, -2.0]]);
+        sweep.metadata.channels[1].scaling.unit = "m\"s, x".into();
+        sweep.samples[1].data[1] = None;
+
+        let mut file = Vec::new();
+        write_sweep(&sweep, &mut file, Delimiter::Comma).unwrap();
+        let text = String::from_utf8(file).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+
+        assert_eq!(lines[0], "# nLab sweep");
+        assert_eq!(lines[3], "# sample_rate_hz: 1000");
+        assert!(lines[6].starts_with("# ch1: ") && lines[6].ends_with("unit V"));
+        assert!(lines[7].ends_with("unit \"m\"\"s, x\""));
+        assert_eq!(lines[8], "# trigger: none");
+        assert_eq!(lines[9], "time_s,ch1_V,\"ch2_m\"\"s, x\"");
+        assert_eq!(lines[10..], ["0,1,0", "0.001,-0.5,", "0.002,2,-2"]);
+
+        // Tabs do not need quoting for a comma
+        let mut file = Vec::new();
+        write_sweep(&sweep, &mut file, Delimiter::Tab).unwrap();
+        let text = String::from_utf8(file).unwrap();
+        assert!(text.contains("time_s\tch1_V\t\"ch2_m\"\"s, x\"\n"));
+        sweep.metadata.channels[1].scaling.unit = "m, s".into();
+        let mut file = Vec::new();
+        write_sweep(&sweep, &mut file, Delimiter::Tab).unwrap();
+        assert!(String::from_utf8(file).unwrap().contains("time_s\tch1_V\tch2_m, s\n"));
+    }
+}
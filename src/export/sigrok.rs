@@ -0,0 +1,266 @@
+/***************************************************************************************************
+ *
+ *  nLabs, LLC
+ *  https://getnlab.com
+ *  Copyright(c) 2020. All Rights Reserved
+ *
+ *  This file is part of the nLab API
+ *
+ **************************************************************************************************/
+
+//! Export to sigrok session files, for viewing and protocol decoding in PulseView
+//!
+//! A session is a zip archive with a `version` file, an INI style `metadata` file and one data
+//! file per analog channel holding little-endian 32-bit floats. Logic channels from a
+//! [`LogicCapture`] of the same sweep are stored together in a single file, one byte per sample.
+
+use std::convert::TryFrom;
+use std::error::Error;
+use std::io::Write;
+
+use crate::logic::LogicCapture;
+use crate::Sweep;
+
+const SESSION_VERSION: &str = "2";
+
+/// Writes a sweep, and optionally its logic capture, as a sigrok `.sr` session
+///
+/// Analog channels are named `CH1` through `CH4` and logic channels `D1` through `D4` after the
+/// scope channel they were converted from. sigrok stores the sample rate as a whole number of
+/// Hz, so it is rounded.
+pub fn write_session<W: Write>(sweep: &Sweep, logic: Option<&LogicCapture>, writer: W) -> Result<(), Box<dyn Error>> {
+    let analog_channels: Vec<usize> = (0..4).filter(|&ch| sweep.metadata.channels[ch].is_on).collect();
+    let logic_channels: Vec<usize> = match logic {
+        Some(capture) => (0..4).filter(|&ch| capture.channels & (1 << ch) != 0).collect(),
+        None => Vec::new(),
+    };
+
+    let mut metadata = String::new();
+    metadata.push_str("[global]\n");
+    metadata.push_str(&format!("sigrok version=nlabapi {}\n", crate::version()));
+    metadata.push_str("\n[device 1]\n");
+    metadata.push_str(&format!("samplerate={} Hz\n", sweep.metadata.sample_rate_hz.round() as u64));
+    metadata.push_str(&format!("total probes={}\n", logic_channels.len()));
+    metadata.push_str(&format!("total analog={}\n", analog_channels.len()));
+    if !logic_channels.is_empty() {
+        metadata.push_str("capturefile=logic-1\n");
+        metadata.push_str("unitsize=1\n");
+    }
+    for (i, &ch) in logic_channels.iter().enumerate() {
+        metadata.push_str(&format!("probe{}=D{}\n", i + 1, ch + 1));
+    }
+    for (i, &ch) in analog_channels.iter().enumerate() {
+        metadata.push_str(&format!("analog{}=CH{}\n", logic_channels.len() + i + 1, ch + 1));
+    }
+
+    let mut archive = ZipWriter::new(writer);
+    archive.add_file("version", SESSION_VERSION.as_bytes())?;
+    archive.add_file("metadata", metadata.as_bytes())?;
+
+    if let Some(capture) = logic {
+        // Remap the capture's state so the converted channels occupy consecutive bits
+        let mut data = Vec::with_capacity(sweep.samples.len());
+        let mut state = capture.initial_state;
+        let mut edges = capture.edges.iter().peekable();
+        for index in 0..sweep.samples.len() as u32 {
+            while let Some(edge) = edges.next_if(|edge| edge.index <= index) {
+                state = edge.state;
+            }
+            let byte = logic_channels
+                .iter()
+                .enumerate()
+                .fold(0u8, |byte, (bit, &ch)| byte | ((state >> ch) & 1) << bit);
+            data.push(byte);
+        }
+        archive.add_file("logic-1-1", &data)?;
+    }
+
+    for (i, &ch) in analog_channels.iter().enumerate() {
+        let data: Vec<u8> = sweep
+            .samples
+            .iter()
+            .flat_map(|sample| (sample.data[ch].unwrap_or(f64::NAN) as f32).to_le_bytes())
+            .collect();
+        archive.add_file(&format!("analog-1-{}-1", logic_channels.len() + i + 1), &data)?;
+    }
+
+    archive.finish()
+}
+
+/// Minimal writer of zip archives with stored, uncompressed entries
+struct ZipWriter<W: Write> {
+    writer: W,
+    offset: u32,
+    central_directory: Vec<u8>,
+    entries: u16,
+}
+
+impl<W: Write> ZipWriter<W> {
+    fn new(writer: W) -> Self {
+        ZipWriter { writer, offset: 0, central_directory: Vec::new(), entries: 0 }
+    }
+
+    fn add_file(&mut self, name: &str, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        let crc = crc32(data);
+        let size = u32::try_from(data.len()).map_err(|_| "Session file is too large")?;
+
+        // Version needed, flags, method (stored), time, date, CRC and sizes, shared by both headers
+        let mut common = Vec::new();
+        common.extend_from_slice(&20u16.to_le_bytes());
+        common.extend_from_slice(&0u16.to_le_bytes());
+        common.extend_from_slice(&0u16.to_le_bytes());
+        common.extend_from_slice(&0u16.to_le_bytes());
+        common.extend_from_slice(&0x21u16.to_le_bytes());
+        common.extend_from_slice(&crc.to_le_bytes());
+        common.extend_from_slice(&size.to_le_bytes());
+        common.extend_from_slice(&size.to_le_bytes());
+        common.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        common.extend_from_slice(&0u16.to_le_bytes());
+
+        let mut local = Vec::new();
+        local.extend_from_slice(&0x0403_4b50u32.to_le_bytes());
+        local.extend_from_slice(&common);
+        local.extend_from_slice(name.as_bytes());
+        self.writer.write_all(&local)?;
+        self.writer.write_all(data)?;
+
+        let central = &mut self.central_directory;
+        central.extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+        central.extend_from_slice(&20u16.to_le_bytes());
+        central.extend_from_slice(&common);
+        // Comment length, disk number, internal and external attributes
+        central.extend_from_slice(&[0u8; 10]);
+        central.extend_from_slice(&self.offset.to_le_bytes());
+        central.extend_from_slice(name.as_bytes());
+
+        self.offset = self
+            .offset
+            .checked_add(local.len() as u32 + size)
+            .ok_or("Session file is too large")?;
+        self.entries += 1;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<(), Box<dyn Error>> {
+        self.writer.write_all(&self.central_directory)?;
+
+        let mut end = Vec::new();
+        end.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+        end.extend_from_slice(&[0u8; 4]);
+        end.extend_from_slice(&self.entries.to_le_bytes());
+        end.extend_from_slice(&self.entries.to_le_bytes());
+        end.extend_from_slice(&(self.central_directory.len() as u32).to_le_bytes());
+        end.extend_from_slice(&self.offset.to_le_bytes());
+        end.extend_from_slice(&0u16.to_le_bytes());
+        self.writer.write_all(&end)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// CRC-32 as used by zip, with the reflected 0xEDB88320 polynomial
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u32, |crc, _| {
+            if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+
+    use crate::export::test_sweep;
+    use crate::logic::LogicEdge;
+
+    use super::*;
+
+    fn u16_at(bytes: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes(bytes[at..at + 2].try_into().unwrap())
+    }
+
+    fn u32_at(bytes: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+    }
+
+    /// Reads the entries of an archive through its central directory, checking both headers
+    fn read_archive(archive: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let end = archive.len() - 22;
+        assert_eq!(u32_at(archive, end), 0x0605_4b50);
+        let entries = u16_at(archive, end + 10) as usize;
+        assert_eq!(u16_at(archive, end + 8) as usize, entries);
+        let directory_size = u32_at(archive, end + 12) as usize;
+        let mut central = u32_at(archive, end + 16) as usize;
+        assert_eq!(central + directory_size, end);
+
+        let mut files = Vec::new();
+        for _ in 0..entries {
+            assert_eq!(u32_at(archive, central), 0x0201_4b50);
+            let (crc, size) = (u32_at(archive, central + 16), u32_at(archive, central + 20) as usize);
+            assert_eq!(u32_at(archive, central + 24) as usize, size);
+            let name_length = u16_at(archive, central + 28) as usize;
+            let local = u32_at(archive, central + 42) as usize;
+            let name = &archive[central + 46..central + 46 + name_length];
+
+            // The local header repeats the fields of the central one after its signature
+            assert_eq!(u32_at(archive, local), 0x0403_4b50);
+            assert_eq!(archive[local + 4..local + 30], archive[central + 6..central + 32]);
+            assert_eq!(&archive[local + 30..local + 30 + name_length], name);
+            let data = &archive[local + 30 + name_length..local + 30 + name_length + size];
+            assert_eq!(crc32(data), crc);
+
+            files.push((String::from_utf8(name.to_vec()).unwrap(), data.to_vec()));
+            central += 46 + name_length;
+        }
+        files
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn writes_a_readable_session() {
+        let sweep = test_sweep(1000.0, &[&[1.0, -1.0, 0.5], &[0.0, 2.0, 0.0]]);
+        let logic = LogicCapture {
+            channels: 0b0010,
+            initial_state: 0b0000,
+            edges: vec![LogicEdge { time: 0.002, index: 2, state: 0b0010, changed: 0b0010 }],
+            number_of_samples: 3,
+            duration: 0.002,
+        };
+
+        let mut archive = Vec::new();
+        write_session(&sweep, Some(&logic), &mut archive).unwrap();
+        let files = read_archive(&archive);
+
+        let names: Vec<&str> = files.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["version", "metadata", "logic-1-1", "analog-1-2-1", "analog-1-3-1"]);
+        assert_eq!(files[0].1, b"2");
+
+        let metadata = String::from_utf8(files[1].1.clone()).unwrap();
+        let expected = [
+            "samplerate=1000 Hz",
+            "total probes=1",
+            "total analog=2",
+            "capturefile=logic-1",
+            "unitsize=1",
+            "probe1=D2",
+            "analog2=CH1",
+            "analog3=CH2",
+        ];
+        for line in expected.iter() {
+            assert!(metadata.lines().any(|l| l == *line), "{} missing from\n{}", line, metadata);
+        }
+
+        assert_eq!(files[2].1, [0, 0, 1]);
+        let ch1: Vec<f32> = files[3].1.chunks(4).map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap())).collect();
+        assert_eq!(ch1, [1.0, -1.0, 0.5]);
+    }
+}
@@ -0,0 +1,450 @@
+/***************************************************************************************************
+ *
+ *  nLabs, LLC
+ *  https://getnlab.com
+ *  Copyright(c) 2020. All Rights Reserved
+ *
+ *  This file is part of the nLab API
+ *
+ **************************************************************************************************/
+
+//! WAV audio files, with one audio channel per scope channel that was on
+//!
+//! Readings are divided by a full scale value so that `±full_scale` maps to the `±1.0` range of
+//! the audio samples. 16-bit files clip anything beyond full scale, while 32-bit float files keep
+//! it. WAV files store the sample rate as a whole number of Hz, so the effective sample rate of
+//! the sweep is rounded.
+
+use std::error::Error;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::time::SystemTime;
+
+use crate::{AnalogInput, ChannelData, Sample, Sweep, SweepHandle, SweepMetadata, Trigger};
+
+const FORMAT_PCM: u16 = 1;
+const FORMAT_FLOAT: u16 = 3;
+const FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+/// Largest format chunk accepted, well above the 40 bytes of the extensible format
+const MAX_FORMAT_CHUNK_SIZE: u32 = 256;
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum WavSampleFormat {
+    Pcm16,
+    Float32,
+}
+
+impl WavSampleFormat {
+    fn bytes_per_sample(&self) -> u16 {
+        match self {
+            WavSampleFormat::Pcm16 => 2,
+            WavSampleFormat::Float32 => 4,
+        }
+    }
+}
+
+/// Writes samples to a WAV file as they arrive
+///
+/// The sizes in the header are filled in by [`finish`](WavWriter::finish), which needs to seek
+/// back to the start of the file. Float files have the fact chunk with the number of frames that
+/// the format requires for data that is not PCM.
+#[derive(Debug)]
+pub struct WavWriter<W: Write + Seek> {
+    writer: W,
+    format: WavSampleFormat,
+    full_scale: f64,
+    channels: Vec<usize>,
+    start: u64,
+    /// Length of the header, up to the start of the samples
+    header_bytes: u32,
+    data_bytes: u32,
+    frames: u32,
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    /// Creates a writer with one audio channel for each channel that is on in the sweep
+    pub fn new(mut writer: W, metadata: &SweepMetadata, format: WavSampleFormat, full_scale: f64) -> Result<Self, Box<dyn Error>> {
+        if !(full_scale.is_finite() && full_scale > 0.0) {
+            return Err("Full scale must be positive and finite".into());
+        }
+        let channels: Vec<usize> = (0..4).filter(|&ch| metadata.channels[ch].is_on).collect();
+        if channels.is_empty() {
+            return Err("No scope channels are on".into());
+        }
+
+        // Rates that round to 0 Hz or overflow the header cannot be stored
+        if !(0.5..u32::MAX as f64).contains(&metadata.sample_rate_hz) {
+            return Err(format!("Sample rate of {} Hz cannot be stored in a WAV file", metadata.sample_rate_hz).into());
+        }
+        let sample_rate = metadata.sample_rate_hz.round() as u32;
+        let bytes_per_sample = format.bytes_per_sample();
+        let block_align = bytes_per_sample * channels.len() as u16;
+        let byte_rate = sample_rate.checked_mul(block_align as u32).ok_or("Sample rate is too high for a WAV file")?;
+        let start = writer.stream_position()?;
+
+        writer.write_all(b"RIFF")?;
+        writer.write_all(&0u32.to_le_bytes())?;
+        writer.write_all(b"WAVE")?;
+        writer.write_all(b"fmt ")?;
+        // Formats other than PCM end the format chunk with the size of an extension, here empty
+        let (format_tag, format_bytes) = match format {
+            WavSampleFormat::Pcm16 => (FORMAT_PCM, 16u32),
+            WavSampleFormat::Float32 => (FORMAT_FLOAT, 18u32),
+        };
+        writer.write_all(&format_bytes.to_le_bytes())?;
+        writer.write_all(&format_tag.to_le_bytes())?;
+        writer.write_all(&(channels.len() as u16).to_le_bytes())?;
+        writer.write_all(&sample_rate.to_le_bytes())?;
+        writer.write_all(&byte_rate.to_le_bytes())?;
+        writer.write_all(&block_align.to_le_bytes())?;
+        writer.write_all(&(bytes_per_sample * 8).to_le_bytes())?;
+        let mut header_bytes = 20 + format_bytes;
+        if format_tag != FORMAT_PCM {
+            writer.write_all(&0u16.to_le_bytes())?;
+            // The number of frames is filled in by `finish`
+            writer.write_all(b"fact")?;
+            writer.write_all(&4u32.to_le_bytes())?;
+            writer.write_all(&0u32.to_le_bytes())?;
+            header_bytes += 12;
+        }
+        writer.write_all(b"data")?;
+        writer.write_all(&0u32.to_le_bytes())?;
+        header_bytes += 8;
+
+        Ok(WavWriter { writer, format, full_scale, channels, start, header_bytes, data_bytes: 0, frames: 0 })
+    }
+
+    /// Writes one frame, with silence for readings missing from the sample
+    pub fn write_sample(&mut self, sample: &Sample) -> Result<(), Box<dyn Error>> {
+        for &ch in self.channels.iter() {
+            let value = sample.data[ch].unwrap_or(0.0) / self.full_scale;
+            match self.format {
+                WavSampleFormat::Pcm16 => {
+                    let code = (value.clamp(-1.0, 1.0) * i16::MAX as f64).round() as i16;
+                    self.writer.write_all(&code.to_le_bytes())?;
+                }
+                WavSampleFormat::Float32 => self.writer.write_all(&(value as f32).to_le_bytes())?,
+            }
+        }
+        let frame_bytes = self.format.bytes_per_sample() as u32 * self.channels.len() as u32;
+        self.data_bytes = self.data_bytes.checked_add(frame_bytes).ok_or("WAV file is too large")?;
+        self.frames += 1;
+        Ok(())
+    }
+
+    /// Fills in the header sizes and returns the underlying writer
+    pub fn finish(mut self) -> Result<W, Box<dyn Error>> {
+        // Chunks are padded to an even length
+        if self.data_bytes % 2 == 1 {
+            self.writer.write_all(&[0])?;
+        }
+        let riff_bytes = (self.header_bytes - 8)
+            .checked_add(self.data_bytes + self.data_bytes % 2)
+            .ok_or("WAV file is too large")?;
+        let end = self.writer.stream_position()?;
+        self.writer.seek(SeekFrom::Start(self.start + 4))?;
+        self.writer.write_all(&riff_bytes.to_le_bytes())?;
+        if self.format != WavSampleFormat::Pcm16 {
+            // The frame count of the fact chunk is just before the data chunk header
+            self.writer.seek(SeekFrom::Start(self.start + self.header_bytes as u64 - 12))?;
+            self.writer.write_all(&self.frames.to_le_bytes())?;
+        }
+        self.writer.seek(SeekFrom::Start(self.start + self.header_bytes as u64 - 4))?;
+        self.writer.write_all(&self.data_bytes.to_le_bytes())?;
+        self.writer.seek(SeekFrom::Start(end))?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Streams a sweep to a WAV file as samples arrive, blocking until the sweep finishes
+pub fn write_sweep_handle<W: Write + Seek>(
+    handle: SweepHandle,
+    writer: W,
+    format: WavSampleFormat,
+    full_scale: f64,
+) -> Result<u32, Box<dyn Error>> {
+    let mut wav = WavWriter::new(writer, handle.metadata(), format, full_scale)?;
+    let mut written = 0;
+    for sample in handle.receiver.iter() {
+        wav.write_sample(&sample)?;
+        written += 1;
+    }
+    wav.finish()?;
+    Ok(written)
+}
+
+/// Writes a finished sweep to a WAV file
+pub fn write_sweep<W: Write + Seek>(sweep: &Sweep, writer: W, format: WavSampleFormat, full_scale: f64) -> Result<(), Box<dyn Error>> {
+    let mut wav = WavWriter::new(writer, &sweep.metadata, format, full_scale)?;
+    for sample in sweep.samples.iter() {
+        wav.write_sample(sample)?;
+    }
+    wav.finish()?;
+    Ok(())
+}
+
+/// Audio read from a WAV file
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct WavData {
+    pub sample_rate_hz: f64,
+    /// Samples of each audio channel, normalized to `±1.0` full scale
+    pub channels: Vec<Vec<f64>>,
+}
+
+impl WavData {
+    /// Reads 8, 16, 24 or 32-bit integer, or 32 or 64-bit float audio
+    pub fn read<R: Read>(mut reader: R) -> Result<Self, Box<dyn Error>> {
+        let mut header = [0u8; 12];
+        reader.read_exact(&mut header)?;
+        if &header[0..4] != b"RIFF" || &header[8..12] != b"WAVE" {
+            return Err("Not a WAV file".into());
+        }
+
+        let mut format: Option<(u16, u16, u32, u16)> = None;
+        loop {
+            let mut chunk_header = [0u8; 8];
+            reader.read_exact(&mut chunk_header)?;
+            let size = u32::from_le_bytes([chunk_header[4], chunk_header[5], chunk_header[6], chunk_header[7]]);
+
+            match &chunk_header[0..4] {
+                b"fmt " => {
+                    if !(16..=MAX_FORMAT_CHUNK_SIZE).contains(&size) {
+                        return Err("Invalid WAV format chunk".into());
+                    }
+                    let chunk = read_chunk(&mut reader, size)?;
+                    if chunk.len() < 16 {
+                        return Err("Invalid WAV format chunk".into());
+                    }
+                    let u16_at = |i: usize| u16::from_le_bytes([chunk[i], chunk[i + 1]]);
+                    let mut tag = u16_at(0);
+                    if tag == FORMAT_EXTENSIBLE && chunk.len() >= 26 {
+                        // The format is the first two bytes of the sub-format GUID
+                        tag = u16_at(24);
+                    }
+                    let rate = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]);
+                    format = Some((tag, u16_at(2), rate, u16_at(14)));
+                }
+                b"data" => {
+                    let (tag, channels, rate, bits) = format.ok_or("WAV data before its format")?;
+                    let chunk = read_chunk(&mut reader, size)?;
+                    return Ok(WavData {
+                        sample_rate_hz: rate as f64,
+                        channels: decode_samples(&chunk, tag, channels as usize, bits)?,
+                    });
+                }
+                _ => {
+                    io::copy(&mut (&mut reader).take(size as u64), &mut io::sink())?;
+                }
+            }
+            // Chunks are padded to an even length
+            if size % 2 == 1 {
+                reader.read_exact(&mut [0u8])?;
+            }
+        }
+    }
+
+    /// Returns an audio channel, scaled so that `±1.0` becomes `±full_scale`, as channel data
+    pub fn channel(&self, channel: usize, full_scale: f64) -> Option<ChannelData> {
+        let values = self.channels.get(channel)?.iter().map(|v| v * full_scale).collect();
+        Some(ChannelData::new(values, self.sample_rate_hz))
+    }
+
+    /// Returns the first four audio channels as a sweep on scope channels 1 through 4
+    ///
+    /// Readings are scaled by `full_scale` and reported in volts, so recordings can be used as
+    /// test data for code that analyzes sweeps. Returns an error if those channels do not all
+    /// have the same number of samples.
+    pub fn to_sweep(&self, full_scale: f64) -> Result<Sweep, Box<dyn Error>> {
+        let number_of_samples = self.channels.first().map_or(0, |values| values.len());
+        if self.channels.iter().take(4).any(|values| values.len() != number_of_samples) {
+            return Err("Audio channels have different numbers of samples".into());
+        }
+        let mut channels = [
+            AnalogInput::create(false).config(),
+            AnalogInput::create(false).config(),
+            AnalogInput::create(false).config(),
+            AnalogInput::create(false).config(),
+        ];
+        for (ch, config) in channels.iter_mut().enumerate() {
+            config.is_on = ch < self.channels.len();
+        }
+
+        let samples = (0..number_of_samples)
+            .map(|i| {
+                let mut sample = Sample { time_since_start: i as f64 / self.sample_rate_hz, ..Default::default() };
+                for (ch, values) in self.channels.iter().take(4).enumerate() {
+                    sample.data[ch] = Some(values[i] * full_scale);
+                    sample.units[ch] = Some(channels[ch].scaling.unit.clone());
+                }
+                sample
+            })
+            .collect();
+
+        Ok(Sweep {
+            metadata: SweepMetadata {
+                sample_rate_hz: self.sample_rate_hz,
+                requested_sample_rate_hz: self.sample_rate_hz,
+                number_of_samples: number_of_samples as u32,
+                channels,
+                trigger: Trigger::default(),
+                firmware_version: None,
+                start_time: SystemTime::now(),
+                stimulus: None,
+            },
+            samples,
+            clipped_samples: [0; 4],
+        })
+    }
+}
+
+/// Reads a chunk whose size comes from the file, without trusting the size for the allocation
+fn read_chunk<R: Read>(reader: &mut R, size: u32) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut chunk = Vec::new();
+    reader.take(size as u64).read_to_end(&mut chunk)?;
+    if chunk.len() != size as usize {
+        return Err("Truncated WAV file".into());
+    }
+    Ok(chunk)
+}
+
+/// Splits interleaved audio frames into samples per channel, with the largest positive integer
+/// code at 1.0
+fn decode_samples(data: &[u8], format: u16, channels: usize, bits: u16) -> Result<Vec<Vec<f64>>, Box<dyn Error>> {
+    let bytes = (bits / 8) as usize;
+    if channels == 0 || bytes == 0 {
+        return Err("Invalid WAV format".into());
+    }
+    let decode: fn(&[u8]) -> f64 = match (format, bits) {
+        (FORMAT_PCM, 8) => |b| (b[0] as f64 - 128.0) / 127.0,
+        (FORMAT_PCM, 16) => |b| i16::from_le_bytes([b[0], b[1]]) as f64 / i16::MAX as f64,
+        (FORMAT_PCM, 24) => |b| (i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8) as f64 / 8_388_607.0,
+        (FORMAT_PCM, 32) => |b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64 / i32::MAX as f64,
+        (FORMAT_FLOAT, 32) => |b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+        (FORMAT_FLOAT, 64) => |b| f64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]),
+        _ => return Err(format!("Unsupported WAV format {} with {} bits per sample", format, bits).into()),
+    };
+
+    let mut result = vec![Vec::with_capacity(data.len() / bytes / channels); channels];
+    for frame in data.chunks_exact(bytes * channels) {
+        for (ch, sample) in frame.chunks_exact(bytes).enumerate() {
+            result[ch].push(decode(sample));
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::export::test_sweep;
+
+    use super::*;
+
+    #[test]
+    fn round_trips_both_formats() {
+        let values: Vec<f64> = (0..100).map(|i| (i as f64 / 10.0).sin() * 4.0).collect();
+        let sweep = test_sweep(8000.0, &[&values]);
+
+        for &format in [WavSampleFormat::Pcm16, WavSampleFormat::Float32].iter() {
+            let mut file = Cursor::new(Vec::new());
+            write_sweep(&sweep, &mut file, format, 5.0).unwrap();
+            file.set_position(0);
+
+            let wav = WavData::read(file).unwrap();
+            assert_eq!(wav.sample_rate_hz, 8000.0);
+            let read = wav.channel(0, 5.0).unwrap().values;
+            assert_eq!(read.len(), values.len());
+            assert!(read.iter().zip(values.iter()).all(|(a, b)| (a - b).abs() < 5.0 / 32767.0));
+        }
+    }
+
+    #[test]
+    fn skips_unknown_chunks_without_trusting_their_size() {
+        let sweep = test_sweep(8000.0, &[&[0.5, -0.5]]);
+        let mut file = Cursor::new(Vec::new());
+        write_sweep(&sweep, &mut file, WavSampleFormat::Float32, 1.0).unwrap();
+        let written = file.into_inner();
+
+        // An odd sized chunk, with its padding byte, ahead of the format
+        let mut with_list = written[..12].to_vec();
+        with_list.extend_from_slice(b"LIST\x03\0\0\0abc\0");
+        with_list.extend_from_slice(&written[12..]);
+        let wav = WavData::read(with_list.as_slice()).unwrap();
+        assert_eq!(wav.channels, vec![vec![0.5, -0.5]]);
+
+        // Chunks claiming more data than the file holds
+        let mut huge = written[..12].to_vec();
+        huge.extend_from_slice(b"LIST\xF0\xFF\xFF\xFF");
+        assert!(WavData::read(huge.as_slice()).is_err());
+        let mut huge_format = written[..12].to_vec();
+        huge_format.extend_from_slice(b"fmt \xF0\xFF\xFF\xFF");
+        assert!(WavData::read(huge_format.as_slice()).is_err());
+        let data = written.windows(4).position(|id| id == b"data").unwrap();
+        let mut huge_data = written[..data].to_vec();
+        huge_data.extend_from_slice(b"data\xF0\xFF\xFF\xFF");
+        assert!(WavData::read(huge_data.as_slice()).is_err());
+    }
+
+    #[test]
+    fn writes_a_fact_chunk_for_float_samples() {
+        let sweep = test_sweep(8000.0, &[&[0.5, -0.5, 0.25], &[0.0, 1.0, 0.0]]);
+        let u32_at = |bytes: &[u8], at: usize| u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]]);
+
+        let mut file = Cursor::new(Vec::new());
+        write_sweep(&sweep, &mut file, WavSampleFormat::Float32, 1.0).unwrap();
+        let float = file.into_inner();
+        assert_eq!(u32_at(&float, 4) as usize, float.len() - 8);
+        assert_eq!(u32_at(&float, 16), 18);
+        assert_eq!(&float[38..42], b"fact");
+        assert_eq!((u32_at(&float, 42), u32_at(&float, 46)), (4, 3));
+        assert_eq!(&float[50..54], b"data");
+        assert_eq!(u32_at(&float, 54), 3 * 2 * 4);
+        assert_eq!(WavData::read(float.as_slice()).unwrap().channels[0], [0.5, -0.5, 0.25]);
+
+        // PCM files keep the plain 44 byte header
+        let mut file = Cursor::new(Vec::new());
+        write_sweep(&sweep, &mut file, WavSampleFormat::Pcm16, 1.0).unwrap();
+        let pcm = file.into_inner();
+        assert_eq!(pcm.len(), 44 + 3 * 2 * 2);
+        assert_eq!(u32_at(&pcm, 4) as usize, pcm.len() - 8);
+        assert_eq!(&pcm[36..40], b"data");
+    }
+
+    #[test]
+    fn rejects_full_scales_that_are_not_positive_and_finite() {
+        let sweep = test_sweep(8000.0, &[&[0.5, -0.5]]);
+        for &full_scale in [0.0, -1.0, f64::NAN, f64::INFINITY].iter() {
+            assert!(write_sweep(&sweep, Cursor::new(Vec::new()), WavSampleFormat::Float32, full_scale).is_err());
+        }
+    }
+
+    #[test]
+    fn rejects_rates_the_header_cannot_store() {
+        for &rate in [0.4, 0.0, f64::NAN, 1e10].iter() {
+            let sweep = test_sweep(rate, &[&[0.5, -0.5]]);
+            assert!(write_sweep(&sweep, Cursor::new(Vec::new()), WavSampleFormat::Pcm16, 1.0).is_err(), "{}", rate);
+        }
+        let sweep = test_sweep(0.5, &[&[0.5, -0.5]]);
+        let mut file = Cursor::new(Vec::new());
+        write_sweep(&sweep, &mut file, WavSampleFormat::Pcm16, 1.0).unwrap();
+        file.set_position(0);
+        assert_eq!(WavData::read(file).unwrap().sample_rate_hz, 1.0);
+    }
+
+    #[test]
+    fn converts_to_a_sweep() {
+        let wav = WavData { sample_rate_hz: 1000.0, channels: vec![vec![0.5, -0.25], vec![0.0, 1.0]] };
+        let sweep = wav.to_sweep(2.0).unwrap();
+        assert_eq!(sweep.metadata.number_of_samples, 2);
+        assert_eq!(sweep.channel(1).unwrap().values, [1.0, -0.5]);
+        assert_eq!(sweep.channel(2).unwrap().values, [0.0, 2.0]);
+        assert!(sweep.channel(3).is_none());
+        assert!(sweep.channel(0).is_none());
+
+        let uneven = WavData { sample_rate_hz: 1000.0, channels: vec![vec![0.5, -0.25], vec![0.0]] };
+        assert!(uneven.to_sweep(2.0).is_err());
+    }
+}
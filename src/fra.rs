@@ -0,0 +1,216 @@
+/***************************************************************************************************
+ *
+ *  nLabs, LLC
+ *  https://getnlab.com
+ *  Copyright(c) 2020. All Rights Reserved
+ *
+ *  This file is part of the nLab API
+ *
+ **************************************************************************************************/
+
+//! Frequency response analysis (Bode plots) using an analog output and two scope channels
+//!
+//! A sine from `a1` or `a2` drives the network under test. One scope channel measures the
+//! stimulus at the input of the network (the reference) and another measures its output (the
+//! response). At each frequency the gain and phase of the response relative to the reference
+//! are computed at exactly the stimulus frequency.
+
+use std::error::Error;
+use std::thread;
+use std::time::Duration;
+
+use crate::measure::{tone, wrap_degrees};
+use crate::scope::data_requests::{buffer_samples, effective_sample_rate, sample_clock};
+use crate::{AnalogOutputState, AnalogSignalPolarity, AnalogWaveType, Nlab};
+
+/// Settings for a frequency response sweep
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FraSettings {
+    /// Analog output driving the network, 1 for `a1` or 2 for `a2`
+    pub output: usize,
+    /// Scope channel measuring the input of the network, 1 through 4
+    pub reference_channel: usize,
+    /// Scope channel measuring the output of the network, 1 through 4
+    pub response_channel: usize,
+    /// Frequencies to measure, in Hz
+    pub frequencies: Vec<f64>,
+    pub amplitude: f64,
+    pub polarity: AnalogSignalPolarity,
+    /// Time to wait after changing frequency, before capturing
+    pub settle_time: Duration,
+    /// Minimum number of stimulus periods to capture at each frequency
+    pub cycles: u32,
+}
+
+impl Default for FraSettings {
+    fn default() -> Self {
+        FraSettings {
+            output: 1,
+            reference_channel: 1,
+            response_channel: 2,
+            frequencies: log_frequencies(10.0, 10_000.0, 31),
+            amplitude: 1.0,
+            polarity: AnalogSignalPolarity::Bipolar,
+            settle_time: Duration::from_millis(50),
+            cycles: 4,
+        }
+    }
+}
+
+/// Gain and phase of the network at a single frequency
+#[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FraPoint {
+    pub frequency: f64,
+    /// Ratio of response to reference amplitude in dB
+    pub gain_db: f64,
+    /// Phase of the response relative to the reference in degrees, negative when it lags
+    pub phase_degrees: f64,
+    /// Peak amplitude of the stimulus component on the reference channel
+    pub reference_amplitude: f64,
+    /// Peak amplitude of the stimulus component on the response channel
+    pub response_amplitude: f64,
+    /// True if either channel saturated, making this point unreliable
+    pub clipped: bool,
+}
+
+/// Target number of samples in each stimulus period
+const SAMPLES_PER_PERIOD: f64 = 50.0;
+/// Periods of the stimulus to wait, in addition to the settle time, after a frequency change
+const SETTLE_PERIODS: f64 = 3.0;
+
+/// Sample rate and length of the capture at a single frequency
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct SamplingPlan {
+    /// Rate passed to [`Nlab::request`]
+    requested_rate: f64,
+    number_of_samples: u32,
+}
+
+/// Picks a sample rate with enough samples per period that fits the minimum number of cycles
+///
+/// Each capture is kept within the nLab's buffer so that any sample rate can be recorded, and the
+/// rate is capped at the sample clock.
+fn sampling_plan(frequency: f64, cycles: u32, is_legacy: bool) -> Result<SamplingPlan, Box<dyn Error>> {
+    let max_samples = buffer_samples(2, is_legacy) as f64;
+    let cycles = cycles.max(1) as f64;
+
+    let requested_rate = (frequency * SAMPLES_PER_PERIOD)
+        .min(max_samples * frequency / cycles)
+        .min(sample_clock(2, is_legacy));
+    let sample_rate = effective_sample_rate(requested_rate, 2, is_legacy);
+    let samples_per_period = sample_rate / frequency;
+    if samples_per_period < 4.0 {
+        return Err(format!("Cannot sample fast enough to measure {} Hz", frequency).into());
+    }
+    let periods = (max_samples / samples_per_period).floor().min(cycles);
+    Ok(SamplingPlan {
+        requested_rate,
+        number_of_samples: (periods * samples_per_period).round() as u32,
+    })
+}
+
+/// Returns `points` frequencies spaced logarithmically from `start_hz` to `stop_hz` inclusive
+pub fn log_frequencies(start_hz: f64, stop_hz: f64, points: usize) -> Vec<f64> {
+    match points {
+        0 => Vec::new(),
+        1 => vec![start_hz],
+        _ => {
+            let ratio = (stop_hz / start_hz).ln() / (points - 1) as f64;
+            (0..points).map(|i| start_hz * (ratio * i as f64).exp()).collect()
+        }
+    }
+}
+
+/// Steps the output across the frequencies in `settings` and measures the response at each
+///
+/// Only the reference and response channels are turned on during the analysis; the previous
+/// channel states are restored and the output is turned off when it finishes.
+pub fn analyze(nlab: &mut Nlab, settings: &FraSettings) -> Result<Vec<FraPoint>, Box<dyn Error>> {
+    let channels = [settings.reference_channel, settings.response_channel];
+    if channels.iter().any(|&ch| nlab.channel(ch).is_none()) || channels[0] == channels[1] {
+        return Err("Reference and response must be two different channels between 1 and 4".into());
+    }
+    if nlab.analog_output(settings.output).is_none() {
+        return Err(format!("Invalid analog output: {}", settings.output).into());
+    }
+
+    let result = nlab.with_channels(&channels, |nlab| sweep_frequencies(nlab, settings));
+    let turned_off = nlab.analog_output(settings.output).unwrap().turn_off();
+    result.and_then(|value| turned_off.map(|()| value))
+}
+
+fn sweep_frequencies(nlab: &Nlab, settings: &FraSettings) -> Result<Vec<FraPoint>, Box<dyn Error>> {
+    let output = nlab.analog_output(settings.output).unwrap();
+    output.set_state(AnalogOutputState {
+        is_on: true,
+        amplitude: settings.amplitude,
+        wave_type: AnalogWaveType::Sine,
+        polarity: settings.polarity,
+        modulation: None,
+        ..output.state()
+    })?;
+
+    let mut points = Vec::with_capacity(settings.frequencies.len());
+    for &requested_frequency in settings.frequencies.iter() {
+        output.set_frequency(requested_frequency)?;
+        let frequency = output.frequency();
+        let plan = sampling_plan(frequency, settings.cycles, nlab.is_legacy())?;
+
+        thread::sleep(settings.settle_time + Duration::from_secs_f64(SETTLE_PERIODS / frequency));
+
+        let number_of_samples = plan.number_of_samples;
+        let sweep = nlab.request(plan.requested_rate, number_of_samples, None).into_sweep();
+        let reference = sweep
+            .channel(settings.reference_channel)
+            .ok_or("No data received on the reference channel")?;
+        let response = sweep
+            .channel(settings.response_channel)
+            .ok_or("No data received on the response channel")?;
+        if reference.values.len() < number_of_samples as usize || response.values.len() < number_of_samples as usize {
+            return Err(format!("Incomplete capture at {} Hz", frequency).into());
+        }
+
+        let rate = sweep.metadata.sample_rate_hz;
+        let (reference_amplitude, reference_phase) = tone(&reference.values, rate, frequency);
+        let (response_amplitude, response_phase) = tone(&response.values, rate, frequency);
+
+        points.push(FraPoint {
+            frequency,
+            gain_db: 20.0 * (response_amplitude / reference_amplitude).log10(),
+            phase_degrees: wrap_degrees((response_phase - reference_phase).to_degrees()),
+            reference_amplitude,
+            response_amplitude,
+            clipped: reference.clipped_samples > 0 || response.clipped_samples > 0,
+        });
+    }
+    Ok(points)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plans_captures_across_the_band() {
+        // Low frequencies are sampled at the target rate for the minimum number of cycles
+        let plan = sampling_plan(0.5, 4, false).unwrap();
+        assert_eq!(plan.requested_rate, 25.0);
+        assert_eq!(plan.number_of_samples, 200);
+
+        // At the highest frequency the sample rate is capped by the nLab's sample clock
+        let plan = sampling_plan(100_000.0, 4, false).unwrap();
+        assert_eq!(plan.requested_rate, 2_000_000.0);
+        assert_eq!(plan.number_of_samples, 80);
+        assert!(sampling_plan(600_000.0, 4, false).is_err());
+        assert!(sampling_plan(600_000.0, 4, true).is_err());
+
+        // Many cycles lower the sample rate to stay within the buffer
+        for &is_legacy in [true, false].iter() {
+            let plan = sampling_plan(1000.0, 200, is_legacy).unwrap();
+            assert!(plan.number_of_samples <= buffer_samples(2, is_legacy));
+            assert!(effective_sample_rate(plan.requested_rate, 2, is_legacy) / 1000.0 >= 4.0);
+        }
+    }
+}
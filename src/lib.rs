@@ -15,6 +15,9 @@
 //! This crate is [on crates.io](https://crates.io/crates/nlabapi) and can be
 //! used by adding `nlab` to the dependencies in your project's `Cargo.toml`.
 //!
+//! Enable the `serde` feature to derive `Serialize` and `Deserialize` for the configuration,
+//! output state and sweep data types, and to save and load setup [`Profile`]s as TOML files.
+//!
 //!
 //! # Example
 //!
@@ -22,7 +25,7 @@
 //! extern crate nlabapi;
 //! use nlabapi::LabBench;
 //!
-//! fn main() {
+//! fn main() -> Result<(), Box<dyn std::error::Error>> {
 //!     // Create a LabBench
 //!     let bench = LabBench::new().expect("Cannot create LabBench");
 //!
@@ -33,7 +36,7 @@
 //!     let nlab = bench.open_first_available(true).expect("Cannot open nLab");
 //!
 //!     // Turn on analog output channel A1
-//!     nlab.a1.turn_on();
+//!     nlab.a1.turn_on()?;
 //!
 //!     // Trigger an auto-triggered sweep of 20 samples at 4.0 Hz sample rate
 //!     let sweep_handle = nlab.request(4.0, 20, None);
@@ -45,8 +48,9 @@
 //!     }
 //!
 //!     // Turn off the analog output channel A1
-//!     nlab.a1.turn_off();
+//!     nlab.a1.turn_off()?;
 //!
+//!     Ok(())
 //! }
 //! ```
 
@@ -56,6 +60,16 @@ mod scope;
 mod version;
 mod firmware;
 mod python;
+pub mod measure;
+pub mod spectrum;
+pub mod fra;
+pub mod curve_tracer;
+pub mod step_response;
+pub mod logic;
+pub mod decode;
+pub mod export;
+pub mod capture;
+pub mod sequence;
 
 pub use lab_bench::LabBench;
 pub use lab_bench::NlabLink;
@@ -66,4 +80,6 @@ pub use scope::analog_output::*;
 pub use scope::analog_input::*;
 pub use scope::data_requests::*;
 pub use scope::trigger::*;
+pub use scope::profile::*;
+pub use scope::safe_state::*;
 pub use version::version;
@@ -0,0 +1,258 @@
+/***************************************************************************************************
+ *
+ *  nLabs, LLC
+ *  https://getnlab.com
+ *  Copyright(c) 2020. All Rights Reserved
+ *
+ *  This file is part of the nLab API
+ *
+ **************************************************************************************************/
+
+//! Logic analyzer mode, treating the scope channels as digital lines
+//!
+//! Each channel is compared against its own pair of thresholds, so noise smaller than the
+//! hysteresis does not produce spurious edges. The state of all four lines is packed into a
+//! bitmask with channel 1 in bit 0. Samples can be processed one at a time as they arrive from
+//! a [`SweepHandle`](crate::SweepHandle), or all at once from a finished [`Sweep`].
+
+use crate::{Sample, Sweep};
+
+/// Switching levels of a single digital line
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LogicThreshold {
+    /// A high line goes low when it falls below this level
+    pub low: f64,
+    /// A low line goes high when it rises above this level
+    pub high: f64,
+}
+
+impl LogicThreshold {
+    /// Thresholds centered on `level`, `hysteresis` apart
+    pub fn new(level: f64, hysteresis: f64) -> Self {
+        LogicThreshold {
+            low: level - hysteresis / 2.0,
+            high: level + hysteresis / 2.0,
+        }
+    }
+
+    /// Input levels of 5 V TTL logic
+    pub fn ttl() -> Self {
+        LogicThreshold { low: 0.8, high: 2.0 }
+    }
+
+    /// Input levels of 3.3 V CMOS logic, 30% and 70% of the supply
+    pub fn cmos_3v3() -> Self {
+        LogicThreshold { low: 0.99, high: 2.31 }
+    }
+
+    fn midpoint(&self) -> f64 {
+        (self.low + self.high) / 2.0
+    }
+}
+
+impl Default for LogicThreshold {
+    fn default() -> Self {
+        LogicThreshold::ttl()
+    }
+}
+
+/// Number of lines in a capture, one per analog input
+pub(crate) const LOGIC_CHANNELS: usize = 4;
+
+/// Bit of a line, 1 through 4, in the states of a capture, empty for lines that are not captured
+pub(crate) fn channel_mask(channel: usize) -> u8 {
+    if (1..=LOGIC_CHANNELS).contains(&channel) { 1 << (channel - 1) } else { 0 }
+}
+
+/// A change on one or more digital lines
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LogicEdge {
+    /// Time since the start of the sweep in seconds
+    pub time: f64,
+    /// Index of the first sample with the new state
+    pub index: u32,
+    /// State of all lines after the edge, channel 1 in bit 0
+    pub state: u8,
+    /// Lines that changed at this edge
+    pub changed: u8,
+}
+
+/// Digital capture of a sweep as an initial state and a list of edges
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LogicCapture {
+    /// Lines that were converted, channel 1 in bit 0
+    pub channels: u8,
+    /// State of all lines at the first sample
+    pub initial_state: u8,
+    pub edges: Vec<LogicEdge>,
+    /// Number of samples processed
+    pub number_of_samples: u32,
+    /// Time of the last sample processed
+    pub duration: f64,
+}
+
+impl LogicCapture {
+    /// State of all lines at a sample index
+    pub fn state_at(&self, index: u32) -> u8 {
+        match self.edges.iter().rposition(|edge| edge.index <= index) {
+            Some(i) => self.edges[i].state,
+            None => self.initial_state,
+        }
+    }
+
+    /// Level of a line, 1 through 4, at the first sample
+    ///
+    /// Lines that are not captured are always low.
+    pub fn initial_level(&self, channel: usize) -> bool {
+        self.initial_state & channel_mask(channel) != 0
+    }
+
+    /// Times and new levels of the edges on a single line, 1 through 4
+    ///
+    /// Lines that are not captured have no edges.
+    pub fn transitions(&self, channel: usize) -> impl Iterator<Item=(f64, bool)> + '_ {
+        let mask = channel_mask(channel);
+        self.edges
+            .iter()
+            .filter(move |edge| edge.changed & mask != 0)
+            .map(move |edge| (edge.time, edge.state & mask != 0))
+    }
+}
+
+/// Converts analog samples into digital edges
+#[derive(Debug, Clone)]
+pub struct LogicAnalyzer {
+    /// Threshold of channels 1 through 4, or None to ignore the channel
+    pub thresholds: [Option<LogicThreshold>; 4],
+    state: Option<u8>,
+    capture: LogicCapture,
+}
+
+impl LogicAnalyzer {
+    pub fn new(thresholds: [Option<LogicThreshold>; 4]) -> Self {
+        LogicAnalyzer {
+            thresholds,
+            state: None,
+            capture: LogicCapture::default(),
+        }
+    }
+
+    /// Analyzer with the same threshold on every channel
+    pub fn with_threshold(threshold: LogicThreshold) -> Self {
+        LogicAnalyzer::new([Some(threshold); 4])
+    }
+
+    /// Processes the next sample of a sweep, returning the edge if any line changed state
+    ///
+    /// Channels without a threshold or without a reading in the sample keep their state.
+    pub fn process(&mut self, sample: &Sample) -> Option<LogicEdge> {
+        let index = self.capture.number_of_samples;
+        self.capture.number_of_samples += 1;
+        self.capture.duration = sample.time_since_start;
+
+        let readings = self.thresholds.iter().zip(sample.data.iter()).enumerate();
+        let state = match self.state {
+            // The first sample decides each level from the midpoint of its thresholds
+            None => {
+                let mut state = 0;
+                for (ch, (threshold, value)) in readings {
+                    if let (Some(threshold), Some(value)) = (threshold, value) {
+                        self.capture.channels |= 1 << ch;
+                        if *value >= threshold.midpoint() {
+                            state |= 1 << ch;
+                        }
+                    }
+                }
+                self.state = Some(state);
+                self.capture.initial_state = state;
+                return None;
+            }
+            Some(previous) => {
+                let mut state = previous;
+                for (ch, (threshold, value)) in readings {
+                    if let (Some(threshold), Some(value)) = (threshold, value) {
+                        if *value > threshold.high {
+                            state |= 1 << ch;
+                        } else if *value < threshold.low {
+                            state &= !(1 << ch);
+                        }
+                    }
+                }
+                state
+            }
+        };
+
+        let previous = self.state.replace(state).unwrap();
+        if state == previous {
+            return None;
+        }
+        let edge = LogicEdge {
+            time: sample.time_since_start,
+            index,
+            state,
+            changed: state ^ previous,
+        };
+        self.capture.edges.push(edge);
+        Some(edge)
+    }
+
+    /// Returns the capture of all samples processed since the last reset
+    pub fn capture(&self) -> &LogicCapture {
+        &self.capture
+    }
+
+    /// Returns the capture and resets the analyzer for the next sweep
+    pub fn take_capture(&mut self) -> LogicCapture {
+        self.state = None;
+        std::mem::take(&mut self.capture)
+    }
+
+    pub fn reset(&mut self) {
+        self.take_capture();
+    }
+
+    /// Converts a finished sweep
+    pub fn analyze(&mut self, sweep: &Sweep) -> LogicCapture {
+        self.reset();
+        for sample in sweep.samples.iter() {
+            self.process(sample);
+        }
+        self.take_capture()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(index: u32, ch1: f64) -> Sample {
+        let mut sample = Sample { time_since_start: index as f64 * 1e-3, ..Default::default() };
+        sample.data[0] = Some(ch1);
+        sample
+    }
+
+    #[test]
+    fn hysteresis_rejects_noise() {
+        let mut analyzer = LogicAnalyzer::with_threshold(LogicThreshold::new(1.5, 0.5));
+        let values = [0.0, 1.6, 1.4, 1.8, 1.6, 1.3, 1.2, 0.1];
+        let edges: Vec<LogicEdge> = values
+            .iter()
+            .enumerate()
+            .filter_map(|(i, &v)| analyzer.process(&sample(i as u32, v)))
+            .collect();
+
+        assert_eq!(edges.len(), 2);
+        assert_eq!((edges[0].index, edges[0].state), (3, 1));
+        assert_eq!((edges[1].index, edges[1].state), (6, 0));
+
+        let capture = analyzer.take_capture();
+        assert_eq!(capture.channels, 0b0001);
+        assert_eq!(capture.state_at(4), 1);
+        assert_eq!(capture.transitions(1).count(), 2);
+        assert_eq!(capture.transitions(0).count(), 0);
+        assert!(!capture.initial_level(1));
+    }
+}
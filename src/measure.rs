@@ -0,0 +1,403 @@
+/***************************************************************************************************
+ *
+ *  nLabs, LLC
+ *  https://getnlab.com
+ *  Copyright(c) 2020. All Rights Reserved
+ *
+ *  This file is part of the nLab API
+ *
+ **************************************************************************************************/
+
+//! Standard scope measurements on sweep data
+//!
+//! Measurements are computed from a completed [`Sweep`] or from any block of [`ChannelData`].
+//! Each result carries a [`Validity`] so that automated scripts can tell a trustworthy number
+//! from one computed on a saturated or non-periodic signal.
+
+use std::f64::consts::PI;
+
+use crate::{ChannelData, Sweep};
+
+/// Fraction of the peak-to-peak amplitude used as hysteresis when detecting level crossings
+const CROSSING_HYSTERESIS: f64 = 0.1;
+
+/// How far a measurement can be trusted
+#[derive(Debug, PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Validity {
+    /// The signal supports the measurement
+    Valid,
+    /// The measurement was made from too few cycles or edges to be reliable
+    Uncertain,
+    /// The input saturated during the sweep, so amplitudes are likely wrong
+    Clipped,
+    /// The signal does not support the measurement, e.g. the frequency of a DC level
+    Invalid,
+}
+
+/// A single measured value, along with how far it can be trusted
+#[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Measurement {
+    pub value: f64,
+    pub validity: Validity,
+}
+
+impl Measurement {
+    fn new(value: f64, validity: Validity) -> Self {
+        if value.is_finite() {
+            Measurement { value, validity }
+        } else {
+            Measurement::invalid()
+        }
+    }
+
+    fn invalid() -> Self {
+        Measurement {
+            value: f64::NAN,
+            validity: Validity::Invalid,
+        }
+    }
+
+    /// Returns the measured value, or None if the measurement is invalid
+    pub fn value(&self) -> Option<f64> {
+        match self.validity {
+            Validity::Invalid => None,
+            _ => Some(self.value),
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.validity == Validity::Valid
+    }
+}
+
+/// The standard set of measurements on a single channel
+///
+/// Amplitudes are in the units of the channel, times are in seconds, and the duty cycle and
+/// overshoot are fractions (of the period and of the step height respectively).
+#[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct WaveformMeasurements {
+    pub min: Measurement,
+    pub max: Measurement,
+    pub peak_to_peak: Measurement,
+    pub mean: Measurement,
+    pub rms: Measurement,
+    pub ac_rms: Measurement,
+    pub frequency: Measurement,
+    pub period: Measurement,
+    pub duty_cycle: Measurement,
+    pub rise_time: Measurement,
+    pub fall_time: Measurement,
+    pub overshoot: Measurement,
+}
+
+/// Timing relationship between a signal and a reference at the reference's fundamental frequency
+#[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PhaseMeasurement {
+    /// Phase of the signal relative to the reference in degrees, negative when the signal lags
+    pub phase: Measurement,
+    /// Time in seconds by which the signal lags the reference
+    pub delay: Measurement,
+}
+
+/// A point where the signal crossed its mid level, as a fractional sample index
+#[derive(Debug, Copy, Clone)]
+struct Crossing {
+    index: f64,
+    rising: bool,
+}
+
+/// Measures one channel of a completed sweep, 1 through 4
+///
+/// Returns None if the channel was off during the sweep
+pub fn measure_channel(sweep: &Sweep, channel: usize) -> Option<WaveformMeasurements> {
+    sweep.channel(channel).map(|data| measure(&data))
+}
+
+/// Computes the standard set of measurements on a block of channel data
+pub fn measure(data: &ChannelData) -> WaveformMeasurements {
+    let values = &data.values;
+    let n = values.len();
+    let sample_period = 1.0 / data.sample_rate_hz;
+
+    let amplitude_validity = match (n, data.clipped_samples) {
+        (0, _) => Validity::Invalid,
+        (_, 0) => Validity::Valid,
+        _ => Validity::Clipped,
+    };
+    let amplitude = |value: f64| match amplitude_validity {
+        Validity::Invalid => Measurement::invalid(),
+        validity => Measurement::new(value, validity),
+    };
+
+    let min = values.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let mean = values.iter().sum::<f64>() / n as f64;
+    let rms = (values.iter().map(|v| v * v).sum::<f64>() / n as f64).sqrt();
+    let ac_rms = (values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / n as f64).sqrt();
+
+    let mut result = WaveformMeasurements {
+        min: amplitude(min),
+        max: amplitude(max),
+        peak_to_peak: amplitude(max - min),
+        mean: amplitude(mean),
+        rms: amplitude(rms),
+        ac_rms: amplitude(ac_rms),
+        frequency: Measurement::invalid(),
+        period: Measurement::invalid(),
+        duty_cycle: Measurement::invalid(),
+        rise_time: Measurement::invalid(),
+        fall_time: Measurement::invalid(),
+        overshoot: Measurement::invalid(),
+    };
+
+    if n < 2 || max <= min {
+        return result;
+    }
+
+    // Settled low and high levels, robust to overshoot and ringing
+    let mid = (max + min) / 2.0;
+    let base = median(values.iter().copied().filter(|&v| v < mid).collect());
+    let top = median(values.iter().copied().filter(|&v| v >= mid).collect());
+    let step = top - base;
+    if step > 0.0 {
+        result.overshoot = amplitude((max - top) / step);
+    }
+
+    let crossings = crossings(values, mid, CROSSING_HYSTERESIS * (max - min));
+    let rising: Vec<f64> = crossings.iter().filter(|c| c.rising).map(|c| c.index).collect();
+
+    // Frequency, period and duty cycle over the whole number of periods in the sweep
+    if rising.len() >= 2 {
+        let validity = if rising.len() >= 3 { Validity::Valid } else { Validity::Uncertain };
+        let (first, last) = (rising[0], rising[rising.len() - 1]);
+        let period = (last - first) / (rising.len() - 1) as f64 * sample_period;
+        result.period = Measurement::new(period, validity);
+        result.frequency = Measurement::new(1.0 / period, validity);
+
+        let high_samples: f64 = crossings
+            .windows(2)
+            .filter(|pair| pair[0].rising && !pair[1].rising && pair[0].index >= first && pair[1].index <= last)
+            .map(|pair| pair[1].index - pair[0].index)
+            .sum();
+        result.duty_cycle = Measurement::new(high_samples / (last - first), validity);
+    }
+
+    // Rise and fall times between the 10% and 90% levels, averaged over all complete edges
+    if step > 0.0 {
+        let low_level = base + 0.1 * step;
+        let high_level = base + 0.9 * step;
+        let edge_time = |rising: bool| {
+            let times: Vec<f64> = crossings
+                .iter()
+                .filter(|c| c.rising == rising)
+                .filter_map(|c| transition_samples(values, c.index, low_level, high_level, rising))
+                .collect();
+            let validity = if times.len() >= 2 { Validity::Valid } else { Validity::Uncertain };
+            match times.len() {
+                0 => Measurement::invalid(),
+                count => Measurement::new(times.iter().sum::<f64>() / count as f64 * sample_period, validity),
+            }
+        };
+        result.rise_time = edge_time(true);
+        result.fall_time = edge_time(false);
+    }
+
+    result
+}
+
+/// Measures the phase and delay of `signal` relative to `reference`
+///
+/// Both blocks must be sampled at the same rate and start at the same time, as is the case for
+/// two channels of the same sweep. The comparison is made at the fundamental frequency of the
+/// reference, over a whole number of its periods.
+pub fn phase(reference: &ChannelData, signal: &ChannelData) -> PhaseMeasurement {
+    let invalid = PhaseMeasurement {
+        phase: Measurement::invalid(),
+        delay: Measurement::invalid(),
+    };
+
+    let reference_measurements = measure(reference);
+    let frequency = match reference_measurements.frequency.value() {
+        Some(frequency) if reference.sample_rate_hz == signal.sample_rate_hz => frequency,
+        _ => return invalid,
+    };
+
+    let sample_rate_hz = reference.sample_rate_hz;
+    let samples_per_period = sample_rate_hz / frequency;
+    let n = reference.values.len().min(signal.values.len());
+    let periods = (n as f64 / samples_per_period).floor();
+    if periods < 1.0 {
+        return invalid;
+    }
+    let span = (periods * samples_per_period).round() as usize;
+
+    let (_, reference_phase) = tone(&reference.values[..span], sample_rate_hz, frequency);
+    let (_, signal_phase) = tone(&signal.values[..span], sample_rate_hz, frequency);
+    let phase_degrees = wrap_degrees((signal_phase - reference_phase).to_degrees());
+
+    let validity = match (reference_measurements.frequency.validity, reference.clipped_samples + signal.clipped_samples) {
+        (Validity::Valid, 0) => Validity::Valid,
+        (Validity::Valid, _) => Validity::Clipped,
+        (validity, _) => validity,
+    };
+    PhaseMeasurement {
+        phase: Measurement::new(phase_degrees, validity),
+        delay: Measurement::new(-phase_degrees / 360.0 / frequency, validity),
+    }
+}
+
+/// Amplitude and phase (in radians, relative to a cosine) of a single frequency component
+///
+/// For an accurate result the block should span a whole number of periods of `frequency`
+pub(crate) fn tone(values: &[f64], sample_rate_hz: f64, frequency: f64) -> (f64, f64) {
+    let omega = 2.0 * PI * frequency / sample_rate_hz;
+    let (re, im) = values
+        .iter()
+        .enumerate()
+        .fold((0.0, 0.0), |(re, im), (i, &v)| {
+            let angle = omega * i as f64;
+            (re + v * angle.cos(), im - v * angle.sin())
+        });
+    let n = values.len() as f64;
+    (2.0 * (re * re + im * im).sqrt() / n, im.atan2(re))
+}
+
+/// Least squares fit of `y = slope * x + intercept`, returning the slope and intercept
+///
+/// Returns `None` with fewer than two points or when all `x` are equal.
+pub(crate) fn regression(x: &[f64], y: &[f64]) -> Option<(f64, f64)> {
+    if x.len() < 2 {
+        return None;
+    }
+    let n = x.len() as f64;
+    let mean_x = x.iter().sum::<f64>() / n;
+    let mean_y = y.iter().sum::<f64>() / n;
+    let sxx: f64 = x.iter().map(|xi| (xi - mean_x).powi(2)).sum();
+    let sxy: f64 = x.iter().zip(y.iter()).map(|(xi, yi)| (xi - mean_x) * (yi - mean_y)).sum();
+    if sxx == 0.0 {
+        return None;
+    }
+    let slope = sxy / sxx;
+    Some((slope, mean_y - slope * mean_x))
+}
+
+/// Wraps an angle in degrees into the range (-180, 180]
+pub(crate) fn wrap_degrees(degrees: f64) -> f64 {
+    let wrapped = (degrees + 180.0).rem_euclid(360.0) - 180.0;
+    if wrapped == -180.0 { 180.0 } else { wrapped }
+}
+
+fn median(mut values: Vec<f64>) -> f64 {
+    if values.is_empty() {
+        return f64::NAN;
+    }
+    values.sort_by(|a, b| a.total_cmp(b));
+    values[values.len() / 2]
+}
+
+/// Finds the crossings of `level`, ignoring excursions smaller than `hysteresis`
+fn crossings(values: &[f64], level: f64, hysteresis: f64) -> Vec<Crossing> {
+    let mut crossings = Vec::new();
+    let mut is_high = values[0] >= level;
+    let mut last_on_side = 0usize;
+
+    for (i, &v) in values.iter().enumerate() {
+        if is_high {
+            if v >= level {
+                last_on_side = i;
+            } else if v < level - hysteresis / 2.0 {
+                crossings.push(Crossing { index: interpolate(values, last_on_side, level), rising: false });
+                is_high = false;
+                last_on_side = i;
+            }
+        } else if v < level {
+            last_on_side = i;
+        } else if v >= level + hysteresis / 2.0 {
+            crossings.push(Crossing { index: interpolate(values, last_on_side, level), rising: true });
+            is_high = true;
+            last_on_side = i;
+        }
+    }
+    crossings
+}
+
+/// Fractional index where the signal passes `level` between sample `i` and the sample after it
+fn interpolate(values: &[f64], i: usize, level: f64) -> f64 {
+    match values.get(i + 1) {
+        Some(&next) if next != values[i] => i as f64 + (level - values[i]) / (next - values[i]),
+        _ => i as f64,
+    }
+}
+
+/// Number of samples the edge through `center` takes to travel between the low and high levels
+fn transition_samples(values: &[f64], center: f64, low: f64, high: f64, rising: bool) -> Option<f64> {
+    let (start_level, end_level) = if rising { (low, high) } else { (high, low) };
+    let before_start = |v: f64| if rising { v < start_level } else { v > start_level };
+    let past_end = |v: f64| if rising { v > end_level } else { v < end_level };
+
+    let center = center.floor() as usize;
+    let start = (0..=center).rev().find(|&i| before_start(values[i]))?;
+    let end = (center + 1..values.len()).find(|&i| past_end(values[i]))?;
+
+    Some(interpolate(values, end - 1, end_level) - interpolate(values, start, start_level))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(frequency: f64, amplitude: f64, phase: f64, sample_rate_hz: f64, n: usize) -> ChannelData {
+        let values = (0..n)
+            .map(|i| amplitude * (2.0 * PI * frequency * i as f64 / sample_rate_hz + phase).sin())
+            .collect();
+        ChannelData::new(values, sample_rate_hz)
+    }
+
+    #[test]
+    fn measures_a_sine_wave() {
+        let data = sine(1000.0, 2.0, 0.0, 100_000.0, 10_000);
+        let m = measure(&data);
+
+        assert!((m.frequency.value - 1000.0).abs() < 0.5, "frequency: {:?}", m.frequency);
+        assert_eq!(m.frequency.validity, Validity::Valid);
+        assert!((m.peak_to_peak.value - 4.0).abs() < 1e-3);
+        assert!((m.rms.value - 2.0 / 2f64.sqrt()).abs() < 1e-3);
+        assert!(m.mean.value.abs() < 1e-3);
+        assert!((m.duty_cycle.value - 0.5).abs() < 1e-3);
+    }
+
+    #[test]
+    fn measures_a_pulse_train() {
+        let values = (0..4000).map(|i| if i % 100 < 25 { 3.3 } else { 0.0 }).collect();
+        let m = measure(&ChannelData::new(values, 10_000.0));
+
+        assert!((m.frequency.value - 100.0).abs() < 1e-6);
+        assert!((m.duty_cycle.value - 0.25).abs() < 1e-6);
+        assert!(m.overshoot.value.abs() < 1e-9);
+    }
+
+    #[test]
+    fn flags_clipped_and_dc_signals() {
+        let mut data = ChannelData::new(vec![1.0; 100], 1000.0);
+        data.clipped_samples = 3;
+        let m = measure(&data);
+
+        assert_eq!(m.max.validity, Validity::Clipped);
+        assert_eq!(m.frequency.validity, Validity::Invalid);
+        assert!(m.frequency.value().is_none());
+    }
+
+    #[test]
+    fn measures_phase_between_channels() {
+        let reference = sine(500.0, 1.0, 0.0, 50_000.0, 5_000);
+        let signal = sine(500.0, 0.5, -PI / 4.0, 50_000.0, 5_000);
+        let p = phase(&reference, &signal);
+
+        assert!((p.phase.value + 45.0).abs() < 0.5, "phase: {:?}", p.phase);
+        assert!((p.delay.value - 0.25e-3).abs() < 1e-5, "delay: {:?}", p.delay);
+    }
+}
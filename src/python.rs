@@ -1,5 +1,6 @@
 mod bench;
 mod scope;
+mod analog_input;
 mod analog_output;
 mod pulse_output;
 mod cli;
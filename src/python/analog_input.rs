@@ -0,0 +1,105 @@
+use pyo3::exceptions::*;
+use pyo3::prelude::*;
+
+use crate::{ChannelScaling, python};
+
+
+#[pymethods]
+impl python::Nlab {
+    fn ch_is_on(&self, ch: i64) -> PyResult<bool> {
+        let scope: &crate::Nlab = &self.0;
+
+        let channel = match ch {
+            1 => &scope.ch1,
+            2 => &scope.ch2,
+            3 => &scope.ch3,
+            4 => &scope.ch4,
+            _ => return Err(PyValueError::new_err(format!("Invalid channel number: {}", ch)))
+        };
+
+        Ok(channel.is_on())
+    }
+
+    fn ch_turn_on(&mut self, ch: i64) -> PyResult<()> {
+        let scope: &mut crate::Nlab = &mut self.0;
+
+        let channel = match ch {
+            1 => &mut scope.ch1,
+            2 => &mut scope.ch2,
+            3 => &mut scope.ch3,
+            4 => &mut scope.ch4,
+            _ => return Err(PyValueError::new_err(format!("Invalid channel number: {}", ch)))
+        };
+
+        channel.turn_on();
+        Ok(())
+    }
+
+    fn ch_turn_off(&mut self, ch: i64) -> PyResult<()> {
+        let scope: &mut crate::Nlab = &mut self.0;
+
+        let channel = match ch {
+            1 => &mut scope.ch1,
+            2 => &mut scope.ch2,
+            3 => &mut scope.ch3,
+            4 => &mut scope.ch4,
+            _ => return Err(PyValueError::new_err(format!("Invalid channel number: {}", ch)))
+        };
+
+        channel.turn_off();
+        Ok(())
+    }
+
+    /// Returns the effective (min_voltage, max_voltage, resolution) of the channel
+    fn ch_range(&self, ch: i64) -> PyResult<(f64, f64, f64)> {
+        let scope: &crate::Nlab = &self.0;
+
+        let channel = match ch {
+            1 => &scope.ch1,
+            2 => &scope.ch2,
+            3 => &scope.ch3,
+            4 => &scope.ch4,
+            _ => return Err(PyValueError::new_err(format!("Invalid channel number: {}", ch)))
+        };
+
+        let range = channel.range();
+        Ok((range.min_voltage, range.max_voltage, range.resolution))
+    }
+
+    /// Sets the range of the channel, returning the effective (min_voltage, max_voltage, resolution)
+    fn ch_set_range(&mut self, ch: i64, vmin: f64, vmax: f64) -> PyResult<(f64, f64, f64)> {
+        let scope: &mut crate::Nlab = &mut self.0;
+
+        let channel = match ch {
+            1 => &mut scope.ch1,
+            2 => &mut scope.ch2,
+            3 => &mut scope.ch3,
+            4 => &mut scope.ch4,
+            _ => return Err(PyValueError::new_err(format!("Invalid channel number: {}", ch)))
+        };
+
+        let range = channel.set_range(vmin, vmax).map_err(|error| PyValueError::new_err(error.to_string()))?;
+        Ok((range.min_voltage, range.max_voltage, range.resolution))
+    }
+
+    /// Sets a linear scaling from connector volts to engineering units: `scale * volts + offset`
+    fn ch_set_scaling(&mut self, ch: i64, scale: f64, offset: f64, unit: &str) -> PyResult<()> {
+        let scope: &mut crate::Nlab = &mut self.0;
+
+        let channel = match ch {
+            1 => &mut scope.ch1,
+            2 => &mut scope.ch2,
+            3 => &mut scope.ch3,
+            4 => &mut scope.ch4,
+            _ => return Err(PyValueError::new_err(format!("Invalid channel number: {}", ch)))
+        };
+
+        channel.set_scaling(ChannelScaling {
+            scale,
+            offset,
+            unit: unit.into(),
+            transform: None,
+        });
+        Ok(())
+    }
+}
@@ -1,7 +1,7 @@
 use pyo3::exceptions::*;
 use pyo3::prelude::*;
 
-use crate::{AnalogSignalPolarity, AnalogWaveType, python};
+use crate::{AnalogSignalPolarity, AnalogWaveType, Modulation, python};
 
 
 #[pymethods]
@@ -75,8 +75,7 @@ impl python::Nlab {
             _ => return Err(PyValueError::new_err(format!("Invalid channel number: {}", ch)))
         };
 
-        ax.turn_on();
-        Ok(())
+        ax.turn_on().map_err(|error| PyRuntimeError::new_err(error.to_string()))
     }
 
     fn ax_turn_off(&self, ch: i64) -> PyResult<()> {
@@ -88,8 +87,7 @@ impl python::Nlab {
             _ => return Err(PyValueError::new_err(format!("Invalid channel number: {}", ch)))
         };
 
-        ax.turn_off();
-        Ok(())
+        ax.turn_off().map_err(|error| PyRuntimeError::new_err(error.to_string()))
     }
 
     fn ax_set_frequency(&self, ch: i64, desired_hz: f64) -> PyResult<()> {
@@ -101,8 +99,7 @@ impl python::Nlab {
             _ => return Err(PyValueError::new_err(format!("Invalid channel number: {}", ch)))
         };
 
-        ax.set_frequency(desired_hz);
-        Ok(())
+        ax.set_frequency(desired_hz).map_err(|error| PyRuntimeError::new_err(error.to_string()))
     }
 
     fn ax_set_amplitude(&self, ch: i64, desired_volts: f64) -> PyResult<()> {
@@ -114,8 +111,7 @@ impl python::Nlab {
             _ => return Err(PyValueError::new_err(format!("Invalid channel number: {}", ch)))
         };
 
-        ax.set_amplitude(desired_volts);
-        Ok(())
+        ax.set_amplitude(desired_volts).map_err(|error| PyRuntimeError::new_err(error.to_string()))
     }
 
     fn ax_set_wave_type(&self, ch: i64, wave_type: AnalogWaveType) -> PyResult<()> {
@@ -127,8 +123,7 @@ impl python::Nlab {
             _ => return Err(PyValueError::new_err(format!("Invalid channel number: {}", ch)))
         };
 
-        ax.set_wave_type(wave_type);
-        Ok(())
+        ax.set_wave_type(wave_type).map_err(|error| PyRuntimeError::new_err(error.to_string()))
     }
 
     fn ax_set_polarity(&self, ch: i64, polarity: AnalogSignalPolarity) -> PyResult<()> {
@@ -140,7 +135,46 @@ impl python::Nlab {
             _ => return Err(PyValueError::new_err(format!("Invalid channel number: {}", ch)))
         };
 
-        ax.set_polarity(polarity);
-        Ok(())
+        ax.set_polarity(polarity).map_err(|error| PyRuntimeError::new_err(error.to_string()))
     }
-}
\ No newline at end of file
+
+    #[pyo3(signature = (ch, frequency_hz, depth, wave_type=AnalogWaveType::Sine))]
+    fn ax_set_am(&self, ch: i64, frequency_hz: f64, depth: f64, wave_type: AnalogWaveType) -> PyResult<()> {
+        let scope: &crate::Nlab = &self.0;
+
+        let ax = match ch {
+            1 => &scope.a1,
+            2 => &scope.a2,
+            _ => return Err(PyValueError::new_err(format!("Invalid channel number: {}", ch)))
+        };
+
+        let modulation = Modulation { wave_type, ..Modulation::amplitude(frequency_hz, depth) };
+        ax.set_modulation(Some(modulation)).map_err(|error| PyRuntimeError::new_err(error.to_string()))
+    }
+
+    #[pyo3(signature = (ch, frequency_hz, deviation_hz, wave_type=AnalogWaveType::Sine))]
+    fn ax_set_fm(&self, ch: i64, frequency_hz: f64, deviation_hz: f64, wave_type: AnalogWaveType) -> PyResult<()> {
+        let scope: &crate::Nlab = &self.0;
+
+        let ax = match ch {
+            1 => &scope.a1,
+            2 => &scope.a2,
+            _ => return Err(PyValueError::new_err(format!("Invalid channel number: {}", ch)))
+        };
+
+        let modulation = Modulation { wave_type, ..Modulation::frequency(frequency_hz, deviation_hz) };
+        ax.set_modulation(Some(modulation)).map_err(|error| PyRuntimeError::new_err(error.to_string()))
+    }
+
+    fn ax_stop_modulation(&self, ch: i64) -> PyResult<()> {
+        let scope: &crate::Nlab = &self.0;
+
+        let ax = match ch {
+            1 => &scope.a1,
+            2 => &scope.a2,
+            _ => return Err(PyValueError::new_err(format!("Invalid channel number: {}", ch)))
+        };
+
+        ax.set_modulation(None).map_err(|error| PyRuntimeError::new_err(error.to_string()))
+    }
+}
@@ -75,8 +75,7 @@ impl python::Nlab {
             _ => return Err(PyValueError::new_err(format!("Invalid channel number: {}", ch)))
         };
 
-        px.turn_on();
-        Ok(())
+        px.turn_on().map_err(|error| PyRuntimeError::new_err(error.to_string()))
     }
 
     fn px_turn_off(&self, ch: i64) -> PyResult<()> {
@@ -88,8 +87,7 @@ impl python::Nlab {
             _ => return Err(PyValueError::new_err(format!("Invalid channel number: {}", ch)))
         };
 
-        px.turn_off();
-        Ok(())
+        px.turn_off().map_err(|error| PyRuntimeError::new_err(error.to_string()))
     }
 
     fn px_set_frequency(&self, ch: i64, desired_hz: f64) -> PyResult<()> {
@@ -101,8 +99,7 @@ impl python::Nlab {
             _ => return Err(PyValueError::new_err(format!("Invalid channel number: {}", ch)))
         };
 
-        px.set_frequency(desired_hz);
-        Ok(())
+        px.set_frequency(desired_hz).map_err(|error| PyRuntimeError::new_err(error.to_string()))
     }
 
     fn px_set_duty(&self, ch: i64, desired_percentage: f64) -> PyResult<()> {
@@ -114,7 +111,21 @@ impl python::Nlab {
             _ => return Err(PyValueError::new_err(format!("Invalid channel number: {}", ch)))
         };
 
-        px.set_duty(desired_percentage);
-        Ok(())
+        px.set_duty(desired_percentage).map_err(|error| PyRuntimeError::new_err(error.to_string()))
+    }
+
+    fn px_achievable_frequencies(&self, ch: i64, desired_hz: f64) -> PyResult<(f64, f64, f64)> {
+        let scope: &crate::Nlab = &self.0;
+
+        let px = match ch {
+            1 => &scope.p1,
+            2 => &scope.p2,
+            _ => return Err(PyValueError::new_err(format!("Invalid channel number: {}", ch)))
+        };
+
+        match px.achievable_frequencies(desired_hz) {
+            Ok(frequencies) => Ok((frequencies.below, frequencies.above, frequencies.resolution)),
+            Err(error) => Err(PyValueError::new_err(error.to_string())),
+        }
     }
 }
\ No newline at end of file
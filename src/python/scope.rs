@@ -1,7 +1,11 @@
 use pyo3::exceptions::*;
 use pyo3::prelude::*;
 
-use crate::{PowerStatus, python, Sample};
+use std::fs::File;
+use std::io::BufWriter;
+
+use crate::{PowerStatus, python, SafeStatePolicy, Sample};
+use crate::export::csv::{self, Delimiter};
 
 #[pymethods]
 impl python::Nlab {
@@ -15,6 +19,12 @@ impl python::Nlab {
         scope.close()
     }
 
+    #[pyo3(signature = (analog_outputs_off=true, pulse_outputs_off=true, power_off=true))]
+    fn set_safe_state(&mut self, analog_outputs_off: bool, pulse_outputs_off: bool, power_off: bool) {
+        let scope: &mut crate::Nlab = &mut self.0;
+        scope.set_safe_state(SafeStatePolicy { analog_outputs_off, pulse_outputs_off, power_off })
+    }
+
     fn version(&self) -> PyResult<u16> {
         let scope: &crate::Nlab = &self.0;
         match scope.version() {
@@ -52,4 +62,34 @@ impl python::Nlab {
         }
         Ok(return_data)
     }
+
+    #[pyo3(signature = (path, sample_rate, number_of_samples, tab_separated=false))]
+    fn write_csv(&self, path: &str, sample_rate: f64, number_of_samples: u32, tab_separated: bool) -> PyResult<u32> {
+        let scope: &crate::Nlab = &self.0;
+        let file = match File::create(path) {
+            Ok(file) => BufWriter::new(file),
+            Err(error) => return Err(PyIOError::new_err(error.to_string())),
+        };
+        let delimiter = if tab_separated { Delimiter::Tab } else { Delimiter::Comma };
+        let sweep_handle = scope.request(sample_rate, number_of_samples, None);
+        match csv::write_sweep_handle(sweep_handle, file, delimiter) {
+            Ok(samples) => Ok(samples),
+            Err(error) => Err(PyIOError::new_err(error.to_string())),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+#[pymethods]
+impl python::Nlab {
+    fn save_profile(&self, path: &str) -> PyResult<()> {
+        let scope: &crate::Nlab = &self.0;
+        scope.snapshot().save(path).map_err(|error| PyIOError::new_err(error.to_string()))
+    }
+
+    fn load_profile(&mut self, path: &str) -> PyResult<()> {
+        let scope: &mut crate::Nlab = &mut self.0;
+        let profile = crate::Profile::load(path).map_err(|error| PyIOError::new_err(error.to_string()))?;
+        scope.apply(&profile).map_err(|error| PyRuntimeError::new_err(error.to_string()))
+    }
 }
@@ -12,6 +12,7 @@ use std::{fmt, thread};
 use std::convert::TryInto;
 use std::error::Error;
 use std::sync::{Arc, mpsc, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::Sender;
 use std::thread::JoinHandle;
 use std::time::Duration;
@@ -23,17 +24,21 @@ use analog_input::AnalogInput;
 use analog_output::AnalogOutput;
 use commands::Command;
 use power::PowerStatus;
+use profile::AcquisitionSettings;
 use pulse_output::PulseOutput;
+use safe_state::SafeStatePolicy;
 use trigger::Trigger;
 use crate::lab_bench::NlabDevice;
 
-mod commands;
+pub(crate) mod commands;
 pub mod analog_input;
 pub mod analog_output;
 pub mod pulse_output;
 pub mod trigger;
 pub mod power;
 pub mod data_requests;
+pub mod profile;
+pub mod safe_state;
 mod run_loops;
 
 enum NlabHandle {
@@ -54,10 +59,17 @@ pub struct Nlab {
     pub ch3: AnalogInput,
     pub ch4: AnalogInput,
 
+    is_legacy: bool,
+    /// Shared with the panic hook, which can turn the power supply off
+    power_on: Arc<AtomicBool>,
+    trigger: Trigger,
+    acquisition: AcquisitionSettings,
     fw_version: Arc<RwLock<Option<u16>>>,
     power_status: Arc<RwLock<PowerStatus>>,
     command_tx: Sender<Command>,
     join_handle: Option<JoinHandle<()>>,
+    safe_state: SafeStatePolicy,
+    safe_state_id: usize,
 }
 
 impl fmt::Debug for Nlab {
@@ -108,20 +120,27 @@ impl Nlab {
             }
         };
 
-        let scope = Nlab {
-            a1: AnalogOutput::create(command_tx.clone(), 0),
-            a2: AnalogOutput::create(command_tx.clone(), 1),
-            p1: PulseOutput::create(command_tx.clone(), 0),
-            p2: PulseOutput::create(command_tx.clone(), 1),
+        let mut scope = Nlab {
+            a1: AnalogOutput::create(command_tx.clone(), 0, is_legacy),
+            a2: AnalogOutput::create(command_tx.clone(), 1, is_legacy),
+            p1: PulseOutput::create(command_tx.clone(), 0, is_legacy),
+            p2: PulseOutput::create(command_tx.clone(), 1, is_legacy),
             ch1: AnalogInput::create(is_legacy),
             ch2: AnalogInput::create(is_legacy),
             ch3: AnalogInput::create(is_legacy),
             ch4: AnalogInput::create(is_legacy),
+            is_legacy,
+            power_on: Arc::new(AtomicBool::new(power_on)),
+            trigger: Trigger::default(),
+            acquisition: AcquisitionSettings::default(),
             fw_version,
             power_status,
             command_tx,
             join_handle,
+            safe_state: SafeStatePolicy::default(),
+            safe_state_id: 0,
         };
+        scope.safe_state_id = safe_state::register(&scope);
 
         // Send the initialization command
         let (init_tx, init_rx) = mpsc::channel::<()>();
@@ -139,6 +158,59 @@ impl Nlab {
         Err("Cannot initialize scope".into())
     }
 
+    /// Creates an nLab without a device, whose commands are acknowledged by a thread on the host
+    ///
+    /// `accept` is called with each command before it is acknowledged. Commands it returns false
+    /// for are dropped unanswered, as when the connection to the nLab is lost.
+    #[cfg(test)]
+    pub(crate) fn mock(is_legacy: bool, mut accept: impl FnMut(&Command) -> bool + Send + 'static) -> Self {
+        use commands::ScopeCommand;
+
+        let (command_tx, command_rx) = mpsc::channel::<Command>();
+        let join_handle = thread::spawn(move || {
+            let usb_buf = [0u8; 64];
+            for command in command_rx.iter() {
+                if let Command::Quit = command {
+                    break;
+                }
+                if !accept(&command) {
+                    continue;
+                }
+                match command {
+                    Command::Initialize(_, tx) => { let _ = tx.send(()); }
+                    Command::SetAnalogOutput(request) if is_legacy => request.handle_rx_legacy(&usb_buf),
+                    Command::SetAnalogOutput(request) => request.handle_rx(&usb_buf),
+                    Command::SetPulseOutput(request) if is_legacy => request.handle_rx_legacy(&usb_buf),
+                    Command::SetPulseOutput(request) => request.handle_rx(&usb_buf),
+                    _ => {}
+                }
+            }
+        });
+
+        let mut scope = Nlab {
+            a1: AnalogOutput::create(command_tx.clone(), 0, is_legacy),
+            a2: AnalogOutput::create(command_tx.clone(), 1, is_legacy),
+            p1: PulseOutput::create(command_tx.clone(), 0, is_legacy),
+            p2: PulseOutput::create(command_tx.clone(), 1, is_legacy),
+            ch1: AnalogInput::create(is_legacy),
+            ch2: AnalogInput::create(is_legacy),
+            ch3: AnalogInput::create(is_legacy),
+            ch4: AnalogInput::create(is_legacy),
+            is_legacy,
+            power_on: Arc::new(AtomicBool::new(true)),
+            trigger: Trigger::default(),
+            acquisition: AcquisitionSettings::default(),
+            fw_version: Arc::new(RwLock::new(None)),
+            power_status: Arc::new(RwLock::new(PowerStatus::default())),
+            command_tx,
+            join_handle: Some(join_handle),
+            safe_state: SafeStatePolicy::default(),
+            safe_state_id: 0,
+        };
+        scope.safe_state_id = safe_state::register(&scope);
+        scope
+    }
+
     pub fn is_connected(&self) -> bool {
         match &self.join_handle {
             Some(handle) => !handle.is_finished(),
@@ -146,12 +218,44 @@ impl Nlab {
         }
     }
 
+    /// Applies the safe state policy and ends the connection to the nLab
     pub fn close(&mut self) {
+        self.shut_down();
+    }
+
+    fn shut_down(&mut self) {
+        safe_state::unregister(self.safe_state_id);
+        if self.join_handle.is_none() {
+            return;
+        }
+        self.apply_safe_state();
+
+        // Send a quit command to the IO loop
         let _ = self.command_tx.send(Command::Quit);
+
         // Wait for the loop to end
-        if self.join_handle.is_some() {
-            self.join_handle.take().unwrap().join().unwrap()
+        self.join_handle.take().unwrap().join().unwrap()
+    }
+
+    /// Returns whether the nLab power supply was last set to on
+    pub fn power_on(&self) -> bool {
+        self.power_on.load(Ordering::Relaxed)
+    }
+
+    /// Turns the nLab power supply on or off
+    pub fn set_power_on(&mut self, power_on: bool) -> Result<(), Box<dyn Error>> {
+        let (tx, rx) = mpsc::channel::<()>();
+        self.command_tx.send(Command::Initialize(power_on, tx)).map_err(|_| "nLab connection aborted")?;
+        // Legacy nLabs do not acknowledge power commands
+        if !self.is_legacy {
+            rx.recv_timeout(Duration::from_secs(5)).map_err(|_| "No response to power command")?;
         }
+        self.power_on.store(power_on, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub(crate) fn is_legacy(&self) -> bool {
+        self.is_legacy
     }
 
     #[deprecated(since = "1.1.0", note = "Please use `version` instead")]
@@ -194,18 +298,49 @@ impl Nlab {
             _ => None,
         }
     }
+
+    pub fn channel_mut(&mut self, channel: usize) -> Option<&mut AnalogInput> {
+        match channel {
+            1 => Some(&mut self.ch1),
+            2 => Some(&mut self.ch2),
+            3 => Some(&mut self.ch3),
+            4 => Some(&mut self.ch4),
+            _ => None,
+        }
+    }
+
+    /// Runs `f` with only the given channels (1 through 4) turned on, restoring them afterwards
+    ///
+    /// The channels are restored even if `f` panics.
+    pub(crate) fn with_channels<T>(&mut self, channels: &[usize], f: impl FnOnce(&Nlab) -> T) -> T {
+        let previous = [self.ch1.is_on, self.ch2.is_on, self.ch3.is_on, self.ch4.is_on];
+        for ch in 1..=4 {
+            self.channel_mut(ch).unwrap().is_on = channels.contains(&ch);
+        }
+
+        let guard = ChannelGuard { nlab: self, previous };
+        f(guard.nlab)
+    }
+}
+
+/// Restores the channels changed by [`Nlab::with_channels`] when dropped
+struct ChannelGuard<'a> {
+    nlab: &'a mut Nlab,
+    previous: [bool; 4],
+}
+
+impl Drop for ChannelGuard<'_> {
+    fn drop(&mut self) {
+        for (ch, &was_on) in (1..=4).zip(self.previous.iter()) {
+            self.nlab.channel_mut(ch).unwrap().is_on = was_on;
+        }
+    }
 }
 
 /// When an Nlab goes out of scope, we need to exit the IO loop
 impl Drop for Nlab {
     fn drop(&mut self) {
-        // Send a quit command to the IO loop
-        let _ = self.command_tx.send(Command::Quit);
-
-        // Wait for the loop to end
-        if self.join_handle.is_some() {
-            self.join_handle.take().unwrap().join().unwrap()
-        }
+        self.shut_down();
     }
 }
 
@@ -10,9 +10,17 @@
 
 mod voltages_legacy;
 mod voltages;
+mod scaling;
+
+use std::error::Error;
+use std::sync::Arc;
 
 use voltages_legacy::AnalogInterfaceLegacy;
 use voltages::AnalogInterfaceModern;
+pub use scaling::{ChannelScaling, SensorTransform};
+
+const ADC_MIN: u16 = 0;
+const ADC_MAX: u16 = 4095;
 
 #[derive(Debug, Copy, Clone)]
 enum AnalogInterface {
@@ -20,11 +28,42 @@ enum AnalogInterface {
     Modern(AnalogInterfaceModern)
 }
 
+/// Effective input range of a scope channel, after quantization to the hardware settings
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ChannelRange {
+    /// Voltage read at the lowest ADC code
+    pub min_voltage: f64,
+    /// Voltage read at the highest ADC code
+    pub max_voltage: f64,
+    /// Voltage represented by a single ADC code
+    pub resolution: f64,
+}
+
+/// Configuration of a single scope channel
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ChannelConfig {
+    pub is_on: bool,
+    pub range: ChannelRange,
+    pub scaling: ChannelScaling,
+    /// Gain setting of a legacy nLab's front end that produces the range, 0 on nLab v2
+    ///
+    /// Recorded so that captures can be converted with the exact settings of the sweep. Ignored
+    /// by [`AnalogInput::apply_config`], which derives the settings from the range.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub gain_cmd: u8,
+    /// Offset setting of a legacy nLab's front end that produces the range, 0 on nLab v2
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub offset_cmd: u8,
+}
+
 /// Interface to a single scope channel
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 pub struct AnalogInput {
     pub(crate) is_on: bool,
     analog_interface: AnalogInterface,
+    scaling: Arc<ChannelScaling>,
 }
 
 impl AnalogInput {
@@ -37,15 +76,29 @@ impl AnalogInput {
                         gain_setting: 0,
                         offset_setting: 0,
                     }),
+                scaling: Default::default(),
             },
             false => AnalogInput {
                 is_on: true,
                 analog_interface: AnalogInterface::Modern(
                     AnalogInterfaceModern {
                     }),
+                scaling: Default::default(),
             }
         };
-        analog_input.set_range(-5.0, 5.0);
+        analog_input.program_range(-5.0, 5.0);
+        analog_input
+    }
+
+    /// Recreates a channel from the gain and offset settings recorded in its configuration
+    pub(crate) fn from_settings(is_legacy: bool, config: &ChannelConfig) -> Self {
+        let mut analog_input = AnalogInput::create(is_legacy);
+        analog_input.is_on = config.is_on;
+        analog_input.scaling = Arc::new(config.scaling.clone());
+        if let AnalogInterface::Legacy(interface) = &mut analog_input.analog_interface {
+            interface.gain_setting = config.gain_cmd;
+            interface.offset_setting = config.offset_cmd;
+        }
         analog_input
     }
 }
@@ -63,13 +116,72 @@ impl AnalogInput {
         self.is_on = false;
     }
 
-    pub fn set_range(&mut self, vmin: f64, vmax: f64) {
-        match self.analog_interface {
-            AnalogInterface::Legacy(mut interface) => { interface.set_range(vmin, vmax) }
-            AnalogInterface::Modern(mut interface) => { interface.set_range(vmin, vmax) }
+    /// Sets the desired input range of the channel
+    ///
+    /// Returns the effective range, which may differ from the desired range after
+    /// the gain and offset are quantized to what the hardware supports. An error is returned,
+    /// and the range left unchanged, unless `vmin` and `vmax` are finite and `vmin < vmax`.
+    pub fn set_range(&mut self, vmin: f64, vmax: f64) -> Result<ChannelRange, Box<dyn Error>> {
+        validate_range(vmin, vmax)?;
+        Ok(self.program_range(vmin, vmax))
+    }
+
+    fn program_range(&mut self, vmin: f64, vmax: f64) -> ChannelRange {
+        match &mut self.analog_interface {
+            AnalogInterface::Legacy(interface) => { interface.set_range(vmin, vmax) }
+            AnalogInterface::Modern(interface) => { interface.set_range(vmin, vmax) }
+        }
+        self.range()
+    }
+
+    /// Returns the effective input range of the channel
+    pub fn range(&self) -> ChannelRange {
+        let min_voltage = self.voltage_from_measurement(ADC_MIN);
+        let max_voltage = self.voltage_from_measurement(ADC_MAX);
+        ChannelRange {
+            min_voltage,
+            max_voltage,
+            resolution: (max_voltage - min_voltage) / (ADC_MAX - ADC_MIN) as f64,
         }
     }
 
+    /// Returns the current configuration of the channel
+    pub fn config(&self) -> ChannelConfig {
+        ChannelConfig {
+            is_on: self.is_on,
+            range: self.range(),
+            scaling: (*self.scaling).clone(),
+            gain_cmd: self.gain_cmd(),
+            offset_cmd: self.offset_cmd(),
+        }
+    }
+
+    /// Applies a channel configuration, returning the effective range of the channel
+    ///
+    /// An error is returned, and the channel left unchanged, if the range is invalid.
+    pub fn apply_config(&mut self, config: &ChannelConfig) -> Result<ChannelRange, Box<dyn Error>> {
+        validate_range(config.range.min_voltage, config.range.max_voltage)?;
+        self.is_on = config.is_on;
+        self.scaling = Arc::new(config.scaling.clone());
+        self.set_range(config.range.min_voltage, config.range.max_voltage)
+    }
+
+    /// Returns the conversion from connector voltage to the channel's engineering units
+    pub fn scaling(&self) -> &ChannelScaling {
+        &self.scaling
+    }
+
+    /// Sets the conversion from connector voltage to engineering units, applied to all
+    /// samples and trigger levels on this channel
+    pub fn set_scaling(&mut self, scaling: ChannelScaling) {
+        self.scaling = Arc::new(scaling);
+    }
+
+    /// Returns the engineering unit of values reported by this channel
+    pub fn unit(&self) -> Arc<str> {
+        self.scaling.unit.clone()
+    }
+
     pub fn gain(&self) -> f64 {
         match self.analog_interface {
             AnalogInterface::Legacy(interface) => { interface.gain() }
@@ -91,6 +203,22 @@ impl AnalogInput {
         }
     }
 
+    pub(crate) fn value_from_measurement(&self, adc_data: u16) -> f64 {
+        self.scaling.apply(self.voltage_from_measurement(adc_data))
+    }
+
+    pub(crate) fn measurement_from_value(&self, value: f64) -> Option<i16> {
+        let range = self.range();
+        self.scaling
+            .invert(value, range.min_voltage, range.max_voltage)
+            .map(|voltage| self.measurement_from_voltage(voltage))
+    }
+
+    /// An ADC code at either rail means the input is at or beyond the edge of the range
+    pub(crate) fn is_clipped(&self, adc_data: u16) -> bool {
+        adc_data == ADC_MIN || adc_data >= ADC_MAX
+    }
+
     pub(crate) fn gain_cmd(&self) -> u8 {
         match self.analog_interface {
             AnalogInterface::Legacy(interface) => { interface.gain_setting }
@@ -106,3 +234,39 @@ impl AnalogInput {
     }
 }
 
+/// Checks that a desired input range is finite and not empty
+pub(crate) fn validate_range(vmin: f64, vmax: f64) -> Result<(), Box<dyn Error>> {
+    if !vmin.is_finite() || !vmax.is_finite() {
+        return Err(format!("Channel range must be finite, got {} V to {} V", vmin, vmax).into());
+    }
+    if vmin >= vmax {
+        return Err(format!("Channel range minimum {} V must be below the maximum {} V", vmin, vmax).into());
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_invalid_ranges() {
+        for &is_legacy in [true, false].iter() {
+            let mut channel = AnalogInput::create(is_legacy);
+            let range = channel.set_range(-2.0, 2.0).unwrap();
+            assert_eq!(channel.range(), range);
+
+            for &(vmin, vmax) in [(1.0, 1.0), (2.0, -2.0), (f64::NAN, 1.0), (-1.0, f64::INFINITY)].iter() {
+                assert!(channel.set_range(vmin, vmax).is_err());
+                assert_eq!(channel.range(), range);
+            }
+
+            let mut config = channel.config();
+            config.is_on = false;
+            config.range.max_voltage = config.range.min_voltage;
+            assert!(channel.apply_config(&config).is_err());
+            assert!(channel.is_on());
+        }
+    }
+}
@@ -0,0 +1,217 @@
+use std::sync::Arc;
+
+const KELVIN_OFFSET: f64 = 273.15;
+const INVERSION_STEPS: usize = 256;
+const INVERSION_ITERATIONS: usize = 60;
+
+/// Non-linear transform applied to a channel value after its scale and offset
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SensorTransform {
+    /// Polynomial with coefficients in increasing order of power: `c0 + c1*x + c2*x^2 + ...`
+    Polynomial(Vec<f64>),
+    /// Piecewise linear table of `(input, output)` points sorted by input, clamped at the ends
+    LookupTable(Vec<(f64, f64)>),
+    /// Thermistor in the lower leg of a voltage divider, converted to degrees Celsius
+    ///
+    /// The divider is driven by `excitation_voltage` through `series_resistance` (in Ohms), and
+    /// the channel measures the voltage across the thermistor.
+    SteinhartHart {
+        a: f64,
+        b: f64,
+        c: f64,
+        series_resistance: f64,
+        excitation_voltage: f64,
+    },
+}
+
+impl SensorTransform {
+    fn apply(&self, x: f64) -> f64 {
+        match self {
+            SensorTransform::Polynomial(coefficients) => {
+                coefficients.iter().rev().fold(0.0, |acc, &c| acc * x + c)
+            }
+            SensorTransform::LookupTable(points) => {
+                match points.iter().position(|&(input, _)| input >= x) {
+                    None => points.last().map_or(f64::NAN, |&(_, output)| output),
+                    Some(0) => points[0].1,
+                    Some(i) => {
+                        let (x0, y0) = points[i - 1];
+                        let (x1, y1) = points[i];
+                        y0 + (y1 - y0) * (x - x0) / (x1 - x0)
+                    }
+                }
+            }
+            SensorTransform::SteinhartHart { a, b, c, series_resistance, excitation_voltage } => {
+                if x <= 0.0 || x >= *excitation_voltage {
+                    return f64::NAN;
+                }
+                let resistance = series_resistance * x / (excitation_voltage - x);
+                let ln_r = resistance.ln();
+                1.0 / (a + b * ln_r + c * ln_r.powi(3)) - KELVIN_OFFSET
+            }
+        }
+    }
+}
+
+/// Conversion from the voltage at a channel connector to engineering units
+///
+/// The voltage is first scaled and offset, `scale * volts + offset`, and the result is then
+/// passed through the optional non-linear transform.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ChannelScaling {
+    pub scale: f64,
+    pub offset: f64,
+    pub unit: Arc<str>,
+    pub transform: Option<SensorTransform>,
+}
+
+impl Default for ChannelScaling {
+    fn default() -> Self {
+        ChannelScaling {
+            scale: 1.0,
+            offset: 0.0,
+            unit: "V".into(),
+            transform: None,
+        }
+    }
+}
+
+impl ChannelScaling {
+    /// Scaling for a voltage probe with the given attenuation, e.g. `10.0` for a 10x probe
+    pub fn probe(attenuation: f64) -> Self {
+        ChannelScaling {
+            scale: attenuation,
+            ..Default::default()
+        }
+    }
+
+    /// Scaling for a current-sense shunt with the given resistance in Ohms
+    pub fn shunt(resistance: f64) -> Self {
+        ChannelScaling {
+            scale: 1.0 / resistance,
+            unit: "A".into(),
+            ..Default::default()
+        }
+    }
+
+    /// Scaling for a sensor with the given transform, reporting values in `unit`
+    pub fn sensor(transform: SensorTransform, unit: &str) -> Self {
+        ChannelScaling {
+            unit: unit.into(),
+            transform: Some(transform),
+            ..Default::default()
+        }
+    }
+
+    /// Converts a voltage at the connector to engineering units
+    pub fn apply(&self, voltage: f64) -> f64 {
+        let linear = self.scale * voltage + self.offset;
+        match &self.transform {
+            Some(transform) => transform.apply(linear),
+            None => linear,
+        }
+    }
+
+    /// Finds the voltage at the connector that reads as `value`, searching between `vmin` and `vmax`
+    pub fn invert(&self, value: f64, vmin: f64, vmax: f64) -> Option<f64> {
+        if self.transform.is_none() {
+            let voltage = (value - self.offset) / self.scale;
+            return voltage.is_finite().then_some(voltage);
+        }
+
+        // Step through the range to bracket the value, then bisect within the bracket
+        let step = (vmax - vmin) / INVERSION_STEPS as f64;
+        let error = |v: f64| self.apply(v) - value;
+        for i in 0..INVERSION_STEPS {
+            let (mut lo, mut hi) = (vmin + step * i as f64, vmin + step * (i + 1) as f64);
+            let (e_lo, e_hi) = (error(lo), error(hi));
+            if !(e_lo.is_finite() && e_hi.is_finite()) || (e_lo.signum() == e_hi.signum() && e_hi != 0.0) {
+                continue;
+            }
+            for _ in 0..INVERSION_ITERATIONS {
+                let mid = (lo + hi) / 2.0;
+                if error(mid).signum() == e_lo.signum() {
+                    lo = mid;
+                } else {
+                    hi = mid;
+                }
+            }
+            return Some((lo + hi) / 2.0);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn thermistor() -> SensorTransform {
+        // Coefficients of a common 10 kOhm NTC thermistor
+        SensorTransform::SteinhartHart {
+            a: 1.009249522e-3,
+            b: 2.378405444e-4,
+            c: 2.019202697e-7,
+            series_resistance: 10e3,
+            excitation_voltage: 3.3,
+        }
+    }
+
+    #[test]
+    fn applies_transforms() {
+        let polynomial = SensorTransform::Polynomial(vec![1.0, 2.0, 3.0]);
+        assert_eq!(polynomial.apply(2.0), 17.0);
+        assert_eq!(SensorTransform::Polynomial(vec![]).apply(2.0), 0.0);
+
+        let table = SensorTransform::LookupTable(vec![(0.0, 0.0), (1.0, 10.0), (2.0, 30.0)]);
+        assert_eq!(table.apply(0.5), 5.0);
+        assert_eq!(table.apply(1.5), 20.0);
+        assert!(SensorTransform::LookupTable(vec![]).apply(1.0).is_nan());
+
+        // Half the excitation puts the thermistor at its 10 kOhm point, close to 25 C
+        let celsius = thermistor().apply(1.65);
+        assert!((celsius - 24.7).abs() < 0.1, "{}", celsius);
+        assert!(thermistor().apply(0.0).is_nan());
+        assert!(thermistor().apply(3.3).is_nan());
+    }
+
+    #[test]
+    fn lookup_table_clamps_at_the_ends() {
+        let table = SensorTransform::LookupTable(vec![(0.0, 0.0), (1.0, 10.0), (2.0, 30.0)]);
+        assert_eq!(table.apply(-5.0), 0.0);
+        assert_eq!(table.apply(0.0), 0.0);
+        assert_eq!(table.apply(2.0), 30.0);
+        assert_eq!(table.apply(7.0), 30.0);
+    }
+
+    #[test]
+    fn inverts_each_transform() {
+        let scalings = [
+            ChannelScaling { offset: 0.5, ..ChannelScaling::probe(10.0) },
+            ChannelScaling::sensor(SensorTransform::Polynomial(vec![0.0, 2.0, 0.5]), "Pa"),
+            ChannelScaling::sensor(SensorTransform::LookupTable(vec![(0.0, 0.0), (1.0, 10.0), (5.0, 30.0)]), "mm"),
+            ChannelScaling::sensor(thermistor(), "C"),
+        ];
+        for scaling in scalings.iter() {
+            for &voltage in [0.2, 1.3, 2.9].iter() {
+                let inverted = scaling.invert(scaling.apply(voltage), 0.0, 3.0).unwrap();
+                assert!((inverted - voltage).abs() < 1e-6, "{:?} at {} V gave {} V", scaling, voltage, inverted);
+            }
+        }
+    }
+
+    #[test]
+    fn invert_fails_outside_the_range() {
+        let polynomial = ChannelScaling::sensor(SensorTransform::Polynomial(vec![0.0, 2.0]), "Pa");
+        assert!(polynomial.invert(100.0, 0.0, 5.0).is_none());
+
+        // The table is clamped at 30, so larger values are never read
+        let table = ChannelScaling::sensor(SensorTransform::LookupTable(vec![(0.0, 0.0), (2.0, 30.0)]), "mm");
+        assert!(table.invert(40.0, -1.0, 5.0).is_none());
+
+        assert!(ChannelScaling::sensor(thermistor(), "C").invert(500.0, 0.0, 3.3).is_none());
+        assert!(ChannelScaling::probe(0.0).invert(1.0, 0.0, 5.0).is_none());
+    }
+}
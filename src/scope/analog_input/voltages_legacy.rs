@@ -27,7 +27,9 @@ impl AnalogInterfaceLegacy {
 
     pub(super) fn set_gain(&mut self, gain: f64) {
         let desired_gain_setting = (gain - 1.0 - ALPHA1) / ALPHA2;
-        self.gain_setting = desired_gain_setting as u8;
+        // Round down so the effective range covers the desired range, with a small tolerance
+        // so that re-applying an effective range lands on the same setting
+        self.gain_setting = (desired_gain_setting + 1e-6) as u8;
     }
 
     pub(super) fn gain(&self) -> f64 {
@@ -9,9 +9,12 @@
  **************************************************************************************************/
 
 use std::error::Error;
+use std::f64::consts::PI;
 use std::str::FromStr;
-use std::sync::{mpsc, RwLock};
-use std::sync::mpsc::Sender;
+use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 use pyo3::pyclass;
 
 use crate::scope::commands::ScopeCommand;
@@ -20,6 +23,7 @@ use super::commands::Command;
 
 /// Possible analog output signal types
 #[derive(Debug, PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[pyclass(eq, eq_int)]
 pub enum AnalogWaveType {
     Sine = 0,
@@ -39,6 +43,7 @@ impl FromStr for AnalogWaveType {
 
 /// Possible analog output polarities
 #[derive(Debug, PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[pyclass(eq, eq_int)]
 pub enum AnalogSignalPolarity {
     Unipolar = 0,
@@ -56,45 +61,325 @@ impl FromStr for AnalogSignalPolarity {
     }
 }
 
-/// Interface to an analog output
-#[derive(Clone, Copy, Debug)]
-struct AnalogOutputState {
-    is_on: bool,
-    frequency: f64,
-    amplitude: f64,
-    wave_type: AnalogWaveType,
-    polarity: AnalogSignalPolarity,
+/// Snapshot of the settings of an analog output
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AnalogOutputState {
+    pub is_on: bool,
+    pub frequency: f64,
+    pub amplitude: f64,
+    pub wave_type: AnalogWaveType,
+    pub polarity: AnalogSignalPolarity,
+    /// Modulation applied to the carrier described by the other settings
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub modulation: Option<Modulation>,
+}
+
+/// Highest rate at which the host updates a modulated output
+pub const MAX_MODULATION_UPDATE_RATE_HZ: f64 = 100.0;
+/// Fewest updates per period of the modulating signal
+const MIN_UPDATES_PER_PERIOD: f64 = 4.0;
+
+/// How the modulating signal changes the carrier
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ModulationType {
+    /// The amplitude swings by `depth`, a fraction of the carrier amplitude from 0 up to but not
+    /// including 1, as the outputs cannot produce a zero amplitude at the trough
+    Amplitude { depth: f64 },
+    /// The frequency swings by `deviation` Hz either side of the carrier frequency
+    Frequency { deviation: f64 },
+}
+
+/// Amplitude or frequency modulation of an analog output
+///
+/// The nLab firmware has no modulation command, so the output is modulated by the host, which
+/// sends the carrier settings at each point of the modulating signal `update_rate` times a
+/// second. The modulating signal is therefore limited to a few Hz.
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Modulation {
+    pub modulation_type: ModulationType,
+    /// Frequency of the modulating signal
+    pub frequency: f64,
+    /// Shape of the modulating signal
+    pub wave_type: AnalogWaveType,
+    /// Number of updates sent to the nLab per second
+    pub update_rate: f64,
+}
+
+impl Modulation {
+    /// Amplitude modulation by a sine at the highest update rate
+    pub fn amplitude(frequency: f64, depth: f64) -> Self {
+        Modulation {
+            modulation_type: ModulationType::Amplitude { depth },
+            frequency,
+            wave_type: AnalogWaveType::Sine,
+            update_rate: MAX_MODULATION_UPDATE_RATE_HZ,
+        }
+    }
+
+    /// Frequency modulation by a sine at the highest update rate
+    pub fn frequency(frequency: f64, deviation: f64) -> Self {
+        Modulation {
+            modulation_type: ModulationType::Frequency { deviation },
+            frequency,
+            wave_type: AnalogWaveType::Sine,
+            update_rate: MAX_MODULATION_UPDATE_RATE_HZ,
+        }
+    }
+
+    /// Value of the modulating signal, from -1 to 1, `t` seconds after modulation started
+    fn signal(&self, t: f64) -> f64 {
+        let cycles = self.frequency * t;
+        match self.wave_type {
+            AnalogWaveType::Sine => (2.0 * PI * cycles).sin(),
+            AnalogWaveType::Triangle => 1.0 - 4.0 * ((cycles + 0.25).rem_euclid(1.0) - 0.5).abs(),
+        }
+    }
+
+    /// Settings of a carrier when the modulating signal is at `signal`
+    fn modulate(&self, carrier: &AnalogOutputState, signal: f64) -> AnalogOutputState {
+        let mut state = AnalogOutputState { modulation: None, ..*carrier };
+        match self.modulation_type {
+            ModulationType::Amplitude { depth } => state.amplitude *= 1.0 + depth * signal,
+            ModulationType::Frequency { deviation } => state.frequency += deviation * signal,
+        }
+        state
+    }
+
+    /// Settings of a carrier `t` seconds after modulation started
+    pub fn apply(&self, carrier: &AnalogOutputState, t: f64) -> AnalogOutputState {
+        self.modulate(carrier, self.signal(t))
+    }
+}
+
+// Legacy output stage: the amplitude is set by an 8 bit digital potentiometer in the feedback
+// path of the output amplifier, and the frequency by a 28 bit DDS clocked at 4 MHz
+const LEGACY_VIN: f64 = 0.6;
+const LEGACY_RF: f64 = 49900.0;
+const LEGACY_RM: f64 = 75.0;
+const LEGACY_RV: f64 = 100000.0 / 257.0;
+const LEGACY_DDS_CLOCK_HZ: f64 = 4000000.0;
+
+/// Largest voltage swing of the outputs, from ground to the 5 V supply
+const OUTPUT_SWING: f64 = 5.0;
+/// Highest frequency passed by the output reconstruction filter
+const MAX_FREQUENCY_HZ: f64 = 100000.0;
+
+/// Range of the magnitude of the amplitude of an analog output, in Volts
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AmplitudeRange {
+    /// Smallest amplitude, or 0 where it is not known
+    pub min: f64,
+    pub max: f64,
+}
+
+impl AmplitudeRange {
+    pub fn contains(&self, amplitude: f64) -> bool {
+        (self.min..=self.max).contains(&amplitude.abs())
+    }
+}
+
+/// Settings an analog output of the connected nLab can produce
+///
+/// Negative amplitudes invert the waveform, so the amplitude limits apply to its magnitude. The
+/// smallest frequency and amplitude of nLab v2 are not published, so they are reported as 0 and
+/// any positive value is sent to the nLab, which may not reproduce very low frequencies or very
+/// small amplitudes accurately.
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AnalogOutputLimits {
+    /// Lowest frequency in Hz, or 0 where it is not known
+    pub min_frequency: f64,
+    pub max_frequency: f64,
+    /// Amplitude of a unipolar signal, which swings between ground and the amplitude
+    pub unipolar: AmplitudeRange,
+    /// Amplitude of a bipolar signal, which swings between plus and minus the amplitude
+    pub bipolar: AmplitudeRange,
+}
+
+impl AnalogOutputLimits {
+    fn new(is_legacy: bool) -> Self {
+        if is_legacy {
+            // The smallest amplitude is at the highest gain setting, the largest is bounded by the
+            // swing of the output rather than the lowest gain setting
+            let min_unipolar = LEGACY_VIN * LEGACY_RF / (LEGACY_RM + LEGACY_RV * 255.0);
+            AnalogOutputLimits {
+                min_frequency: LEGACY_DDS_CLOCK_HZ / 2.0_f64.powi(28),
+                max_frequency: MAX_FREQUENCY_HZ,
+                unipolar: AmplitudeRange { min: min_unipolar, max: OUTPUT_SWING },
+                bipolar: AmplitudeRange { min: min_unipolar / 2.0, max: OUTPUT_SWING / 2.0 },
+            }
+        } else {
+            // The reconstruction filter and the swing of the supply are shared with the legacy
+            // board. The DAC resolution and update rate of the firmware are not published, so the
+            // lower limits are unknown.
+            AnalogOutputLimits {
+                min_frequency: 0.0,
+                max_frequency: MAX_FREQUENCY_HZ,
+                unipolar: AmplitudeRange { min: 0.0, max: OUTPUT_SWING },
+                bipolar: AmplitudeRange { min: 0.0, max: OUTPUT_SWING / 2.0 },
+            }
+        }
+    }
+
+    /// Returns the amplitude range for a signal polarity
+    pub fn amplitude(&self, polarity: AnalogSignalPolarity) -> AmplitudeRange {
+        match polarity {
+            AnalogSignalPolarity::Unipolar => self.unipolar,
+            AnalogSignalPolarity::Bipolar => self.bipolar,
+        }
+    }
+
+    /// Checks that an output state can be produced
+    ///
+    /// The frequency and amplitude must be nonzero even where the lower limits are not known.
+    pub fn validate(&self, state: &AnalogOutputState) -> Result<(), Box<dyn Error>> {
+        if !(state.frequency > 0.0 && (self.min_frequency..=self.max_frequency).contains(&state.frequency)) {
+            return Err(format!(
+                "Frequency {} Hz is outside the range {} Hz to {} Hz",
+                state.frequency, self.min_frequency, self.max_frequency
+            ).into());
+        }
+        let range = self.amplitude(state.polarity);
+        if state.amplitude == 0.0 || !range.contains(state.amplitude) {
+            return Err(format!(
+                "{:?} amplitude {} V is outside the range {} V to {} V",
+                state.polarity, state.amplitude, range.min, range.max
+            ).into());
+        }
+        if let Some(modulation) = &state.modulation {
+            self.validate_modulation(state, modulation)?;
+        }
+        Ok(())
+    }
+
+    fn validate_modulation(&self, carrier: &AnalogOutputState, modulation: &Modulation) -> Result<(), Box<dyn Error>> {
+        if !(modulation.update_rate > 0.0 && modulation.update_rate <= MAX_MODULATION_UPDATE_RATE_HZ) {
+            return Err(format!(
+                "Modulation update rate {} Hz is outside the range 0 Hz to {} Hz",
+                modulation.update_rate, MAX_MODULATION_UPDATE_RATE_HZ
+            ).into());
+        }
+        let max_frequency = modulation.update_rate / MIN_UPDATES_PER_PERIOD;
+        if !(modulation.frequency > 0.0 && modulation.frequency <= max_frequency) {
+            return Err(format!(
+                "Modulating frequency {} Hz is outside the range 0 Hz to {} Hz at an update rate of {} Hz",
+                modulation.frequency, max_frequency, modulation.update_rate
+            ).into());
+        }
+        match modulation.modulation_type {
+            ModulationType::Amplitude { depth } if !(0.0..1.0).contains(&depth) => {
+                return Err(format!("Modulation depth {} must be at least 0 and less than 1", depth).into());
+            }
+            ModulationType::Frequency { deviation } if !(deviation.is_finite() && deviation >= 0.0) => {
+                return Err(format!("Frequency deviation {} Hz must be positive", deviation).into());
+            }
+            _ => {}
+        }
+        // The modulated settings are furthest from the carrier at the peaks of the modulating signal
+        for &signal in [-1.0, 1.0].iter() {
+            self.validate(&modulation.modulate(carrier, signal))
+                .map_err(|error| format!("At the modulation peaks: {}", error))?;
+        }
+        Ok(())
+    }
 }
 
 /// Interface to an analog output channel
 #[derive(Debug)]
 pub struct AnalogOutput {
     pub channel: usize,
+    limits: AnalogOutputLimits,
     command_tx: Sender<Command>,
-    state: RwLock<AnalogOutputState>,
+    state: Arc<RwLock<AnalogOutputState>>,
+    modulator: Mutex<Option<Modulator>>,
+}
+
+/// Thread that sends the settings of a modulated output to the nLab
+#[derive(Debug)]
+struct Modulator {
+    stop_tx: Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl Modulator {
+    fn start(command_tx: Sender<Command>, channel: usize, state: Arc<RwLock<AnalogOutputState>>) -> Self {
+        let (stop_tx, stop_rx) = mpsc::channel::<()>();
+        let handle = thread::spawn(move || Self::run(command_tx, channel, state, stop_rx));
+        Modulator { stop_tx, handle }
+    }
+
+    fn run(
+        command_tx: Sender<Command>,
+        channel: usize,
+        state: Arc<RwLock<AnalogOutputState>>,
+        stop_rx: Receiver<()>,
+    ) {
+        let start = Instant::now();
+        let mut next_update = start;
+        loop {
+            // The carrier and modulation are read at every update so that changes take effect
+            let carrier = *state.read().unwrap();
+            let modulation = match carrier.modulation {
+                Some(modulation) => modulation,
+                None => break,
+            };
+
+            if carrier.is_on {
+                let (tx, rx) = mpsc::channel::<AnalogOutputState>();
+                let command = Command::SetAnalogOutput(AxRequest {
+                    channel,
+                    ax_state: modulation.apply(&carrier, start.elapsed().as_secs_f64()),
+                    sender: tx,
+                });
+                if command_tx.send(command).is_err() || rx.recv().is_err() {
+                    break;
+                }
+            }
+
+            next_update += Duration::from_secs_f64(1.0 / modulation.update_rate);
+            match stop_rx.recv_timeout(next_update.saturating_duration_since(Instant::now())) {
+                Err(RecvTimeoutError::Timeout) => {}
+                _ => break,
+            }
+        }
+    }
+
+    fn stop(self) {
+        let _ = self.stop_tx.send(());
+        let _ = self.handle.join();
+    }
 }
 
 impl AnalogOutput {
-    pub(super) fn create(cmd_tx: Sender<Command>, ax_channel: usize) -> Self {
+    pub(super) fn create(cmd_tx: Sender<Command>, ax_channel: usize, is_legacy: bool) -> Self {
         let default_state = AnalogOutputState {
             is_on: false,
             frequency: 1.0,
             amplitude: 1.0,
             wave_type: AnalogWaveType::Sine,
             polarity: AnalogSignalPolarity::Unipolar,
+            modulation: None,
         };
 
         let ax = AnalogOutput {
             command_tx: cmd_tx,
             channel: ax_channel,
-            state: RwLock::new(default_state),
+            limits: AnalogOutputLimits::new(is_legacy),
+            state: Arc::new(RwLock::new(default_state)),
+            modulator: Mutex::new(None),
         };
 
-        ax.set(default_state);
+        let _ = ax.set(default_state);
         ax
     }
 
-    fn set(&self, ax_state: AnalogOutputState) {
+    fn set(&self, ax_state: AnalogOutputState) -> Result<(), Box<dyn Error>> {
+        self.limits.validate(&ax_state)?;
+
         // Create a method for the backend to communicate back to us what we want
         let (tx, rx) = mpsc::channel::<AnalogOutputState>();
 
@@ -103,18 +388,73 @@ impl AnalogOutput {
             channel: self.channel,
             ax_state,
             sender: tx,
-
         });
 
         // Send the command to the backend
-        if self.command_tx.send(command).is_ok() {
+        self.command_tx.send(command).map_err(|_| "nLab connection aborted")?;
 
-            // Wait for the response from the backend
-            if let Ok(response_state) = rx.recv() {
-                // Write the response state
-                *self.state.write().unwrap() = response_state;
-            }
+        // Wait for the response from the backend
+        let response = rx.recv().map_err(|_| "No response to analog output command")?;
+
+        // Write the response state
+        self.update_state(response);
+        self.update_modulator(ax_state.modulation.is_some());
+        Ok(())
+    }
+
+    /// Starts or stops the thread that modulates the output
+    fn update_modulator(&self, is_modulated: bool) {
+        let mut modulator = self.modulator.lock().unwrap();
+        // A modulator exits by itself when the connection to the nLab is lost
+        let is_running = matches!(modulator.as_ref(), Some(modulator) if !modulator.handle.is_finished());
+        if is_modulated && is_running {
+            return;
+        }
+        if let Some(stopped) = modulator.take() {
+            stopped.stop();
+        }
+        if is_modulated {
+            let command_tx = self.command_tx.clone();
+            *modulator = Some(Modulator::start(command_tx, self.channel, self.state.clone()));
+        }
+    }
+
+    pub(super) fn update_state(&self, state: AnalogOutputState) {
+        *self.state.write().unwrap() = state;
+    }
+
+    /// Returns a snapshot of the output's current settings
+    ///
+    /// nLab v2 firmware acknowledges output commands without reporting the settings it applied,
+    /// so these are the settings last sent to the nLab.
+    pub fn state(&self) -> AnalogOutputState {
+        *self.state.read().unwrap()
+    }
+
+    pub(super) fn shared_state(&self) -> Arc<RwLock<AnalogOutputState>> {
+        self.state.clone()
+    }
+
+    /// Stops any modulation and turns the output off
+    pub(super) fn shut_down(&self) -> Result<(), Box<dyn Error>> {
+        // Stop the modulator first so that it cannot turn the output back on
+        if let Some(modulator) = self.modulator.lock().unwrap().take() {
+            modulator.stop();
         }
+        self.set(AnalogOutputState { is_on: false, modulation: None, ..self.state() })
+    }
+
+    /// Applies all settings of the output in a single command
+    ///
+    /// Use this to change the polarity and amplitude together when the current amplitude is
+    /// outside the range of the new polarity.
+    pub fn set_state(&self, state: AnalogOutputState) -> Result<(), Box<dyn Error>> {
+        self.set(state)
+    }
+
+    /// Returns the frequencies and amplitudes the output can produce
+    pub fn limits(&self) -> AnalogOutputLimits {
+        self.limits
     }
 
     pub fn is_on(&self) -> bool {
@@ -132,44 +472,73 @@ impl AnalogOutput {
     pub fn polarity(&self) -> AnalogSignalPolarity {
         self.state.read().unwrap().polarity
     }
+    pub fn modulation(&self) -> Option<Modulation> {
+        self.state.read().unwrap().modulation
+    }
 
 
-    pub fn turn_on(&self) {
+    pub fn turn_on(&self) -> Result<(), Box<dyn Error>> {
         let mut state = *self.state.read().unwrap();
         state.is_on = true;
         self.set(state)
     }
-    pub fn turn_off(&self) {
+    pub fn turn_off(&self) -> Result<(), Box<dyn Error>> {
         let mut state = *self.state.read().unwrap();
         state.is_on = false;
         self.set(state)
     }
 
-    pub fn set_frequency(&self, desired_hz: f64) {
+    pub fn set_frequency(&self, desired_hz: f64) -> Result<(), Box<dyn Error>> {
         let mut state = *self.state.read().unwrap();
         state.frequency = desired_hz;
         self.set(state)
     }
 
-    pub fn set_amplitude(&self, desired_volts: f64) {
+    pub fn set_amplitude(&self, desired_volts: f64) -> Result<(), Box<dyn Error>> {
         let mut state = *self.state.read().unwrap();
         state.amplitude = desired_volts;
         self.set(state)
     }
 
-    pub fn set_wave_type(&self, wave_type: AnalogWaveType) {
+    pub fn set_wave_type(&self, wave_type: AnalogWaveType) -> Result<(), Box<dyn Error>> {
         let mut state = *self.state.read().unwrap();
         state.wave_type = wave_type;
         self.set(state)
     }
 
-    pub fn set_polarity(&self, polarity: AnalogSignalPolarity) {
+    pub fn set_polarity(&self, polarity: AnalogSignalPolarity) -> Result<(), Box<dyn Error>> {
         let mut state = *self.state.read().unwrap();
         state.polarity = polarity;
         self.set(state)
     }
+
+    /// Modulates the output, or returns it to a steady carrier when `modulation` is `None`
+    ///
+    /// The carrier is set by the other settings of the output, which can be changed while it
+    /// is modulated.
+    pub fn set_modulation(&self, modulation: Option<Modulation>) -> Result<(), Box<dyn Error>> {
+        let mut state = *self.state.read().unwrap();
+        state.modulation = modulation;
+        self.set(state)
+    }
+}
+
+impl Drop for AnalogOutput {
+    fn drop(&mut self) {
+        if let Some(modulator) = self.modulator.get_mut().unwrap().take() {
+            modulator.stop();
+        }
+    }
 }
 
+/// Writes the settings of an analog output as the 12 byte block of the nLab v2 commands
+pub(super) fn encode(state: &AnalogOutputState, block: &mut [u8]) {
+    block[0] = state.is_on as u8;
+    block[1..=4].copy_from_slice(&(state.frequency as f32).to_le_bytes());
+    block[5..=8].copy_from_slice(&(state.amplitude as f32).to_le_bytes());
+    block[9] = state.wave_type as u8;
+    block[10] = state.polarity as u8;
+}
 
 #[derive(Debug)]
 pub(crate) struct AxRequest {
@@ -178,6 +547,15 @@ pub(crate) struct AxRequest {
     sender: Sender<AnalogOutputState>,
 }
 
+impl AxRequest {
+    /// Request that turns an output off, for use where the output itself is not available
+    pub(super) fn turn_off(channel: usize, state: AnalogOutputState) -> (Self, Receiver<AnalogOutputState>) {
+        let (tx, rx) = mpsc::channel();
+        let ax_state = AnalogOutputState { is_on: false, modulation: None, ..state };
+        (AxRequest { channel, ax_state, sender: tx }, rx)
+    }
+}
+
 impl ScopeCommand for AxRequest {
     fn fill_tx_buffer_legacy(&self, usb_buf: &mut [u8; 65]) -> Result<(), Box<dyn Error>> {
         usb_buf[1] = 0x02;
@@ -187,7 +565,7 @@ impl ScopeCommand for AxRequest {
             usb_buf[i_ch] = self.ax_state.wave_type as u8;
             usb_buf[i_ch] |= 0x80;
 
-            let scaled_frequency = self.ax_state.frequency * 2.0_f64.powi(28) / 4000000.0;
+            let scaled_frequency = self.ax_state.frequency * 2.0_f64.powi(28) / LEGACY_DDS_CLOCK_HZ;
             let freq_register: u32 = scaled_frequency as u32;
 
             usb_buf[i_ch + 1] = (freq_register & 0x00FF) as u8;
@@ -198,10 +576,10 @@ impl ScopeCommand for AxRequest {
             if self.ax_state.amplitude < 0.0 {
                 usb_buf[i_ch] |= 0x2;
             }
-            let rf = 49900.0;
-            let vin = 0.6;
-            let rm = 75.0;
-            let rv = 100000.0 / 257.0;
+            let rf = LEGACY_RF;
+            let vin = LEGACY_VIN;
+            let rm = LEGACY_RM;
+            let rv = LEGACY_RV;
 
             let gain: u8 = match self.ax_state.polarity {
                 AnalogSignalPolarity::Unipolar => ((vin * rf / self.ax_state.amplitude.abs() - rm) / rv) as u8,
@@ -228,27 +606,111 @@ impl ScopeCommand for AxRequest {
         usb_buf[3] = 0x1 << self.channel;
 
         let idx_start = 4 + 12 * self.channel;
-
-        usb_buf[idx_start] = self.ax_state.is_on as u8;
-        usb_buf[idx_start + 1..=idx_start + 4].copy_from_slice(
-            &(self.ax_state.frequency as f32).to_le_bytes());
-        usb_buf[idx_start + 5..=idx_start + 8].copy_from_slice(
-            &(self.ax_state.amplitude as f32).to_le_bytes());
-        usb_buf[idx_start + 9] = self.ax_state.wave_type as u8;
-        usb_buf[idx_start + 10] = self.ax_state.polarity as u8;
+        encode(&self.ax_state, &mut usb_buf[idx_start..idx_start + 12]);
 
         Ok(())
     }
 
     fn handle_rx_legacy(&self, _usb_buf: &[u8; 64]) {
-        self.sender.send(self.ax_state).unwrap();
+        // The requester may have stopped waiting, as the panic hook does
+        let _ = self.sender.send(self.ax_state);
     }
 
     fn handle_rx(&self, _usb_buf: &[u8; 64]) {
-        self.sender.send(self.ax_state).unwrap();
+        // The response only acknowledges the command, it does not report the applied settings
+        let _ = self.sender.send(self.ax_state);
     }
 
     fn is_finished(&self) -> bool {
         true
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_settings_outside_the_limits() {
+        let state = AnalogOutputState {
+            is_on: true,
+            frequency: 1000.0,
+            amplitude: 2.0,
+            wave_type: AnalogWaveType::Sine,
+            polarity: AnalogSignalPolarity::Bipolar,
+            modulation: None,
+        };
+        for &is_legacy in [true, false].iter() {
+            let limits = AnalogOutputLimits::new(is_legacy);
+            assert!(limits.validate(&state).is_ok());
+            assert!(limits.validate(&AnalogOutputState { amplitude: -2.0, ..state }).is_ok());
+            assert!(limits.validate(&AnalogOutputState { amplitude: 0.0, ..state }).is_err());
+            assert!(limits.validate(&AnalogOutputState { amplitude: 3.0, ..state }).is_err());
+            assert!(limits.validate(&AnalogOutputState { frequency: 0.0, ..state }).is_err());
+            assert!(limits.validate(&AnalogOutputState { frequency: f64::NAN, ..state }).is_err());
+            // Above the reconstruction filter
+            assert!(limits.validate(&AnalogOutputState { frequency: 1e6, ..state }).is_err());
+            assert!(limits.validate(&AnalogOutputState { frequency: 3e38, ..state }).is_err());
+        }
+
+        // The legacy gain register must stay within 8 bits across the whole range
+        let limits = AnalogOutputLimits::new(true);
+        let gain = (LEGACY_VIN * LEGACY_RF / limits.unipolar.min - LEGACY_RM) / LEGACY_RV;
+        assert!((gain - 255.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn keeps_the_requested_settings() {
+        let ax_state = AnalogOutputState {
+            is_on: true,
+            frequency: 100.0,
+            amplitude: 1.0,
+            wave_type: AnalogWaveType::Sine,
+            polarity: AnalogSignalPolarity::Unipolar,
+            modulation: None,
+        };
+        // The response of nLab v2 firmware does not hold the applied settings
+        let mut usb_buf = [0u8; 64];
+        usb_buf[8..20].fill(0xFF);
+
+        let (sender, receiver) = mpsc::channel();
+        AxRequest { channel: 1, ax_state, sender }.handle_rx(&usb_buf);
+        assert_eq!(receiver.recv().unwrap(), ax_state);
+    }
+
+    #[test]
+    fn modulates_the_carrier() {
+        let carrier = AnalogOutputState {
+            is_on: true,
+            frequency: 1000.0,
+            amplitude: 1.0,
+            wave_type: AnalogWaveType::Sine,
+            polarity: AnalogSignalPolarity::Bipolar,
+            modulation: None,
+        };
+
+        let am = Modulation { wave_type: AnalogWaveType::Triangle, ..Modulation::amplitude(2.0, 0.5) };
+        assert!((am.apply(&carrier, 0.0).amplitude - 1.0).abs() < 1e-9);
+        assert!((am.apply(&carrier, 0.125).amplitude - 1.5).abs() < 1e-9);
+        assert!((am.apply(&carrier, 0.375).amplitude - 0.5).abs() < 1e-9);
+        assert_eq!(am.apply(&carrier, 0.125).frequency, 1000.0);
+
+        let fm = Modulation::frequency(1.0, 200.0);
+        assert!((fm.apply(&carrier, 0.25).frequency - 1200.0).abs() < 1e-9);
+        assert!((fm.apply(&carrier, 0.75).frequency - 800.0).abs() < 1e-9);
+
+        let limits = AnalogOutputLimits::new(false);
+        let modulated = |modulation| AnalogOutputState { modulation: Some(modulation), ..carrier };
+        assert!(limits.validate(&modulated(am)).is_ok());
+        assert!(limits.validate(&modulated(fm)).is_ok());
+        // A full depth would need a zero amplitude at the trough
+        let error = limits.validate(&modulated(Modulation::amplitude(2.0, 1.0))).unwrap_err();
+        assert!(error.to_string().starts_with("Modulation depth 1 must be"), "{}", error);
+        assert!(limits.validate(&modulated(Modulation::amplitude(2.0, 0.99))).is_ok());
+        // The peaks of the modulated amplitude must be within the limits
+        assert!(limits.validate(&AnalogOutputState { amplitude: 1.6, ..modulated(Modulation::amplitude(2.0, 0.5)) }).is_ok());
+        assert!(limits.validate(&AnalogOutputState { amplitude: 1.8, ..modulated(Modulation::amplitude(2.0, 0.5)) }).is_err());
+        // Each period of the modulating signal needs several updates
+        assert!(limits.validate(&modulated(Modulation::frequency(50.0, 200.0))).is_err());
+        assert!(limits.validate(&modulated(Modulation { update_rate: 1000.0, ..fm })).is_err());
+    }
+}
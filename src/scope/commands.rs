@@ -84,7 +84,7 @@ impl Command {
     pub(super) fn handle_rx(&self, buffer: &[u8; 64]) {
         match self {
             Command::Quit => {}
-            Command::Initialize(_, sender) => { sender.send(()).unwrap() }
+            Command::Initialize(_, sender) => { let _ = sender.send(()); }
             Command::SetAnalogOutput(cmd) => { cmd.handle_rx(buffer) }
             Command::SetPulseOutput(cmd) => { cmd.handle_rx(buffer) }
             Command::RequestData(cmd) => { cmd.handle_rx(buffer) }
@@ -12,20 +12,32 @@ use std::collections::VecDeque;
 use std::error::Error;
 use std::sync::{Arc, mpsc, RwLock};
 use std::sync::mpsc::{Receiver, Sender};
+use std::time::{Instant, SystemTime};
 
 use log::{trace, debug};
 
 use super::AnalogInput;
+use super::analog_input::ChannelConfig;
+use super::analog_output::AnalogOutputState;
+use super::pulse_output::PulseOutputState;
 use super::Command;
 use super::commands::ScopeCommand;
 use super::Nlab;
 use super::Trigger;
 
-/// Voltage information from all open channels at a given time
+/// Readings from all open channels at a given time
+///
+/// Each reading is in the engineering units of its channel's scaling, volts by default
 #[derive(Debug, Default, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Sample {
     pub time_since_start: f64,
     pub data: [Option<f64>; Sample::num_channels() as usize],
+    pub units: [Option<Arc<str>>; Sample::num_channels() as usize],
+    /// Whether each reading is at the limit of its channel's range, meaning the input saturated
+    pub clipped: [bool; Sample::num_channels() as usize],
+    /// Raw 12-bit ADC code of each reading
+    pub raw: [Option<u16>; Sample::num_channels() as usize],
 }
 
 impl Sample {
@@ -33,6 +45,108 @@ impl Sample {
 
     pub fn clear(&mut self) {
         self.data = [None; Sample::num_channels() as usize];
+        self.units = Default::default();
+        self.clipped = [false; Sample::num_channels() as usize];
+        self.raw = [None; Sample::num_channels() as usize];
+    }
+
+    /// Returns true if any channel in this sample is over-range
+    pub fn is_clipped(&self) -> bool {
+        self.clipped.iter().any(|&clipped| clipped)
+    }
+}
+
+/// Parameters of a data sweep, as configured when the sweep was requested
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SweepMetadata {
+    /// Sample rate produced by the nLab, after quantization to its sample clock
+    pub sample_rate_hz: f64,
+    pub requested_sample_rate_hz: f64,
+    pub number_of_samples: u32,
+    pub channels: [ChannelConfig; 4],
+    pub trigger: Trigger,
+    /// Firmware version of the nLab that recorded the sweep
+    pub firmware_version: Option<u16>,
+    /// Time at which the sweep was requested
+    pub start_time: SystemTime,
+    /// When the stimulus of a sweep started by [`Nlab::request_with_stimulus`] was applied
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub stimulus: Option<StimulusTiming>,
+}
+
+/// Output change applied just before a sweep, numbered from 1 as in [`Nlab::analog_output`] and
+/// [`Nlab::pulse_output`]
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Stimulus {
+    Analog(usize, AnalogOutputState),
+    Pulse(usize, PulseOutputState),
+}
+
+/// Time at which a stimulus took effect, relative to the start of the sweep
+///
+/// The nLab does not timestamp output changes, so the timing is measured on the host. The output
+/// changed while its command was in flight, between sending it and its acknowledgement, and the
+/// sweep started within one more command round trip of being requested. `offset` is the middle
+/// of the resulting window and `uncertainty` is half of its width, both in seconds. With a
+/// trigger enabled, the sweep is armed at that time and starts at the trigger.
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StimulusTiming {
+    /// Seconds from the start of the sweep to the stimulus, negative as it is applied first
+    pub offset: f64,
+    pub uncertainty: f64,
+}
+
+/// Data from a sweep that has finished
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Sweep {
+    pub metadata: SweepMetadata,
+    pub samples: Vec<Sample>,
+    pub clipped_samples: [u32; 4],
+}
+
+/// Readings from a single channel of a sweep, at a fixed sample rate
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ChannelData {
+    pub values: Vec<f64>,
+    pub sample_rate_hz: f64,
+    pub unit: Arc<str>,
+    pub clipped_samples: u32,
+}
+
+impl ChannelData {
+    /// Creates channel data from a block of voltage readings taken at `sample_rate_hz`
+    pub fn new(values: Vec<f64>, sample_rate_hz: f64) -> Self {
+        ChannelData {
+            values,
+            sample_rate_hz,
+            unit: "V".into(),
+            clipped_samples: 0,
+        }
+    }
+}
+
+impl Sweep {
+    /// Returns the readings of a channel, 1 through 4 as in [`Nlab::channel`], or None if the
+    /// channel was off
+    ///
+    /// [`Nlab::channel`]: crate::Nlab::channel
+    pub fn channel(&self, channel: usize) -> Option<ChannelData> {
+        let index = channel.checked_sub(1)?;
+        let config = self.metadata.channels.get(index)?;
+        if !config.is_on {
+            return None;
+        }
+        Some(ChannelData {
+            values: self.samples.iter().filter_map(|sample| sample.data[index]).collect(),
+            sample_rate_hz: self.metadata.sample_rate_hz,
+            unit: config.scaling.unit.clone(),
+            clipped_samples: self.clipped_samples[index],
+        })
     }
 }
 
@@ -40,10 +154,13 @@ impl Sample {
 pub(crate) struct DataRequest {
     pub channels: [AnalogInput; 4],
     pub sample_rate_hz: f64,
+    pub effective_sample_rate_hz: f64,
+    pub number_of_samples: u32,
     pub remaining_samples: Arc<RwLock<u32>>,
     pub trigger: Trigger,
     pub sender: Sender<Sample>,
     pub stop_recv: Receiver<()>,
+    pub clipped_samples: Arc<RwLock<[u32; 4]>>,
 
     data_collator: Arc<RwLock<[VecDeque<u16>; 4]>>,
 }
@@ -52,7 +169,9 @@ pub(crate) struct DataRequest {
 #[derive(Debug)]
 pub struct SweepHandle {
     pub receiver: Receiver<Sample>,
+    metadata: SweepMetadata,
     samples_remaining: Arc<RwLock<u32>>,
+    clipped_samples: Arc<RwLock<[u32; 4]>>,
     stop_send: Sender<()>,
 }
 
@@ -62,13 +181,32 @@ impl Nlab {
         let (stop_send, stop_recv) = mpsc::channel::<()>();
 
         let remaining_samples = Arc::new(RwLock::new(number_of_samples));
+        let clipped_samples = Arc::new(RwLock::new([0; 4]));
+        let channels = [self.ch1.clone(), self.ch2.clone(), self.ch3.clone(), self.ch4.clone()];
+        let trigger = trigger.unwrap_or_default();
+        let num_channels_on = channels.iter().filter(|&ch| ch.is_on).count();
+
+        let metadata = SweepMetadata {
+            sample_rate_hz: effective_sample_rate(sample_rate_hz, num_channels_on, self.is_legacy),
+            requested_sample_rate_hz: sample_rate_hz,
+            number_of_samples,
+            channels: [channels[0].config(), channels[1].config(), channels[2].config(), channels[3].config()],
+            trigger,
+            firmware_version: *self.fw_version.read().unwrap(),
+            start_time: SystemTime::now(),
+            stimulus: None,
+        };
+
         let command = Command::RequestData(DataRequest {
-            channels: [self.ch1, self.ch2, self.ch3, self.ch4],
+            channels,
             sample_rate_hz,
+            effective_sample_rate_hz: metadata.sample_rate_hz,
+            number_of_samples,
             remaining_samples: remaining_samples.clone(),
-            trigger: trigger.unwrap_or_default(),
+            trigger,
             sender: tx,
             stop_recv,
+            clipped_samples: clipped_samples.clone(),
             data_collator: Default::default(),
         });
 
@@ -78,17 +216,148 @@ impl Nlab {
 
         SweepHandle {
             receiver: rx,
+            metadata,
             samples_remaining: remaining_samples,
+            clipped_samples,
             stop_send,
         }
     }
+
+    /// Applies an output change, then requests a sweep, recording when the change took effect
+    ///
+    /// The output command and the sweep request are separate USB commands, so the stimulus lands
+    /// shortly before the sweep starts. The measured offset between them and its uncertainty,
+    /// typically around a millisecond, are stored in [`SweepMetadata::stimulus`]. Use a trigger
+    /// on a channel that sees the stimulus when the response must be aligned more closely.
+    pub fn request_with_stimulus(
+        &self,
+        stimulus: Stimulus,
+        sample_rate_hz: f64,
+        number_of_samples: u32,
+        trigger: Option<Trigger>,
+    ) -> Result<SweepHandle, Box<dyn Error>> {
+        let sent = Instant::now();
+        match stimulus {
+            Stimulus::Analog(channel, state) => self
+                .analog_output(channel)
+                .ok_or_else(|| format!("Invalid analog output: {}", channel))?
+                .set_state(state)?,
+            Stimulus::Pulse(channel, state) => self
+                .pulse_output(channel)
+                .ok_or_else(|| format!("Invalid pulse output: {}", channel))?
+                .set_state(state)?,
+        }
+        let round_trip = sent.elapsed();
+
+        let mut handle = self.request(sample_rate_hz, number_of_samples, trigger);
+        let requested = sent.elapsed();
+        // The stimulus took effect within [0, round_trip] of `sent`, and the sweep started
+        // within [requested, requested + round_trip]
+        handle.metadata.stimulus = Some(StimulusTiming {
+            offset: -requested.as_secs_f64(),
+            uncertainty: round_trip.as_secs_f64(),
+        });
+        Ok(handle)
+    }
+}
+
+/// Samples per channel that fit in the buffer of a legacy nLab, shared by the open channels
+const LEGACY_BUFFER_SAMPLES: u32 = 3200;
+/// Samples per channel that fit in the buffer of an nLab v2
+const BUFFER_SAMPLES: u32 = 2400;
+
+/// Number of samples per channel that the nLab can record at any sample rate
+///
+/// Longer sweeps are streamed as they are recorded, which only keeps up at low sample rates.
+pub(crate) fn buffer_samples(num_channels_on: usize, is_legacy: bool) -> u32 {
+    if is_legacy {
+        LEGACY_BUFFER_SAMPLES / num_channels_on.max(1) as u32
+    } else {
+        BUFFER_SAMPLES
+    }
+}
+
+/// Sample clock of the nLab, the highest rate at which it records samples
+pub(crate) fn sample_clock(num_channels_on: usize, is_legacy: bool) -> f64 {
+    match (is_legacy, num_channels_on) {
+        (false, _) => 2_000_000.0,
+        (true, 1) => 4_000_000.0,
+        (true, 2) => 2_000_000.0,
+        (true, _) => 1_000_000.0,
+    }
+}
+
+/// Sample rate the nLab produces for a requested rate, given the integer sample clock divider
+pub(crate) fn effective_sample_rate(sample_rate_hz: f64, num_channels_on: usize, is_legacy: bool) -> f64 {
+    let clock_hz = sample_clock(num_channels_on, is_legacy);
+    let samples_between_records = ((clock_hz / sample_rate_hz) as u32).max(1);
+    clock_hz / samples_between_records as f64
+}
+
+/// Converts the ADC codes of one sample into a `Sample` in the units of each channel
+pub(crate) fn sample_from_measurements(
+    channels: &[AnalogInput; 4],
+    sample_rate_hz: f64,
+    index: u32,
+    measurements: [Option<u16>; 4],
+) -> Sample {
+    let mut sample = Sample {
+        time_since_start: index as f64 / sample_rate_hz,
+        ..Default::default()
+    };
+    for (ch, measurement) in measurements.iter().enumerate() {
+        if let Some(adc_data) = *measurement {
+            let channel = &channels[ch];
+            sample.data[ch] = Some(channel.value_from_measurement(adc_data));
+            sample.units[ch] = Some(channel.unit());
+            sample.clipped[ch] = channel.is_clipped(adc_data);
+            sample.raw[ch] = Some(adc_data);
+        }
+    }
+    sample
 }
 
 impl SweepHandle {
+    /// Creates a handle to a sweep whose samples are produced by something other than an nLab
+    pub(crate) fn new(
+        receiver: Receiver<Sample>,
+        metadata: SweepMetadata,
+        samples_remaining: Arc<RwLock<u32>>,
+        clipped_samples: Arc<RwLock<[u32; 4]>>,
+        stop_send: Sender<()>,
+    ) -> Self {
+        SweepHandle { receiver, metadata, samples_remaining, clipped_samples, stop_send }
+    }
+
+    /// Returns the parameters the sweep was requested with
+    pub fn metadata(&self) -> &SweepMetadata {
+        &self.metadata
+    }
+
+    /// Returns the sample rate produced by the nLab for this sweep
+    pub fn sample_rate_hz(&self) -> f64 {
+        self.metadata.sample_rate_hz
+    }
+
+    /// Blocks until the sweep finishes, returning all of its data
+    pub fn into_sweep(self) -> Sweep {
+        let samples: Vec<Sample> = self.receiver.iter().collect();
+        Sweep {
+            clipped_samples: *self.clipped_samples.read().unwrap(),
+            metadata: self.metadata,
+            samples,
+        }
+    }
+
     pub fn remaining_samples(&self) -> u32 {
         *self.samples_remaining.read().unwrap()
     }
 
+    /// Returns the number of over-range samples received so far on each channel
+    pub fn clipped_samples(&self) -> [u32; 4] {
+        *self.clipped_samples.read().unwrap()
+    }
+
     pub fn stop(&self) {
         self.stop_send.send(()).ok();
     }
@@ -108,9 +377,12 @@ impl ScopeCommand for DataRequest {
             3 | 4 => { (1_000_000.0 / self.sample_rate_hz) as u32 }
             _ => { return Err("Unexpected number of channels are on".into()); }
         };
+        if samples_between_records == 0 {
+            return Err("Sample rate is above the sample clock of the nLab".into());
+        }
 
         let total_samples = *self.remaining_samples.read().unwrap();
-        if samples_between_records < 250 && total_samples * num_channels_on as u32 > 3200 {
+        if samples_between_records < 250 && total_samples * num_channels_on as u32 > LEGACY_BUFFER_SAMPLES {
             return Err("Data not recordable".into());
         }
 
@@ -125,8 +397,7 @@ impl ScopeCommand for DataRequest {
             if !(0..4usize).contains(&self.trigger.source_channel) {
                 return Err("Invalid trigger channel".into());
             }
-            let trigger_channel = self.channels[self.trigger.source_channel];
-            let trigger_level = trigger_channel.measurement_from_voltage(self.trigger.trigger_level);
+            let trigger_level = self.trigger_measurement()?;
             if !(105..3990).contains(&trigger_level) {
                 return Err("Trigger level is outside operating range of the channel".into());
             }
@@ -161,10 +432,13 @@ impl ScopeCommand for DataRequest {
 
     fn fill_tx_buffer(&self, usb_buf: &mut [u8; 64]) -> Result<(), Box<dyn Error>> {
         let samples_between_records: u32 = (2_000_000.0 / self.sample_rate_hz) as u32;
+        if samples_between_records == 0 {
+            return Err("Sample rate is above the sample clock of the nLab".into());
+        }
 
         let total_samples = *self.remaining_samples.read().unwrap();
         debug!("Requesting {} samples with {} samples between records", total_samples, samples_between_records);
-        if samples_between_records < 25 && total_samples > 2400 {
+        if samples_between_records < 25 && total_samples > BUFFER_SAMPLES {
             return Err("Data not recordable".into());
         }
 
@@ -195,8 +469,7 @@ impl ScopeCommand for DataRequest {
             usb_buf[14] = self.trigger.trigger_type.value();
             usb_buf[15] = self.trigger.source_channel as u8;
 
-            let trigger_channel = self.channels[self.trigger.source_channel];
-            let trigger_level = trigger_channel.measurement_from_voltage(self.trigger.trigger_level);
+            let trigger_level = self.trigger_measurement()?;
             if !(5..4090).contains(&trigger_level) {
                 return Err("Trigger level is outside operating range of the channel".into());
             }
@@ -217,9 +490,11 @@ impl ScopeCommand for DataRequest {
 
     fn handle_rx_legacy(&self, usb_buf: &[u8; 64]) {
         let number_received_samples = usb_buf[3] as u32;
+        let first_index;
 
         {
             let mut remaining_samples = self.remaining_samples.write().unwrap();
+            first_index = self.number_of_samples - *remaining_samples;
             *remaining_samples -= number_received_samples;
             trace!("Received {} samples, {} samples remaining", number_received_samples, remaining_samples);
         }
@@ -227,11 +502,8 @@ impl ScopeCommand for DataRequest {
 
         let mut total_parsed_readings: usize = 0;
 
-        for _ in 0..number_received_samples {
-            let mut sample = Sample {
-                time_since_start: 0.0,
-                data: [None; 4],
-            };
+        for index in first_index..first_index + number_received_samples {
+            let mut measurements: [Option<u16>; 4] = [None; 4];
 
             for (i, ch) in self.channels.iter().enumerate() {
                 if ch.is_on {
@@ -244,12 +516,12 @@ impl ScopeCommand for DataRequest {
                     };
 
                     trace!("Ch{}: ADCData: {} Vi: {}", i+1, adc_data, ch.voltage_from_measurement(adc_data));
-                    sample.data[i] = Some(ch.voltage_from_measurement(adc_data));
+                    measurements[i] = Some(adc_data);
                     total_parsed_readings += 1;
                 }
             }
 
-            self.sender.send(sample).unwrap();
+            self.sender.send(self.sample_from_measurements(index, measurements)).unwrap();
         }
     }
     fn handle_rx(&self, _usb_buf: &[u8; 64]) {}
@@ -261,6 +533,22 @@ impl ScopeCommand for DataRequest {
 
 
 impl DataRequest {
+    /// Converts the trigger level, given in the units of the source channel, to an ADC code
+    fn trigger_measurement(&self) -> Result<i16, Box<dyn Error>> {
+        self.channels[self.trigger.source_channel]
+            .measurement_from_value(self.trigger.trigger_level)
+            .ok_or_else(|| "Trigger level is outside operating range of the channel".into())
+    }
+
+    fn sample_from_measurements(&self, index: u32, measurements: [Option<u16>; 4]) -> Sample {
+        let sample = sample_from_measurements(&self.channels, self.effective_sample_rate_hz, index, measurements);
+        let mut clipped_samples = self.clipped_samples.write().unwrap();
+        for (count, &clipped) in clipped_samples.iter_mut().zip(sample.clipped.iter()) {
+            *count += clipped as u32;
+        }
+        sample
+    }
+
     pub(crate) fn handle_incoming_data(&self, usb_buf: &[u8; 64], channel: usize) {
         let num_received = usb_buf[1] as usize;
         let mut num_parsed: usize = 0;
@@ -289,23 +577,16 @@ impl DataRequest {
             .collect::<Vec<usize>>();
 
         if let Some(&complete_samples) = received_samples.iter().min() {
-            let mut samples_to_pop = complete_samples;
-            while samples_to_pop > 0 {
-                let mut sample = Sample {
-                    time_since_start: 0.0,
-                    data: [None; 4],
-                };
+            let first_index = self.number_of_samples - *self.remaining_samples.read().unwrap();
+            for index in first_index..first_index + complete_samples as u32 {
+                let mut measurements: [Option<u16>; 4] = [None; 4];
 
                 for (ch, input_buffer) in data_collator.iter_mut().enumerate() {
-                    let channel = &self.channels[ch];
-
-                    if channel.is_on {
-                        let data = input_buffer.pop_front().unwrap();
-                        sample.data[ch] = Some(channel.voltage_from_measurement(data));
+                    if self.channels[ch].is_on {
+                        measurements[ch] = input_buffer.pop_front();
                     }
                 }
-                self.sender.send(sample).unwrap();
-                samples_to_pop -= 1;
+                self.sender.send(self.sample_from_measurements(index, measurements)).unwrap();
             }
 
 
@@ -316,4 +597,75 @@ impl DataRequest {
             }
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_rates_above_the_sample_clock() {
+        for &is_legacy in [true, false].iter() {
+            let (sender, _receiver) = mpsc::channel();
+            let (_stop_send, stop_recv) = mpsc::channel();
+            let mut channels = [0; 4].map(|_| AnalogInput::create(is_legacy));
+            channels[2].is_on = false;
+            channels[3].is_on = false;
+            let request = DataRequest {
+                channels,
+                sample_rate_hz: 5_000_000.0,
+                effective_sample_rate_hz: effective_sample_rate(5_000_000.0, 2, is_legacy),
+                number_of_samples: 100,
+                remaining_samples: Arc::new(RwLock::new(100)),
+                trigger: Trigger::default(),
+                sender,
+                stop_recv,
+                clipped_samples: Default::default(),
+                data_collator: Default::default(),
+            };
+            assert_eq!(request.effective_sample_rate_hz, sample_clock(2, is_legacy));
+
+            let result = if is_legacy {
+                request.fill_tx_buffer_legacy(&mut [0u8; 65])
+            } else {
+                request.fill_tx_buffer(&mut [0u8; 64])
+            };
+            assert!(result.is_err());
+        }
+    }
+
+    #[test]
+    fn records_when_the_stimulus_took_effect() {
+        let (tx, commands) = mpsc::channel();
+        let nlab = Nlab::mock(false, move |command| {
+            let kind = match command {
+                Command::SetAnalogOutput(_) => "analog",
+                Command::RequestData(_) => "sweep",
+                _ => return true,
+            };
+            // Delay the acknowledgement, as a USB round trip does
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            let _ = tx.send((Instant::now(), kind));
+            true
+        });
+        let state = AnalogOutputState { is_on: true, amplitude: 2.0, ..nlab.a2.state() };
+
+        let start = Instant::now();
+        let handle = nlab.request_with_stimulus(Stimulus::Analog(2, state), 1000.0, 100, None).unwrap();
+
+        let timing = handle.metadata().stimulus.unwrap();
+        assert!(timing.uncertainty >= 0.02, "{:?}", timing);
+        assert!(timing.offset <= -timing.uncertainty, "{:?}", timing);
+        assert!(nlab.a2.is_on());
+        assert_eq!(nlab.a2.amplitude(), 2.0);
+
+        let kinds: Vec<&str> = commands.iter().filter(|&(time, _)| time >= start).take(2).map(|(_, kind)| kind).collect();
+        assert_eq!(kinds, ["analog", "sweep"]);
+    }
+
+    #[test]
+    fn rejects_stimulus_on_missing_outputs() {
+        let nlab = Nlab::mock(false, |_| true);
+        let error = nlab.request_with_stimulus(Stimulus::Pulse(3, PulseOutputState::default()), 1000.0, 100, None).unwrap_err();
+        assert_eq!(error.to_string(), "Invalid pulse output: 3");
+    }
+}
@@ -14,6 +14,7 @@ use super::Nlab;
 
 /// Information about the power supply status of nLab
 #[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[pyclass]
 pub struct PowerStatus {
     #[pyo3(get)]
@@ -40,6 +41,7 @@ impl PowerStatus {
 
 /// Possible states of the nLab power supply
 #[derive(Debug, PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[pyclass(eq, eq_int)]
 pub enum PowerState {
     PowerOff,
@@ -0,0 +1,221 @@
+/***************************************************************************************************
+ *
+ *  nLabs, LLC
+ *  https://getnlab.com
+ *  Copyright(c) 2020. All Rights Reserved
+ *
+ *  This file is part of the nLab API
+ *
+ **************************************************************************************************/
+
+//! Saving and restoring the complete setup of an nLab
+//!
+//! With the `serde` feature enabled, a [`Profile`] can be written to and read from a TOML file,
+//! so that a setup can be prepared once and loaded onto any nLab with a single call:
+//!
+//! ```rust,no_run
+//! # #[cfg(feature = "serde")]
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! use nlabapi::{LabBench, Profile};
+//!
+//! let bench = LabBench::new()?;
+//! let mut nlab = bench.open_first_available(true)?;
+//! nlab.apply(&Profile::load("lab5.toml")?)?;
+//! # Ok(())
+//! # }
+//! # #[cfg(not(feature = "serde"))]
+//! # fn main() {}
+//! ```
+
+use std::error::Error;
+
+use super::analog_input::validate_range;
+use super::Nlab;
+use crate::{AnalogOutputState, ChannelConfig, PulseOutputState, SweepHandle, Trigger};
+
+/// Sample rate and length of the sweeps started with [`Nlab::acquire`]
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AcquisitionSettings {
+    pub sample_rate_hz: f64,
+    pub number_of_samples: u32,
+}
+
+impl Default for AcquisitionSettings {
+    fn default() -> Self {
+        AcquisitionSettings {
+            sample_rate_hz: 1000.0,
+            number_of_samples: 1000,
+        }
+    }
+}
+
+/// Complete setup of an nLab: scope channels, outputs, default trigger and acquisition, and power
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Profile {
+    /// Whether the nLab power supply is on
+    pub power_on: bool,
+    /// Channels 1 through 4. Only the minimum and maximum voltage of each range are applied,
+    /// the resolution is recalculated by the nLab.
+    pub channels: [ChannelConfig; 4],
+    /// Outputs `a1` and `a2`
+    pub analog_outputs: [AnalogOutputState; 2],
+    /// Outputs `p1` and `p2`
+    pub pulse_outputs: [PulseOutputState; 2],
+    pub trigger: Trigger,
+    pub acquisition: AcquisitionSettings,
+}
+
+#[cfg(feature = "serde")]
+impl Profile {
+    /// Parses a profile from TOML
+    pub fn from_toml(text: &str) -> Result<Self, Box<dyn Error>> {
+        Ok(toml::from_str(text)?)
+    }
+
+    /// Formats the profile as TOML
+    pub fn to_toml(&self) -> Result<String, Box<dyn Error>> {
+        Ok(toml::to_string_pretty(self)?)
+    }
+
+    /// Reads a profile from a TOML file
+    pub fn load<P: AsRef<std::path::Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        Profile::from_toml(&std::fs::read_to_string(path)?)
+    }
+
+    /// Writes the profile to a TOML file
+    pub fn save<P: AsRef<std::path::Path>>(&self, path: P) -> Result<(), Box<dyn Error>> {
+        std::fs::write(path, self.to_toml()?)?;
+        Ok(())
+    }
+}
+
+impl Nlab {
+    /// Captures the current setup of the nLab
+    pub fn snapshot(&self) -> Profile {
+        Profile {
+            power_on: self.power_on(),
+            channels: [self.ch1.config(), self.ch2.config(), self.ch3.config(), self.ch4.config()],
+            analog_outputs: [self.a1.state(), self.a2.state()],
+            pulse_outputs: [self.p1.state(), self.p2.state()],
+            trigger: self.trigger,
+            acquisition: self.acquisition,
+        }
+    }
+
+    /// Restores a setup captured with [`Nlab::snapshot`] or loaded from a file
+    ///
+    /// Settings that the hardware quantizes, such as channel ranges and output frequencies, are
+    /// set as close as the nLab allows. Use [`Nlab::snapshot`] to read the effective setup.
+    ///
+    /// The whole profile is checked before anything is sent, so an invalid profile leaves the
+    /// nLab unchanged.
+    pub fn apply(&mut self, profile: &Profile) -> Result<(), Box<dyn Error>> {
+        self.validate(profile)?;
+
+        if profile.power_on != self.power_on() {
+            self.set_power_on(profile.power_on)?;
+        }
+
+        for (ch, config) in (1..=4).zip(profile.channels.iter()) {
+            self.channel_mut(ch).unwrap().apply_config(config)?;
+        }
+
+        self.a1.set_state(profile.analog_outputs[0])?;
+        self.a2.set_state(profile.analog_outputs[1])?;
+        self.p1.set_state(profile.pulse_outputs[0])?;
+        self.p2.set_state(profile.pulse_outputs[1])?;
+
+        self.trigger = profile.trigger;
+        self.acquisition = profile.acquisition;
+        Ok(())
+    }
+
+    /// Checks every setting of a profile against this nLab
+    fn validate(&self, profile: &Profile) -> Result<(), Box<dyn Error>> {
+        if !(0..4).contains(&profile.trigger.source_channel) {
+            return Err(format!("Invalid trigger channel: {}", profile.trigger.source_channel).into());
+        }
+        if !(profile.acquisition.sample_rate_hz.is_finite() && profile.acquisition.sample_rate_hz > 0.0) {
+            return Err("Sample rate must be positive".into());
+        }
+        for config in profile.channels.iter() {
+            validate_range(config.range.min_voltage, config.range.max_voltage)?;
+        }
+        self.a1.limits().validate(&profile.analog_outputs[0])?;
+        self.a2.limits().validate(&profile.analog_outputs[1])?;
+        self.p1.validate(&profile.pulse_outputs[0])?;
+        self.p2.validate(&profile.pulse_outputs[1])?;
+        Ok(())
+    }
+
+    /// Returns the trigger used by [`Nlab::acquire`]
+    pub fn default_trigger(&self) -> Trigger {
+        self.trigger
+    }
+
+    pub fn set_default_trigger(&mut self, trigger: Trigger) {
+        self.trigger = trigger;
+    }
+
+    /// Returns the sample rate and length used by [`Nlab::acquire`]
+    pub fn acquisition(&self) -> AcquisitionSettings {
+        self.acquisition
+    }
+
+    pub fn set_acquisition(&mut self, acquisition: AcquisitionSettings) {
+        self.acquisition = acquisition;
+    }
+
+    /// Starts a sweep with the default trigger and acquisition settings
+    pub fn acquire(&self) -> SweepHandle {
+        let trigger = if self.trigger.is_enabled { Some(self.trigger) } else { None };
+        self.request(self.acquisition.sample_rate_hz, self.acquisition.number_of_samples, trigger)
+    }
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod tests {
+    use super::*;
+    use crate::{AnalogSignalPolarity, AnalogWaveType, ChannelRange, ChannelScaling, TriggerType};
+
+    #[test]
+    fn round_trips_through_toml() {
+        let channel = ChannelConfig {
+            is_on: true,
+            range: ChannelRange { min_voltage: -5.0, max_voltage: 5.0, resolution: 10.0 / 4095.0 },
+            scaling: ChannelScaling { scale: 10.0, offset: 0.0, unit: "A".into(), transform: None },
+            gain_cmd: 0,
+            offset_cmd: 0,
+        };
+        let profile = Profile {
+            power_on: true,
+            channels: [channel.clone(), channel.clone(), channel.clone(), channel],
+            analog_outputs: [AnalogOutputState {
+                is_on: true,
+                frequency: 100.0,
+                amplitude: 2.5,
+                wave_type: AnalogWaveType::Triangle,
+                polarity: AnalogSignalPolarity::Bipolar,
+                modulation: None,
+            }; 2],
+            pulse_outputs: [PulseOutputState {
+                is_on: false,
+                frequency: 1000.0,
+                duty: 0.25,
+            }; 2],
+            trigger: Trigger {
+                is_enabled: true,
+                trigger_type: TriggerType::FallingEdge,
+                source_channel: 2,
+                trigger_level: 1.5,
+                trigger_delay_us: 10,
+            },
+            acquisition: AcquisitionSettings { sample_rate_hz: 2000.0, number_of_samples: 500 },
+        };
+
+        let text = profile.to_toml().unwrap();
+        assert_eq!(Profile::from_toml(&text).unwrap(), profile);
+    }
+}
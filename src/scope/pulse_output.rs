@@ -9,12 +9,13 @@
  **************************************************************************************************/
 
 use std::error::Error;
-use std::sync::{mpsc, RwLock};
-use std::sync::mpsc::Sender;
+use std::sync::{mpsc, Arc, RwLock};
+use std::sync::mpsc::{Receiver, Sender};
 use std::time::Duration;
 
 use crate::scope::commands::{Command, ScopeCommand};
 
+
 #[derive(Debug, Copy, Clone)]
 enum PulsePreScale {
     One,
@@ -42,13 +43,30 @@ impl PulsePreScale {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
-struct PulseOutputState {
+/// Snapshot of the settings of a pulse output
+///
+/// On legacy nLabs, the frequency and duty reported by a [`PulseOutput`] are those produced by
+/// the pulse timers, which can differ slightly from the requested values. nLab v2 firmware
+/// acknowledges output commands without reporting the settings it applied, so they are the
+/// requested values.
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PulseOutputState {
     pub is_on: bool,
     pub frequency: f64,
     pub duty: f64,
 }
 
+impl Default for PulseOutputState {
+    fn default() -> Self {
+        PulseOutputState {
+            is_on: false,
+            frequency: 1.0,
+            duty: 0.5,
+        }
+    }
+}
+
 impl PulseOutputState {
     pub fn period(&self) -> Duration {
         let period = 1.0 / self.frequency;
@@ -58,56 +76,99 @@ impl PulseOutputState {
         let period = self.period();
         period.mul_f64(self.duty)
     }
+
+    pub(super) fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if !self.frequency.is_finite() || self.frequency <= 0.0 {
+            return Err(format!("Pulse frequency must be positive, got {}", self.frequency).into());
+        }
+        if !(0.0..=1.0).contains(&self.duty) {
+            return Err(format!("Pulse duty must be from 0 to 1, got {}", self.duty).into());
+        }
+        Ok(())
+    }
+
 }
 
 /// Interface to a pulse output channel
+///
+/// Each pulse output is programmed by its own command and runs freely, so the phase of `p1`
+/// relative to `p2` is not defined and cannot be set. Starting both from the host leaves them
+/// offset by the latency between two commands, around a millisecond, which is too coarse to
+/// align their periods.
 #[derive(Debug)]
 pub struct PulseOutput {
     pub channel: usize,
+    is_legacy: bool,
     command_tx: Sender<Command>,
-    state: RwLock<PulseOutputState>,
+    state: Arc<RwLock<PulseOutputState>>,
+    /// Settings as last requested, which setters modify so that quantization does not accumulate
+    pub(super) requested: Arc<RwLock<PulseOutputState>>,
 }
 
 
 impl PulseOutput {
-    pub(super) fn create(cmd_tx: Sender<Command>, px_channel: usize) -> Self {
-        let default_state = PulseOutputState {
-            is_on: false,
-            frequency: 1.0,
-            duty: 0.5,
-        };
+    pub(super) fn create(cmd_tx: Sender<Command>, px_channel: usize, is_legacy: bool) -> Self {
+        let default_state = PulseOutputState::default();
 
         let px = PulseOutput {
             command_tx: cmd_tx,
             channel: px_channel,
-            state: RwLock::new(default_state),
+            is_legacy,
+            state: Arc::new(RwLock::new(default_state)),
+            requested: Arc::new(RwLock::new(default_state)),
         };
 
-        px.set(default_state);
+        let _ = px.set(default_state);
         px
     }
 
-    fn set(&self, px_state: PulseOutputState) {
+    fn set(&self, px_state: PulseOutputState) -> Result<(), Box<dyn Error>> {
+        self.validate(&px_state)?;
+
         // Create a method for the backend to communicate back to us what we want
         let (tx, rx) = mpsc::channel::<PulseOutputState>();
 
-        // Create the command to set an analog output
+        // Create the command to set a pulse output
         let command = Command::SetPulseOutput(PxRequest {
             channel: self.channel,
             px_state,
             sender: tx,
-
         });
 
         // Send the command to the backend
-        if self.command_tx.send(command).is_ok() {
+        self.command_tx.send(command).map_err(|_| "nLab connection aborted")?;
+        *self.requested.write().unwrap() = px_state;
+
+        // Wait for the response from the backend
+        let response = rx.recv().map_err(|_| "No response to pulse output command")?;
+
+        // Write the response state
+        self.update_state(response);
+        Ok(())
+    }
 
-            // Wait for the response from the backend
-            if let Ok(response_state) = rx.recv() {
-                // Write the response state
-                *self.state.write().unwrap() = response_state;
-            }
+    /// Checks that this output can produce a state
+    pub fn validate(&self, px_state: &PulseOutputState) -> Result<(), Box<dyn Error>> {
+        px_state.validate()?;
+        // The legacy run loop cannot report a failure to program the timer registers
+        if self.is_legacy {
+            achieved_legacy(px_state)?;
         }
+        Ok(())
+    }
+
+    pub(super) fn update_state(&self, state: PulseOutputState) {
+        *self.state.write().unwrap() = state;
+    }
+
+    /// Returns a snapshot of the output's current settings
+    pub fn state(&self) -> PulseOutputState {
+        *self.state.read().unwrap()
+    }
+
+    /// Returns the reported and the requested settings, for updating from the panic hook
+    pub(super) fn shared_states(&self) -> [Arc<RwLock<PulseOutputState>>; 2] {
+        [self.state.clone(), self.requested.clone()]
     }
 
     pub fn is_on(&self) -> bool {
@@ -126,31 +187,107 @@ impl PulseOutput {
         self.state.read().unwrap().pulse_width()
     }
 
-    pub fn turn_on(&self) {
-        let mut state = *self.state.read().unwrap();
+    /// Applies all settings of the output in a single command
+    pub fn set_state(&self, state: PulseOutputState) -> Result<(), Box<dyn Error>> {
+        self.set(state)
+    }
+
+    pub fn turn_on(&self) -> Result<(), Box<dyn Error>> {
+        let mut state = *self.requested.read().unwrap();
         state.is_on = true;
         self.set(state)
     }
-    pub fn turn_off(&self) {
-        let mut state = *self.state.read().unwrap();
+    pub fn turn_off(&self) -> Result<(), Box<dyn Error>> {
+        let mut state = *self.requested.read().unwrap();
         state.is_on = false;
         self.set(state)
     }
 
-    pub fn set_frequency(&self, desired_hz: f64) {
-        let mut state = *self.state.read().unwrap();
+    pub fn set_frequency(&self, desired_hz: f64) -> Result<(), Box<dyn Error>> {
+        let mut state = *self.requested.read().unwrap();
         state.frequency = desired_hz;
         self.set(state)
     }
 
-    pub fn set_duty(&self, desired_percentage: f64) {
-        let mut state = *self.state.read().unwrap();
+    pub fn set_duty(&self, desired_percentage: f64) -> Result<(), Box<dyn Error>> {
+        let mut state = *self.requested.read().unwrap();
         state.duty = desired_percentage;
         self.set(state)
     }
+
+    /// Returns the achievable frequencies closest to `desired_hz`
+    ///
+    /// Legacy nLabs divide a 16 MHz clock, so the resolution coarsens as the frequency rises.
+    pub fn achievable_frequencies(&self, desired_hz: f64) -> Result<FrequencyResolution, Box<dyn Error>> {
+        if self.is_legacy {
+            frequency_resolution_legacy(desired_hz)
+        } else {
+            frequency_resolution(desired_hz)
+        }
+    }
+}
+
+/// Clock of the legacy pulse timers
+const LEGACY_CLOCK_HZ: f64 = 16e6;
+
+/// Achievable frequencies around a desired pulse frequency
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FrequencyResolution {
+    /// Highest achievable frequency at or below the desired frequency
+    pub below: f64,
+    /// Lowest achievable frequency at or above the desired frequency
+    pub above: f64,
+    /// Spacing between adjacent achievable frequencies around the desired frequency
+    pub resolution: f64,
+}
+
+/// Frequency and duty produced by a legacy nLab, after quantization to its timer registers
+fn achieved_legacy(pulse_output: &PulseOutputState) -> Result<PulseOutputState, Box<dyn Error>> {
+    let (prescale, period, duty) = get_registers(pulse_output)?;
+    let mut achieved = *pulse_output;
+    achieved.frequency = LEGACY_CLOCK_HZ / (prescale.value() * period as u64) as f64;
+    achieved.duty = duty as f64 / period as f64;
+    Ok(achieved)
+}
+
+fn frequency_resolution_legacy(desired_hz: f64) -> Result<FrequencyResolution, Box<dyn Error>> {
+    let state = PulseOutputState { frequency: desired_hz, ..Default::default() };
+    let (prescale, period, _) = get_registers(&state)?;
+    let tick_hz = LEGACY_CLOCK_HZ / prescale.value() as f64;
+    let exact = tick_hz / desired_hz;
+
+    // The period register is truncated, so the achieved frequency is at or above the desired one
+    let above = tick_hz / period as f64;
+    let below = tick_hz / exact.ceil();
+    Ok(FrequencyResolution {
+        below,
+        above,
+        resolution: above - tick_hz / (period + 1) as f64,
+    })
+}
+
+/// nLab v2 synthesizes the frequency from the single precision value it receives
+fn frequency_resolution(desired_hz: f64) -> Result<FrequencyResolution, Box<dyn Error>> {
+    if !desired_hz.is_finite() || desired_hz <= 0.0 {
+        return Err("Desired frequency must be positive".into());
+    }
+    let nearest = desired_hz as f32;
+    let (below, above) = if nearest as f64 > desired_hz {
+        (f32::from_bits(nearest.to_bits() - 1), nearest)
+    } else if (nearest as f64) < desired_hz {
+        (nearest, f32::from_bits(nearest.to_bits() + 1))
+    } else {
+        (nearest, nearest)
+    };
+    Ok(FrequencyResolution {
+        below: below as f64,
+        above: above as f64,
+        resolution: (f32::from_bits(nearest.to_bits() + 1) - nearest) as f64,
+    })
 }
 
-fn get_registers(pulse_output: &PulseOutputState) -> Result<(u8, u32, u32), Box<dyn Error>> {
+fn get_registers(pulse_output: &PulseOutputState) -> Result<(PulsePreScale, u32, u32), Box<dyn Error>> {
 
     // The period and duty registers are an integeter number of 16 MHz clock cycles
     let period = (pulse_output.period().as_nanos() * 16 / 1000) as u64;
@@ -173,7 +310,7 @@ fn get_registers(pulse_output: &PulseOutputState) -> Result<(u8, u32, u32), Box<
     let period_register = (period / (prescale.value())) as u32;
     let duty_register = (duty / (prescale.value())) as u32;
 
-    Ok((prescale.register(), period_register, duty_register))
+    Ok((prescale, period_register, duty_register))
 }
 
 #[derive(Debug)]
@@ -183,6 +320,14 @@ pub(crate) struct PxRequest {
     sender: Sender<PulseOutputState>,
 }
 
+impl PxRequest {
+    /// Request that turns an output off, for use where the output itself is not available
+    pub(super) fn turn_off(channel: usize) -> (Self, Receiver<PulseOutputState>) {
+        let (tx, rx) = mpsc::channel();
+        (PxRequest { channel, px_state: PulseOutputState::default(), sender: tx }, rx)
+    }
+}
+
 impl ScopeCommand for PxRequest {
     fn fill_tx_buffer_legacy(&self, usb_buf: &mut [u8; 65]) -> Result<(), Box<dyn Error>> {
         usb_buf[1] = 0x01;
@@ -191,7 +336,7 @@ impl ScopeCommand for PxRequest {
         let (prescale, period, duty) = get_registers(&self.px_state)?;
 
         if self.px_state.is_on {
-            usb_buf[i_ch] = 0x80 | prescale;
+            usb_buf[i_ch] = 0x80 | prescale.register();
             usb_buf[i_ch + 1..=i_ch + 4].copy_from_slice(&period.to_le_bytes());
             usb_buf[i_ch + 5..=i_ch + 8].copy_from_slice(&duty.to_le_bytes());
         } else {
@@ -202,10 +347,11 @@ impl ScopeCommand for PxRequest {
     }
 
     fn fill_tx_buffer(&self, usb_buf: &mut [u8; 64]) -> Result<(), Box<dyn Error>> {
+        self.px_state.validate()?;
+
         // Set the channel of interest
         usb_buf[3] = 0x1 << self.channel;
 
-
         let idx_start = 4 + 12 * self.channel;
         usb_buf[idx_start] = self.px_state.is_on as u8;
         usb_buf[idx_start + 1..=idx_start + 4].copy_from_slice(
@@ -217,14 +363,69 @@ impl ScopeCommand for PxRequest {
     }
 
     fn handle_rx_legacy(&self, _usb_buf: &[u8; 64]) {
-        self.sender.send(self.px_state).unwrap();
+        // Legacy firmware does not report its settings, so compute them from the registers. The
+        // settings were checked before the request was sent.
+        let achieved = achieved_legacy(&self.px_state).unwrap_or(self.px_state);
+        // The requester may have stopped waiting, as the panic hook does
+        let _ = self.sender.send(achieved);
     }
 
     fn handle_rx(&self, _usb_buf: &[u8; 64]) {
-        self.sender.send(self.px_state).unwrap();
+        // The response only acknowledges the command, it does not report the applied settings.
+        // The requester may have stopped waiting, as the panic hook does
+        let _ = self.sender.send(self.px_state);
     }
 
     fn is_finished(&self) -> bool {
         true
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legacy_outputs_report_quantized_values() {
+        // 16 MHz / 3 kHz is 5333.3 ticks, truncated to 5333
+        let requested = PulseOutputState { frequency: 3000.0, duty: 0.25, ..Default::default() };
+        let achieved = achieved_legacy(&requested).unwrap();
+        assert!((achieved.frequency - 16e6 / 5333.0).abs() < 1e-9);
+        assert!((achieved.duty - 1333.0 / 5333.0).abs() < 1e-12);
+
+        let resolution = frequency_resolution_legacy(3000.0).unwrap();
+        assert!((resolution.above - 16e6 / 5333.0).abs() < 1e-9);
+        assert!((resolution.below - 16e6 / 5334.0).abs() < 1e-9);
+        assert!(resolution.below <= 3000.0 && resolution.above >= 3000.0);
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        let state = PulseOutputState { is_on: true, frequency: 1000.0, ..Default::default() };
+        assert!(state.validate().is_ok());
+        for &frequency in [0.0, -1.0, f64::NAN, f64::INFINITY].iter() {
+            assert!(PulseOutputState { frequency, ..state }.validate().is_err());
+        }
+        for &duty in [-0.1, 1.5, f64::NAN].iter() {
+            assert!(PulseOutputState { duty, ..state }.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn legacy_outputs_reject_unachievable_settings() {
+        let (command_tx, command_rx) = mpsc::channel();
+        let output = PulseOutput {
+            channel: 0,
+            is_legacy: true,
+            command_tx,
+            state: Arc::new(RwLock::new(PulseOutputState::default())),
+            requested: Arc::new(RwLock::new(PulseOutputState::default())),
+        };
+        // Longer than the slowest prescaler can count, and shorter than four clock cycles
+        assert!(output.set_frequency(0.5).is_err());
+        assert!(output.set_frequency(5e6).is_err());
+        assert!(output.set_state(PulseOutputState { is_on: true, frequency: 0.5, duty: 0.5 }).is_err());
+        assert!(command_rx.try_recv().is_err());
+        assert_eq!(output.frequency(), 1.0);
+    }
+}
@@ -0,0 +1,272 @@
+/***************************************************************************************************
+ *
+ *  nLabs, LLC
+ *  https://getnlab.com
+ *  Copyright(c) 2020. All Rights Reserved
+ *
+ *  This file is part of the nLab API
+ *
+ **************************************************************************************************/
+
+use std::panic;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, Once, RwLock, TryLockError};
+use std::time::Duration;
+
+use log::warn;
+
+use super::analog_output::{AnalogOutputState, AnalogSignalPolarity, AnalogWaveType, AxRequest};
+use super::commands::Command;
+use super::pulse_output::{PulseOutputState, PxRequest};
+use super::Nlab;
+
+/// Outputs that are turned off when the connection to an nLab ends
+///
+/// The policy is applied by [`Nlab::close`] and when an `Nlab` is dropped, before the connection
+/// closes, and on a best-effort basis when the program panics if
+/// [`install_safe_state_panic_hook`] was called. The default turns off all analog and pulse
+/// outputs and the power supply, so that a script that exits or crashes does not leave a circuit
+/// driven.
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SafeStatePolicy {
+    pub analog_outputs_off: bool,
+    pub pulse_outputs_off: bool,
+    pub power_off: bool,
+}
+
+impl Default for SafeStatePolicy {
+    fn default() -> Self {
+        SafeStatePolicy {
+            analog_outputs_off: true,
+            pulse_outputs_off: true,
+            power_off: true,
+        }
+    }
+}
+
+impl SafeStatePolicy {
+    /// Leaves the outputs and power supply as they are
+    pub fn none() -> Self {
+        SafeStatePolicy {
+            analog_outputs_off: false,
+            pulse_outputs_off: false,
+            power_off: false,
+        }
+    }
+}
+
+/// Time to wait for the nLab to acknowledge each command sent from the panic hook
+const PANIC_COMMAND_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// An open nLab, as seen by the panic hook
+#[derive(Clone)]
+struct Registration {
+    id: usize,
+    command_tx: Sender<Command>,
+    policy: SafeStatePolicy,
+    analog_states: [Arc<RwLock<AnalogOutputState>>; 2],
+    /// Reported and requested settings of each pulse output
+    pulse_states: [[Arc<RwLock<PulseOutputState>>; 2]; 2],
+    power_on: Arc<AtomicBool>,
+}
+
+static REGISTRY: Mutex<Vec<Registration>> = Mutex::new(Vec::new());
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+static INSTALL_HOOK: Once = Once::new();
+
+fn registry() -> MutexGuard<'static, Vec<Registration>> {
+    REGISTRY.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Installs a panic hook that puts every open nLab in its safe state
+///
+/// The hook runs after any hook that was installed before it, on every panic of any thread,
+/// including panics that are caught and those that pyo3 turns into Python exceptions. The
+/// outputs of each nLab are then turned off as set by [`Nlab::set_safe_state`], while the
+/// program may keep running. Calling this more than once has no further effect.
+pub fn install_safe_state_panic_hook() {
+    INSTALL_HOOK.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            previous(info);
+            apply_all();
+        }));
+    });
+}
+
+/// Adds an nLab to those put in their safe state by the panic hook, returning its registration
+pub(super) fn register(nlab: &Nlab) -> usize {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    registry().push(Registration {
+        id,
+        command_tx: nlab.command_tx.clone(),
+        policy: nlab.safe_state,
+        analog_states: [nlab.a1.shared_state(), nlab.a2.shared_state()],
+        pulse_states: [nlab.p1.shared_states(), nlab.p2.shared_states()],
+        power_on: nlab.power_on.clone(),
+    });
+    id
+}
+
+pub(super) fn update(id: usize, policy: SafeStatePolicy) {
+    if let Some(registration) = registry().iter_mut().find(|registration| registration.id == id) {
+        registration.policy = policy;
+    }
+}
+
+pub(super) fn unregister(id: usize) {
+    registry().retain(|registration| registration.id != id);
+}
+
+fn apply_all() {
+    // The panic may have happened while the registry was locked. The registrations are copied so
+    // that other threads can open and close nLabs while the commands are sent.
+    let registrations = match REGISTRY.try_lock() {
+        Ok(registry) => registry.clone(),
+        Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner().clone(),
+        Err(TryLockError::WouldBlock) => return,
+    };
+    for registration in registrations.iter() {
+        registration.apply();
+    }
+}
+
+impl Registration {
+    /// Sends the commands of the policy, waiting a short time for each to be acknowledged
+    fn apply(&self) {
+        if self.policy.analog_outputs_off {
+            for (channel, shared_state) in self.analog_states.iter().enumerate() {
+                // Mark the output off first so that a modulator does not turn it back on. The
+                // state may be locked by the panicking thread, in which case any settings do.
+                let state = match shared_state.try_write() {
+                    Ok(mut state) => {
+                        state.is_on = false;
+                        state.modulation = None;
+                        *state
+                    }
+                    Err(_) => AnalogOutputState {
+                        is_on: false,
+                        frequency: 1.0,
+                        amplitude: 1.0,
+                        wave_type: AnalogWaveType::Sine,
+                        polarity: AnalogSignalPolarity::Unipolar,
+                        modulation: None,
+                    },
+                };
+                let (request, rx) = AxRequest::turn_off(channel, state);
+                if self.command_tx.send(Command::SetAnalogOutput(request)).is_ok() {
+                    let _ = rx.recv_timeout(PANIC_COMMAND_TIMEOUT);
+                }
+            }
+        }
+        if self.policy.pulse_outputs_off {
+            for (channel, shared_states) in self.pulse_states.iter().enumerate() {
+                let (request, rx) = PxRequest::turn_off(channel);
+                if self.command_tx.send(Command::SetPulseOutput(request)).is_ok() {
+                    let _ = rx.recv_timeout(PANIC_COMMAND_TIMEOUT);
+                    for shared_state in shared_states.iter() {
+                        if let Ok(mut state) = shared_state.try_write() {
+                            state.is_on = false;
+                        }
+                    }
+                }
+            }
+        }
+        if self.policy.power_off {
+            let (tx, rx) = mpsc::channel::<()>();
+            if self.command_tx.send(Command::Initialize(false, tx)).is_ok() {
+                let _ = rx.recv_timeout(PANIC_COMMAND_TIMEOUT);
+                self.power_on.store(false, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+impl Nlab {
+    /// Returns the policy applied when the connection to the nLab ends
+    pub fn safe_state(&self) -> SafeStatePolicy {
+        self.safe_state
+    }
+
+    pub fn set_safe_state(&mut self, policy: SafeStatePolicy) {
+        self.safe_state = policy;
+        update(self.safe_state_id, policy);
+    }
+
+    /// Puts the outputs and power supply in the state given by the safe state policy
+    pub(super) fn apply_safe_state(&mut self) {
+        let policy = self.safe_state;
+        let mut results = Vec::new();
+        if policy.analog_outputs_off {
+            results.push(self.a1.shut_down());
+            results.push(self.a2.shut_down());
+        }
+        if policy.pulse_outputs_off {
+            results.push(self.p1.turn_off());
+            results.push(self.p2.turn_off());
+        }
+        if policy.power_off && self.power_on() {
+            results.push(self.set_power_on(false));
+        }
+        for error in results.into_iter().filter_map(Result::err) {
+            warn!("Could not apply the safe state: {}", error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use crate::scope::commands::ScopeCommand;
+
+    #[test]
+    fn panic_hook_releases_registry_and_marks_outputs_off() {
+        let (command_tx, command_rx) = mpsc::channel::<Command>();
+        let responder = thread::spawn(move || {
+            for command in command_rx.iter() {
+                // Registering or closing another nLab must not wait for the hook
+                let _ = registry().len();
+                match command {
+                    Command::SetAnalogOutput(request) => request.handle_rx(&[0u8; 64]),
+                    Command::SetPulseOutput(request) => request.handle_rx(&[0u8; 64]),
+                    Command::Initialize(_, tx) => { let _ = tx.send(()); }
+                    _ => {}
+                }
+            }
+        });
+
+        let analog_state = AnalogOutputState {
+            is_on: true,
+            frequency: 1.0,
+            amplitude: 1.0,
+            wave_type: AnalogWaveType::Sine,
+            polarity: AnalogSignalPolarity::Unipolar,
+            modulation: None,
+        };
+        let pulse_state = PulseOutputState { is_on: true, ..Default::default() };
+        let analog = || Arc::new(RwLock::new(analog_state));
+        let pulse = || Arc::new(RwLock::new(pulse_state));
+        let registration = Registration {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            command_tx,
+            policy: SafeStatePolicy::default(),
+            analog_states: [analog(), analog()],
+            pulse_states: [[pulse(), pulse()], [pulse(), pulse()]],
+            power_on: Arc::new(AtomicBool::new(true)),
+        };
+        let id = registration.id;
+        registry().push(registration.clone());
+
+        apply_all();
+        unregister(id);
+        drop(registration.command_tx);
+        responder.join().unwrap();
+
+        assert!(registration.analog_states.iter().all(|state| !state.read().unwrap().is_on));
+        assert!(registration.pulse_states.iter().flatten().all(|state| !state.read().unwrap().is_on));
+        assert!(!registration.power_on.load(Ordering::Relaxed));
+    }
+}
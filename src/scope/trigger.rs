@@ -10,17 +10,20 @@
 
 /// Different trigger types used to start a data sweep
 #[derive(Debug, PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum TriggerType {
     RisingEdge,
     FallingEdge,
 }
 
 /// A representation of a trigger used to start a data sweep
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Trigger {
     pub is_enabled: bool,
     pub trigger_type: TriggerType,
     pub source_channel: usize,
+    /// Trigger level in the engineering units of the source channel
     pub trigger_level: f64,
     pub trigger_delay_us: u32,
 }
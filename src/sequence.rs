@@ -0,0 +1,439 @@
+/***************************************************************************************************
+ *
+ *  nLabs, LLC
+ *  https://getnlab.com
+ *  Copyright(c) 2020. All Rights Reserved
+ *
+ *  This file is part of the nLab API
+ *
+ **************************************************************************************************/
+
+//! Timed sequences of output changes for scripted stimulus
+//!
+//! An [`OutputSequence`] is a list of steps, each an action on the outputs at a time from the
+//! start of the sequence. The sequence is run on the host, which waits until each step is due
+//! and applies it, so steps take effect a few milliseconds after their scheduled time. The time
+//! at which the nLab acknowledged each step is reported as the sequence runs.
+//!
+//! ```rust,no_run
+//! use std::time::Duration;
+//! use nlabapi::sequence::{Output, OutputSequence, SequenceAction};
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let bench = nlabapi::LabBench::new()?;
+//! # let nlab = bench.open_first_available(true)?;
+//! let sequence = OutputSequence::new()
+//!     .at(Duration::ZERO, SequenceAction::SetFrequency(Output::Analog(1), 1000.0))
+//!     .at(Duration::ZERO, SequenceAction::SetAmplitude(Output::Analog(1), 2.0))
+//!     .at(Duration::ZERO, SequenceAction::TurnOn(Output::Analog(1)))
+//!     .at(Duration::from_millis(500), SequenceAction::SetDuty(Output::Pulse(1), 0.5))
+//!     .at(Duration::from_millis(500), SequenceAction::TurnOn(Output::Pulse(1)))
+//!     .at(Duration::from_secs(2), SequenceAction::SetAmplitude(Output::Analog(1), 3.0))
+//!     .at(Duration::from_secs(3), SequenceAction::AllOff);
+//!
+//! sequence.run(&nlab, |report| println!("{:?}", report))?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Single pulses and bursts of pulses are added with [`OutputSequence::one_shot`] and
+//! [`OutputSequence::burst`]. Adding a burst at the same time as a
+//! [`SequenceAction::StartSweep`] step fires it as the sweep starts:
+//!
+//! ```rust,no_run
+//! use std::time::Duration;
+//! use nlabapi::AcquisitionSettings;
+//! use nlabapi::sequence::{OutputSequence, SequenceAction};
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let bench = nlabapi::LabBench::new()?;
+//! # let nlab = bench.open_first_available(true)?;
+//! let acquisition = AcquisitionSettings { sample_rate_hz: 10_000.0, number_of_samples: 2000 };
+//! let mut report = OutputSequence::new()
+//!     .at(Duration::ZERO, SequenceAction::StartSweep(acquisition, None))
+//!     .burst(Duration::ZERO, 1, 10, 100.0, 0.25)
+//!     .run(&nlab, |_| {})?;
+//! let sweep = report.sweeps.remove(0).into_sweep();
+//! # Ok(())
+//! # }
+//! ```
+
+use std::error::Error;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{AcquisitionSettings, AnalogOutputState, Nlab, PulseOutputState, SweepHandle, Trigger};
+
+/// An analog or pulse output, numbered from 1
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Output {
+    /// `a1` or `a2`
+    Analog(usize),
+    /// `p1` or `p2`
+    Pulse(usize),
+}
+
+/// Change made by a step of a sequence
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SequenceAction {
+    TurnOn(Output),
+    TurnOff(Output),
+    SetFrequency(Output, f64),
+    /// Sets the amplitude of an analog output in Volts
+    SetAmplitude(Output, f64),
+    /// Sets the duty of a pulse output, from 0 to 1
+    SetDuty(Output, f64),
+    /// Applies all settings of an analog output in a single command
+    SetAnalog(Output, AnalogOutputState),
+    /// Applies all settings of a pulse output in a single command
+    SetPulse(Output, PulseOutputState),
+    /// Turns off all analog and pulse outputs
+    AllOff,
+    /// Starts a sweep, returned in the [`SequenceReport`]
+    StartSweep(AcquisitionSettings, Option<Trigger>),
+}
+
+/// A step of a sequence
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SequenceStep {
+    /// Time from the start of the sequence at which the step is due
+    pub time: Duration,
+    pub action: SequenceAction,
+}
+
+/// Progress of a running sequence, reported as each step takes effect
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StepReport {
+    /// Position of the step in the sequence, in order of time
+    pub index: usize,
+    pub step: SequenceStep,
+    /// Time from the start of the sequence at which the nLab acknowledged the step
+    pub applied: Duration,
+}
+
+impl StepReport {
+    /// How long after its scheduled time the step took effect
+    pub fn latency(&self) -> Duration {
+        self.applied.saturating_sub(self.step.time)
+    }
+}
+
+/// Outcome of running a sequence
+#[derive(Debug)]
+pub struct SequenceReport {
+    pub steps: Vec<StepReport>,
+    /// Sweeps started by [`SequenceAction::StartSweep`] steps, in order
+    pub sweeps: Vec<SweepHandle>,
+}
+
+/// Builder for a timed list of output changes
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct OutputSequence {
+    steps: Vec<SequenceStep>,
+}
+
+impl OutputSequence {
+    pub fn new() -> Self {
+        OutputSequence::default()
+    }
+
+    /// Adds a step at a time from the start of the sequence
+    ///
+    /// Steps due at the same time are applied in the order they were added.
+    pub fn at(mut self, time: Duration, action: SequenceAction) -> Self {
+        let index = self.steps.partition_point(|step| step.time <= time);
+        self.steps.insert(index, SequenceStep { time, action });
+        self
+    }
+
+    /// Returns the steps in the order they are applied
+    pub fn steps(&self) -> &[SequenceStep] {
+        &self.steps
+    }
+
+    /// Returns the time of the last step
+    pub fn duration(&self) -> Duration {
+        self.steps.last().map(|step| step.time).unwrap_or_default()
+    }
+
+    /// Adds a single pulse of `width` on a pulse output, numbered from 1, starting at `time`
+    ///
+    /// This is a burst of one pulse, see [`OutputSequence::burst`], so the width is timed by the
+    /// nLab but the pulse is only single while the host turns the output off within half of
+    /// `width` of its scheduled time.
+    pub fn one_shot(self, time: Duration, output: usize, width: Duration) -> Self {
+        self.burst(time, output, 1, 0.5 / width.as_secs_f64(), 0.5)
+    }
+
+    /// Adds a burst of `count` pulses on a pulse output, numbered from 1, starting at `time`
+    ///
+    /// The nLab has no burst mode, so the output is started at `frequency` and `duty` and the
+    /// host turns it off in the middle of the low time of the last pulse. The pulses themselves
+    /// are timed by the nLab, which starts a period with its pulse when the output turns on. The
+    /// count is exact as long as the latency of the turn-off command varies by less than half of
+    /// the low time, `(1 - duty) / (2 * frequency)`. That latency commonly varies by a
+    /// millisecond or more, so bursts are reliable up to a few hundred Hz.
+    pub fn burst(self, time: Duration, output: usize, count: u32, frequency: f64, duty: f64) -> Self {
+        if count == 0 {
+            return self;
+        }
+        let state = PulseOutputState { is_on: true, frequency, duty };
+        // An invalid state fails validation before the sequence runs, so any turn-off time will do
+        let off = Duration::try_from_secs_f64((count as f64 - 0.5 + duty / 2.0) / frequency).unwrap_or_default();
+        self.at(time, SequenceAction::SetPulse(Output::Pulse(output), state))
+            .at(time + off, SequenceAction::TurnOff(Output::Pulse(output)))
+    }
+
+    /// Checks that every step refers to an output of the nLab that supports its action
+    fn validate(&self, nlab: &Nlab) -> Result<(), Box<dyn Error>> {
+        for (index, step) in self.steps.iter().enumerate() {
+            let output = match step.action {
+                SequenceAction::TurnOn(output)
+                | SequenceAction::TurnOff(output)
+                | SequenceAction::SetFrequency(output, _) => Some(output),
+                SequenceAction::SetAmplitude(output, _) | SequenceAction::SetAnalog(output, _) => {
+                    if let Output::Pulse(_) = output {
+                        return Err(format!("Step {}: {:?} is not an analog output", index, output).into());
+                    }
+                    Some(output)
+                }
+                SequenceAction::SetDuty(output, _) | SequenceAction::SetPulse(output, _) => {
+                    if let Output::Analog(_) = output {
+                        return Err(format!("Step {}: {:?} is not a pulse output", index, output).into());
+                    }
+                    Some(output)
+                }
+                SequenceAction::AllOff => None,
+                SequenceAction::StartSweep(acquisition, _) => {
+                    if acquisition.sample_rate_hz <= 0.0 {
+                        return Err(format!("Step {}: sample rate must be positive", index).into());
+                    }
+                    None
+                }
+            };
+            let exists = match output {
+                Some(Output::Analog(ch)) => nlab.analog_output(ch).is_some(),
+                Some(Output::Pulse(ch)) => nlab.pulse_output(ch).is_some(),
+                None => true,
+            };
+            if !exists {
+                return Err(format!("Step {}: invalid output {:?}", index, output.unwrap()).into());
+            }
+            if let SequenceAction::SetPulse(Output::Pulse(ch), state) = step.action {
+                nlab.pulse_output(ch).unwrap().validate(&state).map_err(|error| format!("Step {}: {}", index, error))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the sequence, blocking until the last step has been applied
+    ///
+    /// `progress` is called with the report of each step as it takes effect. The sequence stops
+    /// at the first step the nLab rejects, leaving the outputs as they were after the previous
+    /// step.
+    pub fn run<F: FnMut(&StepReport)>(&self, nlab: &Nlab, mut progress: F) -> Result<SequenceReport, Box<dyn Error>> {
+        self.validate(nlab)?;
+
+        let mut report = SequenceReport { steps: Vec::with_capacity(self.steps.len()), sweeps: Vec::new() };
+        let start = Instant::now();
+        for (index, step) in self.steps.iter().enumerate() {
+            thread::sleep(step.time.saturating_sub(start.elapsed()));
+
+            if let Some(sweep) = apply(nlab, &step.action).map_err(|error| format!("Step {}: {}", index, error))? {
+                report.sweeps.push(sweep);
+            }
+
+            let step_report = StepReport { index, step: *step, applied: start.elapsed() };
+            progress(&step_report);
+            report.steps.push(step_report);
+        }
+        Ok(report)
+    }
+}
+
+fn apply(nlab: &Nlab, action: &SequenceAction) -> Result<Option<SweepHandle>, Box<dyn Error>> {
+    // Outputs and their actions have been checked by `OutputSequence::validate`
+    let analog = |ch: usize| nlab.analog_output(ch).unwrap();
+    let pulse = |ch: usize| nlab.pulse_output(ch).unwrap();
+
+    match *action {
+        SequenceAction::TurnOn(Output::Analog(ch)) => analog(ch).turn_on()?,
+        SequenceAction::TurnOn(Output::Pulse(ch)) => pulse(ch).turn_on()?,
+        SequenceAction::TurnOff(Output::Analog(ch)) => analog(ch).turn_off()?,
+        SequenceAction::TurnOff(Output::Pulse(ch)) => pulse(ch).turn_off()?,
+        SequenceAction::SetFrequency(Output::Analog(ch), hz) => analog(ch).set_frequency(hz)?,
+        SequenceAction::SetFrequency(Output::Pulse(ch), hz) => pulse(ch).set_frequency(hz)?,
+        SequenceAction::SetAmplitude(Output::Analog(ch), volts) => analog(ch).set_amplitude(volts)?,
+        SequenceAction::SetDuty(Output::Pulse(ch), duty) => pulse(ch).set_duty(duty)?,
+        SequenceAction::SetAnalog(Output::Analog(ch), state) => analog(ch).set_state(state)?,
+        SequenceAction::SetPulse(Output::Pulse(ch), state) => pulse(ch).set_state(state)?,
+        SequenceAction::SetAmplitude(Output::Pulse(_), _)
+        | SequenceAction::SetDuty(Output::Analog(_), _)
+        | SequenceAction::SetAnalog(Output::Pulse(_), _)
+        | SequenceAction::SetPulse(Output::Analog(_), _) => unreachable!(),
+        SequenceAction::AllOff => {
+            // Turn every output off even if one of them fails
+            let (a1, a2) = (nlab.a1.turn_off(), nlab.a2.turn_off());
+            let (p1, p2) = (nlab.p1.turn_off(), nlab.p2.turn_off());
+            a1.and(a2).and(p1).and(p2)?;
+        }
+        SequenceAction::StartSweep(acquisition, trigger) => {
+            return Ok(Some(nlab.request(acquisition.sample_rate_hz, acquisition.number_of_samples, trigger)));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::{self, Receiver};
+
+    use crate::scope::commands::Command;
+
+    use super::*;
+
+    /// Mocked nLab that records when each output command reaches it, rejecting pulse output
+    /// commands if `reject_pulse` is set
+    fn recording_nlab(reject_pulse: bool) -> (Nlab, Receiver<(Instant, &'static str)>) {
+        let (tx, rx) = mpsc::channel();
+        let nlab = Nlab::mock(false, move |command| {
+            let kind = match command {
+                Command::SetAnalogOutput(_) => "analog",
+                Command::SetPulseOutput(_) => "pulse",
+                _ => return true,
+            };
+            let _ = tx.send((Instant::now(), kind));
+            !(reject_pulse && kind == "pulse")
+        });
+        (nlab, rx)
+    }
+
+    #[test]
+    fn orders_steps_by_time() {
+        let sequence = OutputSequence::new()
+            .at(Duration::from_secs(3), SequenceAction::AllOff)
+            .at(Duration::ZERO, SequenceAction::SetFrequency(Output::Analog(1), 1000.0))
+            .at(Duration::from_secs(2), SequenceAction::SetAmplitude(Output::Analog(1), 3.0))
+            .at(Duration::ZERO, SequenceAction::TurnOn(Output::Analog(1)));
+
+        let actions: Vec<SequenceAction> = sequence.steps().iter().map(|step| step.action).collect();
+        assert_eq!(actions, vec![
+            SequenceAction::SetFrequency(Output::Analog(1), 1000.0),
+            SequenceAction::TurnOn(Output::Analog(1)),
+            SequenceAction::SetAmplitude(Output::Analog(1), 3.0),
+            SequenceAction::AllOff,
+        ]);
+        assert_eq!(sequence.duration(), Duration::from_secs(3));
+    }
+
+    #[test]
+    fn applies_each_step_when_it_is_due() {
+        let (nlab, commands) = recording_nlab(false);
+        let sequence = OutputSequence::new()
+            .at(Duration::ZERO, SequenceAction::SetFrequency(Output::Analog(1), 1000.0))
+            .at(Duration::from_millis(40), SequenceAction::SetDuty(Output::Pulse(2), 0.25))
+            .at(Duration::from_millis(80), SequenceAction::TurnOn(Output::Pulse(2)));
+
+        let start = Instant::now();
+        let mut progress = Vec::new();
+        let report = sequence.run(&nlab, |step| progress.push(step.index)).unwrap();
+
+        assert_eq!(progress, [0, 1, 2]);
+        assert!(report.sweeps.is_empty());
+        let sent: Vec<(Instant, &str)> = commands.try_iter().filter(|&(time, _)| time >= start).collect();
+        let kinds: Vec<&str> = sent.iter().map(|&(_, kind)| kind).collect();
+        assert_eq!(kinds, ["analog", "pulse", "pulse"]);
+        for ((step, &(sent_at, _)), expected) in report.steps.iter().zip(sent.iter()).zip(sequence.steps()) {
+            assert_eq!(step.step, *expected);
+            assert!(sent_at - start >= expected.time, "step {} sent early", step.index);
+            assert!(step.applied >= sent_at - start);
+        }
+
+        assert_eq!(nlab.a1.frequency(), 1000.0);
+        assert_eq!(nlab.p2.duty(), 0.25);
+        assert!(nlab.p2.is_on());
+    }
+
+    #[test]
+    fn stops_at_the_first_step_that_fails() {
+        let (nlab, commands) = recording_nlab(true);
+        let sequence = OutputSequence::new()
+            .at(Duration::ZERO, SequenceAction::SetAmplitude(Output::Analog(1), 1.0))
+            .at(Duration::ZERO, SequenceAction::TurnOn(Output::Pulse(1)))
+            .at(Duration::from_millis(10), SequenceAction::TurnOn(Output::Analog(1)));
+
+        let start = Instant::now();
+        let mut progress = Vec::new();
+        let error = sequence.run(&nlab, |step| progress.push(step.index)).unwrap_err();
+
+        assert!(error.to_string().starts_with("Step 1: "), "{}", error);
+        assert_eq!(progress, [0]);
+        let kinds: Vec<&str> = commands.try_iter().filter(|&(time, _)| time >= start).map(|(_, kind)| kind).collect();
+        assert_eq!(kinds, ["analog", "pulse"]);
+        assert_eq!(nlab.a1.amplitude(), 1.0);
+        assert!(!nlab.a1.is_on());
+    }
+
+    #[test]
+    fn checks_every_step_before_sending_any() {
+        let (nlab, commands) = recording_nlab(false);
+        let start = Instant::now();
+        let invalid = [
+            SequenceAction::SetDuty(Output::Analog(1), 0.5),
+            SequenceAction::SetAmplitude(Output::Pulse(1), 1.0),
+            SequenceAction::TurnOn(Output::Analog(3)),
+            SequenceAction::SetPulse(Output::Analog(1), PulseOutputState::default()),
+            SequenceAction::SetPulse(Output::Pulse(1), PulseOutputState { frequency: 0.0, ..Default::default() }),
+        ];
+        for &action in invalid.iter() {
+            let sequence = OutputSequence::new()
+                .at(Duration::ZERO, SequenceAction::TurnOn(Output::Analog(1)))
+                .at(Duration::from_millis(10), action);
+            let error = sequence.run(&nlab, |_| {}).unwrap_err();
+            assert!(error.to_string().starts_with("Step 1: "), "{}", error);
+        }
+        assert_eq!(commands.try_iter().filter(|&(time, _)| time >= start).count(), 0);
+        assert!(!nlab.a1.is_on());
+    }
+
+    #[test]
+    fn ends_bursts_in_the_low_time_of_the_last_pulse() {
+        let state = PulseOutputState { is_on: true, frequency: 100.0, duty: 0.25 };
+        let sequence = OutputSequence::new().burst(Duration::from_millis(10), 2, 3, 100.0, 0.25);
+        assert_eq!(sequence.steps(), [
+            SequenceStep { time: Duration::from_millis(10), action: SequenceAction::SetPulse(Output::Pulse(2), state) },
+            SequenceStep { time: Duration::from_micros(36_250), action: SequenceAction::TurnOff(Output::Pulse(2)) },
+        ]);
+
+        let state = PulseOutputState { is_on: true, frequency: 25.0, duty: 0.5 };
+        let sequence = OutputSequence::new().one_shot(Duration::ZERO, 1, Duration::from_millis(20));
+        assert_eq!(sequence.steps(), [
+            SequenceStep { time: Duration::ZERO, action: SequenceAction::SetPulse(Output::Pulse(1), state) },
+            SequenceStep { time: Duration::from_millis(30), action: SequenceAction::TurnOff(Output::Pulse(1)) },
+        ]);
+
+        assert!(OutputSequence::new().burst(Duration::ZERO, 1, 0, 100.0, 0.5).steps().is_empty());
+    }
+
+    #[test]
+    fn fires_a_burst_as_a_sweep_starts() {
+        let (nlab, commands) = recording_nlab(false);
+        let acquisition = AcquisitionSettings { sample_rate_hz: 1000.0, number_of_samples: 100 };
+        let sequence = OutputSequence::new()
+            .at(Duration::ZERO, SequenceAction::StartSweep(acquisition, None))
+            .burst(Duration::ZERO, 1, 4, 200.0, 0.5);
+
+        let start = Instant::now();
+        let report = sequence.run(&nlab, |_| {}).unwrap();
+
+        assert_eq!(report.sweeps.len(), 1);
+        let sent: Vec<(Instant, &str)> = commands.try_iter().filter(|&(time, _)| time >= start).collect();
+        assert_eq!(sent.len(), 2);
+        assert!(sent[1].0 - start >= Duration::from_micros(18_750));
+        assert!(!nlab.p1.is_on());
+        assert_eq!((nlab.p1.frequency(), nlab.p1.duty()), (200.0, 0.5));
+    }
+}
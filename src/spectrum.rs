@@ -0,0 +1,332 @@
+/***************************************************************************************************
+ *
+ *  nLabs, LLC
+ *  https://getnlab.com
+ *  Copyright(c) 2020. All Rights Reserved
+ *
+ *  This file is part of the nLab API
+ *
+ **************************************************************************************************/
+
+//! Magnitude and phase spectra of sweep data
+//!
+//! Spectra are computed at the effective sample rate of the sweep. Blocks whose length is not a
+//! power of two are windowed and then zero-padded, so the bin spacing is `sample_rate / fft_size`.
+
+use std::error::Error;
+use std::f64::consts::PI;
+
+use crate::{ChannelData, Sweep};
+
+/// Window functions applied to a block before the transform
+#[derive(Debug, PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Window {
+    Rectangular,
+    Hann,
+    Hamming,
+    /// 4-term Blackman-Harris, for low leakage between nearby tones
+    BlackmanHarris,
+    /// Flat-top, for accurate amplitudes of tones that fall between bins
+    FlatTop,
+}
+
+impl Window {
+    fn coefficients(&self) -> &'static [f64] {
+        match self {
+            Window::Rectangular => &[1.0],
+            Window::Hann => &[0.5, 0.5],
+            Window::Hamming => &[0.54, 0.46],
+            Window::BlackmanHarris => &[0.35875, 0.48829, 0.14128, 0.01168],
+            Window::FlatTop => &[0.21557895, 0.41663158, 0.277263158, 0.083578947, 0.006947368],
+        }
+    }
+
+    /// Periodic window of length `n`
+    fn samples(&self, n: usize) -> Vec<f64> {
+        let coefficients = self.coefficients();
+        (0..n)
+            .map(|i| {
+                let x = 2.0 * PI * i as f64 / n as f64;
+                coefficients
+                    .iter()
+                    .enumerate()
+                    .map(|(k, &a)| {
+                        let sign = if k % 2 == 0 { 1.0 } else { -1.0 };
+                        sign * a * (k as f64 * x).cos()
+                    })
+                    .sum()
+            })
+            .collect()
+    }
+}
+
+/// Units of spectrum magnitudes
+#[derive(Debug, PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Scaling {
+    /// RMS amplitude in the units of the channel
+    Linear,
+    /// Decibels relative to 1 V RMS
+    DbV,
+    /// Decibels relative to 1 mW into a load of the given impedance in Ohms
+    DbM { impedance_ohms: f64 },
+}
+
+impl Scaling {
+    fn apply(&self, rms: f64) -> f64 {
+        match self {
+            Scaling::Linear => rms,
+            Scaling::DbV => 20.0 * rms.log10(),
+            Scaling::DbM { impedance_ohms } => 10.0 * (rms * rms / impedance_ohms / 1e-3).log10(),
+        }
+    }
+}
+
+/// Single-sided spectrum of a channel
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Spectrum {
+    /// Center frequency of each bin in Hz
+    pub frequencies: Vec<f64>,
+    /// Magnitude of each bin, in the units given by `scaling`
+    pub magnitudes: Vec<f64>,
+    /// Phase of each bin in degrees, relative to a cosine at the start of the block
+    pub phases: Vec<f64>,
+    pub scaling: Scaling,
+    pub window: Window,
+    /// Number of blocks averaged into this spectrum
+    pub averages: u32,
+    rms: Vec<f64>,
+}
+
+/// A local maximum in a spectrum
+#[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Peak {
+    /// Frequency in Hz, interpolated between bins
+    pub frequency: f64,
+    /// Magnitude in the units of the spectrum's scaling
+    pub magnitude: f64,
+}
+
+impl Spectrum {
+    /// Spacing between bins in Hz
+    pub fn resolution_hz(&self) -> f64 {
+        self.frequencies.get(1).copied().unwrap_or(0.0)
+    }
+
+    /// Returns up to `max_peaks` of the largest local maxima, largest first, excluding DC
+    pub fn peaks(&self, max_peaks: usize) -> Vec<Peak> {
+        let rms = &self.rms;
+        let mut peaks: Vec<(usize, f64)> = (1..rms.len().saturating_sub(1))
+            .filter(|&k| rms[k] > rms[k - 1] && rms[k] >= rms[k + 1])
+            .map(|k| (k, rms[k]))
+            .collect();
+        peaks.sort_by(|a, b| b.1.total_cmp(&a.1));
+        peaks.truncate(max_peaks);
+
+        peaks
+            .into_iter()
+            .map(|(k, _)| {
+                // Quadratic interpolation of the peak location between neighbouring bins
+                let (a, b, c) = (rms[k - 1], rms[k], rms[k + 1]);
+                let denominator = a - 2.0 * b + c;
+                let offset = if denominator != 0.0 { 0.5 * (a - c) / denominator } else { 0.0 };
+                Peak {
+                    frequency: (k as f64 + offset) * self.resolution_hz(),
+                    magnitude: self.magnitudes[k],
+                }
+            })
+            .collect()
+    }
+}
+
+/// Accumulates spectra of repeated sweeps, averaging their power
+#[derive(Debug, Clone)]
+pub struct SpectrumAnalyzer {
+    window: Window,
+    scaling: Scaling,
+    sample_rate_hz: f64,
+    block_size: usize,
+    power: Vec<f64>,
+    re: Vec<f64>,
+    im: Vec<f64>,
+    averages: u32,
+}
+
+impl SpectrumAnalyzer {
+    pub fn new(window: Window, scaling: Scaling) -> Self {
+        SpectrumAnalyzer {
+            window,
+            scaling,
+            sample_rate_hz: 0.0,
+            block_size: 0,
+            power: Vec::new(),
+            re: Vec::new(),
+            im: Vec::new(),
+            averages: 0,
+        }
+    }
+
+    /// Adds a block of channel data to the average
+    ///
+    /// All blocks must have the same length and sample rate as the first one added
+    pub fn add(&mut self, data: &ChannelData) -> Result<(), Box<dyn Error>> {
+        let n = data.values.len();
+        if n < 2 {
+            return Err("Not enough samples to compute a spectrum".into());
+        }
+        if self.averages == 0 {
+            self.sample_rate_hz = data.sample_rate_hz;
+            self.block_size = n;
+        } else if n != self.block_size || data.sample_rate_hz != self.sample_rate_hz {
+            return Err("Sweeps must have the same length and sample rate to be averaged".into());
+        }
+
+        let window = self.window.samples(n);
+        let coherent_gain: f64 = window.iter().sum();
+        let fft_size = n.next_power_of_two();
+
+        let mut re = vec![0.0; fft_size];
+        let mut im = vec![0.0; fft_size];
+        for (i, (v, w)) in data.values.iter().zip(window.iter()).enumerate() {
+            re[i] = v * w;
+        }
+        fft(&mut re, &mut im);
+
+        // Single-sided RMS amplitude, corrected for the gain of the window
+        let bins = fft_size / 2 + 1;
+        if self.averages == 0 {
+            self.power = vec![0.0; bins];
+            self.re = vec![0.0; bins];
+            self.im = vec![0.0; bins];
+        }
+        for k in 0..bins {
+            let single_sided = if k == 0 || k == fft_size / 2 { 1.0 } else { 2f64.sqrt() };
+            let scale = single_sided / coherent_gain;
+            let (bin_re, bin_im) = (re[k] * scale, im[k] * scale);
+            self.power[k] += bin_re * bin_re + bin_im * bin_im;
+            self.re[k] += bin_re;
+            self.im[k] += bin_im;
+        }
+        self.averages += 1;
+        Ok(())
+    }
+
+    /// Adds one channel of a sweep, 1 through 4, to the average
+    pub fn add_sweep(&mut self, sweep: &Sweep, channel: usize) -> Result<(), Box<dyn Error>> {
+        let data = sweep.channel(channel).ok_or("Channel was not on during the sweep")?;
+        self.add(&data)
+    }
+
+    /// Returns the averaged spectrum, or None if no data has been added
+    pub fn spectrum(&self) -> Option<Spectrum> {
+        if self.averages == 0 {
+            return None;
+        }
+        let averages = self.averages as f64;
+        let fft_size = (self.power.len() - 1) * 2;
+        let rms: Vec<f64> = self.power.iter().map(|p| (p / averages).sqrt()).collect();
+
+        Some(Spectrum {
+            frequencies: (0..rms.len()).map(|k| k as f64 * self.sample_rate_hz / fft_size as f64).collect(),
+            magnitudes: rms.iter().map(|&v| self.scaling.apply(v)).collect(),
+            phases: self.re.iter().zip(self.im.iter()).map(|(re, im)| im.atan2(*re).to_degrees()).collect(),
+            scaling: self.scaling,
+            window: self.window,
+            averages: self.averages,
+            rms,
+        })
+    }
+
+    pub fn reset(&mut self) {
+        self.averages = 0;
+    }
+}
+
+/// Computes the spectrum of a single block of channel data
+pub fn spectrum(data: &ChannelData, window: Window, scaling: Scaling) -> Result<Spectrum, Box<dyn Error>> {
+    let mut analyzer = SpectrumAnalyzer::new(window, scaling);
+    analyzer.add(data)?;
+    Ok(analyzer.spectrum().unwrap())
+}
+
+/// In-place iterative radix-2 FFT, the length must be a power of two
+fn fft(re: &mut [f64], im: &mut [f64]) {
+    let n = re.len();
+
+    // Bit-reversed reordering
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let mut length = 2;
+    while length <= n {
+        let angle = -2.0 * PI / length as f64;
+        for start in (0..n).step_by(length) {
+            for k in 0..length / 2 {
+                let (w_re, w_im) = ((angle * k as f64).cos(), (angle * k as f64).sin());
+                let (a, b) = (start + k, start + k + length / 2);
+                let t_re = re[b] * w_re - im[b] * w_im;
+                let t_im = re[b] * w_im + im[b] * w_re;
+                re[b] = re[a] - t_re;
+                im[b] = im[a] - t_im;
+                re[a] += t_re;
+                im[a] += t_im;
+            }
+        }
+        length <<= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tone(frequency: f64, amplitude: f64, sample_rate_hz: f64, n: usize) -> ChannelData {
+        let values = (0..n)
+            .map(|i| amplitude * (2.0 * PI * frequency * i as f64 / sample_rate_hz).cos())
+            .collect();
+        ChannelData::new(values, sample_rate_hz)
+    }
+
+    #[test]
+    fn finds_the_amplitude_and_frequency_of_a_tone() {
+        // 1 kHz falls exactly on a bin, 1 V peak is 1/sqrt(2) V RMS, or about -3.01 dBV
+        let data = tone(1000.0, 1.0, 64_000.0, 4096);
+        let s = spectrum(&data, Window::Hann, Scaling::DbV).unwrap();
+        let peak = s.peaks(1)[0];
+
+        assert!((peak.frequency - 1000.0).abs() < s.resolution_hz() / 2.0);
+        assert!((peak.magnitude + 3.0103).abs() < 0.01, "magnitude: {}", peak.magnitude);
+    }
+
+    #[test]
+    fn flat_top_is_accurate_between_bins() {
+        let data = tone(1007.8125, 0.5, 64_000.0, 4096);
+        let s = spectrum(&data, Window::FlatTop, Scaling::Linear).unwrap();
+        let peak = s.peaks(1)[0];
+
+        assert!((peak.magnitude - 0.5 / 2f64.sqrt()).abs() < 0.005, "magnitude: {}", peak.magnitude);
+    }
+
+    #[test]
+    fn averages_only_matching_blocks() {
+        let mut analyzer = SpectrumAnalyzer::new(Window::Hann, Scaling::Linear);
+        analyzer.add(&tone(1000.0, 1.0, 64_000.0, 1024)).unwrap();
+        analyzer.add(&tone(1000.0, 1.0, 64_000.0, 1024)).unwrap();
+        assert!(analyzer.add(&tone(1000.0, 1.0, 32_000.0, 1024)).is_err());
+        assert_eq!(analyzer.spectrum().unwrap().averages, 2);
+    }
+}
@@ -0,0 +1,302 @@
+/***************************************************************************************************
+ *
+ *  nLabs, LLC
+ *  https://getnlab.com
+ *  Copyright(c) 2020. All Rights Reserved
+ *
+ *  This file is part of the nLab API
+ *
+ **************************************************************************************************/
+
+//! Step response and time-constant measurement using a pulse output as the stimulus
+//!
+//! A square wave from `p1` or `p2` drives the network under test. One scope channel measures
+//! the stimulus and triggers each sweep on its rising edge, and another measures the response.
+//! Several edges are averaged before a first-order, and optionally second-order, model is
+//! fitted. The half period of the square wave should be several time constants long so the
+//! response settles before the next edge.
+
+use std::error::Error;
+use std::f64::consts::PI;
+use std::thread;
+use std::time::Duration;
+
+use crate::measure::regression;
+use crate::scope::data_requests::{buffer_samples, effective_sample_rate, sample_clock};
+use crate::{Nlab, Trigger, TriggerType};
+
+/// Band around the final value within which the response is considered settled
+const SETTLING_BAND: f64 = 0.02;
+
+/// Settings for a step response measurement
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StepSettings {
+    /// Pulse output driving the network, 1 for `p1` or 2 for `p2`
+    pub output: usize,
+    /// Scope channel measuring the stimulus, also used as the trigger source, 1 through 4
+    pub stimulus_channel: usize,
+    /// Scope channel measuring the response of the network, 1 through 4
+    pub response_channel: usize,
+    /// Frequency of the square wave in Hz
+    pub frequency: f64,
+    /// Stimulus level that triggers each sweep on its rising edge
+    pub trigger_level: f64,
+    /// Number of edges to average
+    pub averages: u32,
+    /// Also fit an underdamped second-order model to the response
+    pub second_order: bool,
+    /// Time to wait after starting the stimulus, before capturing
+    pub settle_time: Duration,
+}
+
+impl Default for StepSettings {
+    fn default() -> Self {
+        StepSettings {
+            output: 1,
+            stimulus_channel: 1,
+            response_channel: 2,
+            frequency: 100.0,
+            trigger_level: 1.0,
+            averages: 8,
+            second_order: false,
+            settle_time: Duration::from_millis(50),
+        }
+    }
+}
+
+/// Parameters of an underdamped second-order response
+#[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SecondOrderFit {
+    /// Damping ratio ζ, estimated from the overshoot
+    pub damping_ratio: f64,
+    /// Undamped natural frequency in Hz, estimated from the time of the first peak
+    pub natural_frequency_hz: f64,
+}
+
+/// Averaged step response and the parameters fitted to it
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StepResponse {
+    /// Averaged response, starting at the stimulus edge
+    pub values: Vec<f64>,
+    pub sample_rate_hz: f64,
+    /// Settled level before the edge
+    pub initial_value: f64,
+    /// Settled level after the edge
+    pub final_value: f64,
+    /// First-order time constant in seconds
+    pub tau: Option<f64>,
+    /// Time from the edge until the response stays within 2% of the step
+    pub settling_time: Option<f64>,
+    /// Overshoot past the final value, as a fraction of the step
+    pub overshoot: f64,
+    pub second_order: Option<SecondOrderFit>,
+    /// Number of edges averaged
+    pub averages: u32,
+    /// True if either channel saturated during any of the captures
+    pub clipped: bool,
+}
+
+impl StepResponse {
+    /// Analyzes a response sampled from the stimulus edge onwards
+    ///
+    /// The response must settle before the end of the data; the initial value is taken from
+    /// the first sample and the final value from the last tenth of the data.
+    pub fn from_values(values: Vec<f64>, sample_rate_hz: f64, second_order: bool) -> Self {
+        let n = values.len();
+        let mut response = StepResponse {
+            values,
+            sample_rate_hz,
+            initial_value: 0.0,
+            final_value: 0.0,
+            tau: None,
+            settling_time: None,
+            overshoot: 0.0,
+            second_order: None,
+            averages: 1,
+            clipped: false,
+        };
+        if n < 10 {
+            return response;
+        }
+
+        let tail = &response.values[n - n / 10..];
+        response.initial_value = response.values[0];
+        response.final_value = tail.iter().sum::<f64>() / tail.len() as f64;
+        let step = response.final_value - response.initial_value;
+        if step == 0.0 {
+            return response;
+        }
+
+        // Normalized response, rising from 0 to 1 regardless of the direction of the step
+        let normalized: Vec<f64> = response.values.iter().map(|v| (v - response.initial_value) / step).collect();
+        let time = |i: usize| i as f64 / sample_rate_hz;
+
+        // First-order fit of ln(1 - y) = -t / tau over the 10% to 90% part of the rise
+        let (t, ln_remaining): (Vec<f64>, Vec<f64>) = normalized
+            .iter()
+            .enumerate()
+            .take_while(|(_, &y)| y < 0.9)
+            .filter(|(_, &y)| y >= 0.1)
+            .map(|(i, &y)| (time(i), (1.0 - y).ln()))
+            .unzip();
+        response.tau = regression(&t, &ln_remaining)
+            .map(|(slope, _)| slope)
+            .filter(|&slope| slope < 0.0)
+            .map(|slope| -1.0 / slope);
+
+        response.settling_time = normalized
+            .iter()
+            .rposition(|y| (y - 1.0).abs() > SETTLING_BAND)
+            .map(|i| time(i + 1));
+
+        let (peak_index, &peak) = normalized
+            .iter()
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(b.1))
+            .unwrap();
+        response.overshoot = (peak - 1.0).max(0.0);
+
+        if second_order && response.overshoot > 0.0 && peak_index > 0 {
+            let log_overshoot = response.overshoot.ln();
+            let damping_ratio = -log_overshoot / (PI * PI + log_overshoot * log_overshoot).sqrt();
+            let damped_frequency = PI / time(peak_index);
+            response.second_order = Some(SecondOrderFit {
+                damping_ratio,
+                natural_frequency_hz: damped_frequency / (1.0 - damping_ratio * damping_ratio).sqrt() / (2.0 * PI),
+            });
+        }
+        response
+    }
+
+    /// Capacitance of an RC network with the given series resistance in Ohms
+    pub fn capacitance(&self, resistance: f64) -> Option<f64> {
+        self.tau.map(|tau| tau / resistance)
+    }
+
+    /// Inductance of an RL network with the given resistance in Ohms
+    pub fn inductance(&self, resistance: f64) -> Option<f64> {
+        self.tau.map(|tau| tau * resistance)
+    }
+}
+
+/// Drives the pulse output as a square wave and measures the averaged step response
+///
+/// Only the stimulus and response channels are turned on during the measurement; the previous
+/// channel states are restored and the output is turned off when it finishes.
+pub fn measure_step(nlab: &mut Nlab, settings: &StepSettings) -> Result<StepResponse, Box<dyn Error>> {
+    let channels = [settings.stimulus_channel, settings.response_channel];
+    if channels.iter().any(|&ch| nlab.channel(ch).is_none()) || channels[0] == channels[1] {
+        return Err("Stimulus and response must be two different channels between 1 and 4".into());
+    }
+    if nlab.pulse_output(settings.output).is_none() {
+        return Err(format!("Invalid pulse output: {}", settings.output).into());
+    }
+
+    let result = nlab.with_channels(&channels, |nlab| capture_edges(nlab, settings));
+    let turned_off = nlab.pulse_output(settings.output).unwrap().turn_off();
+    result.and_then(|value| turned_off.map(|()| value))
+}
+
+fn capture_edges(nlab: &Nlab, settings: &StepSettings) -> Result<StepResponse, Box<dyn Error>> {
+    let output = nlab.pulse_output(settings.output).unwrap();
+    output.set_frequency(settings.frequency)?;
+    output.set_duty(0.5)?;
+    output.turn_on()?;
+
+    // Capture the high half of the square wave, filling the nLab's buffer
+    let max_samples = buffer_samples(2, nlab.is_legacy());
+    let high_time = output.pulse_width().as_secs_f64();
+    let requested_rate = (max_samples as f64 / high_time).min(sample_clock(2, nlab.is_legacy()));
+    let sample_rate = effective_sample_rate(requested_rate, 2, nlab.is_legacy());
+    let number_of_samples = ((high_time * sample_rate).floor() as u32).min(max_samples);
+    if number_of_samples < 10 {
+        return Err("Cannot sample fast enough for the stimulus frequency".into());
+    }
+
+    let trigger = Trigger {
+        is_enabled: true,
+        trigger_type: TriggerType::RisingEdge,
+        source_channel: settings.stimulus_channel - 1,
+        trigger_level: settings.trigger_level,
+        trigger_delay_us: 0,
+    };
+
+    thread::sleep(settings.settle_time);
+
+    let averages = settings.averages.max(1);
+    let mut stimulus_sum = vec![0.0; number_of_samples as usize];
+    let mut response_sum = vec![0.0; number_of_samples as usize];
+    let mut clipped = false;
+    let mut rate = sample_rate;
+    for _ in 0..averages {
+        let sweep = nlab.request(requested_rate, number_of_samples, Some(trigger)).into_sweep();
+        let stimulus = sweep
+            .channel(settings.stimulus_channel)
+            .ok_or("No data received on the stimulus channel")?;
+        let response = sweep
+            .channel(settings.response_channel)
+            .ok_or("No data received on the response channel")?;
+        if stimulus.values.len() < stimulus_sum.len() || response.values.len() < response_sum.len() {
+            return Err("Incomplete capture".into());
+        }
+
+        stimulus_sum.iter_mut().zip(stimulus.values.iter()).for_each(|(sum, v)| *sum += v);
+        response_sum.iter_mut().zip(response.values.iter()).for_each(|(sum, v)| *sum += v);
+        clipped |= stimulus.clipped_samples > 0 || response.clipped_samples > 0;
+        rate = sweep.metadata.sample_rate_hz;
+    }
+
+    // Start the response at the stimulus edge, which may lag the trigger point by a few samples
+    let stimulus_max = stimulus_sum.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let stimulus_min = stimulus_sum.iter().copied().fold(f64::INFINITY, f64::min);
+    let edge = stimulus_sum
+        .iter()
+        .position(|&v| v >= (stimulus_max + stimulus_min) / 2.0)
+        .unwrap_or(0);
+
+    let values = response_sum[edge..].iter().map(|v| v / averages as f64).collect();
+    let mut response = StepResponse::from_values(values, rate, settings.second_order);
+    response.averages = averages;
+    response.clipped = clipped;
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fits_an_rc_charge() {
+        // 1 kOhm and 1 uF sampled at 100 kHz for 10 time constants
+        let tau = 1e-3;
+        let values = (0..1000).map(|i| 3.3 * (1.0 - (-(i as f64) / 1e5 / tau).exp())).collect();
+        let response = StepResponse::from_values(values, 1e5, false);
+
+        assert!((response.tau.unwrap() / tau - 1.0).abs() < 0.01);
+        assert!((response.capacitance(1000.0).unwrap() - 1e-6).abs() < 1e-8);
+        // ln(1 / 0.02) time constants
+        assert!((response.settling_time.unwrap() - 3.912e-3).abs() < 2e-5);
+        assert!(response.overshoot < 1e-3);
+    }
+
+    #[test]
+    fn fits_an_underdamped_response() {
+        let (zeta, fn_hz, rate) = (0.3f64, 1000.0, 1e6);
+        let wn = 2.0 * PI * fn_hz;
+        let wd = wn * (1.0 - zeta * zeta).sqrt();
+        let phi = (1.0 - zeta * zeta).sqrt().atan2(zeta);
+        let values = (0..20_000)
+            .map(|i| {
+                let t = i as f64 / rate;
+                1.0 - (-zeta * wn * t).exp() * (wd * t + phi).sin() / (1.0 - zeta * zeta).sqrt()
+            })
+            .collect();
+        let fit = StepResponse::from_values(values, rate, true).second_order.unwrap();
+
+        assert!((fit.damping_ratio - zeta).abs() < 0.01, "zeta: {}", fit.damping_ratio);
+        assert!((fit.natural_frequency_hz / fn_hz - 1.0).abs() < 0.01, "fn: {}", fit.natural_frequency_hz);
+    }
+}